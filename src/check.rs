@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+use crate::{
+    emit_keymap_drawer::predefined_named_keys,
+    errors::{AppError, Errors},
+    keycodes,
+    process::unknown_named_key,
+    syntax::{File, Key, KeyOrChord, PlainKey},
+};
+
+/// Checks keys against what every backend knows, rather than what one
+/// backend in particular supports
+struct Check {
+    named_keys: HashSet<String>,
+}
+
+impl Check {
+    fn check_key(&self, key: &Key<'_>, errors: &mut Errors) {
+        match key {
+            Key::Plain(p) => self.check_plain_key(p, errors),
+            Key::ModTap { tap, hold, .. } => {
+                self.check_plain_key(tap, errors);
+                self.check_plain_key(hold, errors);
+            }
+            Key::Inherit(_) => {}
+        }
+    }
+
+    fn check_plain_key(&self, key: &PlainKey<'_>, errors: &mut Errors) {
+        match key {
+            PlainKey::Named(name) => {
+                if self.named_keys.contains(name.s) {
+                    return;
+                }
+
+                errors.push(unknown_named_key(&self.named_keys, name));
+            }
+            PlainKey::Char { c, span, .. } => {
+                if !keycodes::is_typeable(*c) {
+                    errors.push(AppError::UnknownKey {
+                        span: *span,
+                        key: *c,
+                    });
+                }
+            }
+            PlainKey::Modified { key, .. } => self.check_plain_key(key, errors),
+            // layers are checked when processing
+            PlainKey::Trans(_) | PlainKey::Layer { .. } | PlainKey::LayerSwitch { .. } => {}
+        }
+    }
+}
+
+/// Make sure every key of the layout is one that exists, named keys need to
+/// be in the keycode registry or be custom keys
+pub fn check(file: &File<'_>) -> miette::Result<()> {
+    let mut named_keys = predefined_named_keys().into_keys().collect::<HashSet<_>>();
+    named_keys.extend(file.custom_keys.iter().map(|k| k.name.s.to_string()));
+
+    let check = Check { named_keys };
+    let mut errors = Errors::default();
+
+    for layer in &file.layers {
+        for item in layer.rows.iter().flat_map(|r| &r.items) {
+            match item {
+                KeyOrChord::Key(key) => check.check_key(key, &mut errors),
+                KeyOrChord::Chord(chord) => check.check_key(&chord.key, &mut errors),
+            }
+        }
+
+        for combo in &layer.combos {
+            check.check_key(&combo.key, &mut errors);
+        }
+    }
+
+    errors.finish()
+}
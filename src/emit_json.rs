@@ -0,0 +1,78 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{process::Metadata, syntax::File};
+
+/// Bumped whenever something is removed or changes meaning, adding fields
+/// doesn't count
+const SCHEMA_VERSION: u32 = 1;
+
+/// The layout after processing, for scripts that want to work with it.
+///
+/// Schema version 1:
+///
+/// - `version`: the schema version, a number
+/// - `custom_keys`: each custom key's outputs, by key name then backend name
+/// - `options`: each options block, by backend name then option name
+/// - `layout`:
+///   - `width` and `height` of the matrix
+///   - `phys_to_matrix`: `[[x, y], at]` pairs, `at` is `"space"` for gaps or
+///     `{"located": [column, row]}` for keys
+///   - `layout_to_matrix`: the same, from positions counting only keys
+///   - `layout_to_phys`: `[[x, y], [x, y]]` pairs
+/// - `layers`:
+///   - `layer_map`: the index of each layer by name
+///   - `layers`: in order, each with a `name`, `keys` and `chords`. Keys have
+///     `key`, `layout_pos`, `physical_pos`, `matrix_pos` and `effective`, which
+///     is the key once transparent keys are resolved or `null`. Chords have
+///     `key`, `layout_positions` and `matrix_positions`.
+///
+/// Positions are `[x, y]` arrays. Keys are objects with a `type` of:
+///
+/// - `named` with a `name`
+/// - `char` with a `char`
+/// - `trans`
+/// - `layer` with a `layer` to hold
+/// - `layer_switch` with a `kind` of `toggle`, `oneshot` or `default` and a
+///   `layer`
+/// - `modified` with a list of `modifiers` (`ctrl`, `shift`, `alt`, `gui`) and
+///   the `key` they modify
+/// - `mod_tap` with `tap` and `hold` keys, a `flavor` of `permissive` or
+///   `on_other_key` and a `timeout` in milliseconds or `null`
+#[derive(serde::Serialize)]
+struct Export<'a> {
+    version: u32,
+    custom_keys: BTreeMap<&'a str, BTreeMap<&'a str, &'a str>>,
+    #[serde(flatten)]
+    metadata: &'a Metadata<'a>,
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let custom_keys = file
+        .custom_keys
+        .iter()
+        .map(|k| {
+            let outputs = k
+                .outputs
+                .iter()
+                .map(|d| (d.name.s, d.output.text.as_ref()))
+                .collect();
+
+            (k.name.s, outputs)
+        })
+        .collect();
+
+    let export = Export {
+        version: SCHEMA_VERSION,
+        custom_keys,
+        metadata,
+    };
+
+    serde_json::to_writer_pretty(&mut *out, &export).unwrap();
+    writeln!(out).unwrap();
+
+    Ok(())
+}
@@ -0,0 +1,453 @@
+use std::{collections::HashMap, io::Write};
+
+use indexmap::IndexMap;
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_layer, unknown_named_key, LayerMeta, Metadata},
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Alias names for each hold-tap, by their definition
+    aliases: IndexMap<String, String>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+struct MappedLayer {
+    name: String,
+    rows: Vec<Vec<String>>,
+    chords: Option<MappedChords>,
+}
+
+/// A `defchords` group, kanata chords are made of keys that have been
+/// replaced with `(chord group key)` in the layer
+struct MappedChords {
+    group: String,
+    chords: Vec<(Vec<String>, String)>,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata
+            .get_option(crate::process::OptionKey::Kanata, key)
+    }
+
+    fn option_d<'d: 'a>(&self, key: &str, default: &'d str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span: _,
+            } => {
+                let tap = self.map_plain_key(tap)?;
+                let hold = self.map_plain_key(hold)?;
+
+                let action = match at {
+                    ModTapType::Permissive(_) => "tap-hold-release",
+                    ModTapType::OnOtherKey(_) => "tap-hold-press",
+                };
+
+                let definition = format!(
+                    "({action} {} {} {tap} {hold})",
+                    self.option_d("tap_timeout", "200"),
+                    timeout.as_ref().map_or_else(
+                        || self.option_d("hold_tap_timeout", "200").to_string(),
+                        |t| t.timeout.to_string()
+                    ),
+                );
+
+                let next = format!("ht{}", self.aliases.len());
+                let name = self.aliases.entry(definition).or_insert(next);
+
+                Ok(format!("@{name}"))
+            }
+        }
+    }
+
+    fn map_plain_key(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok("_".to_owned()),
+            PlainKey::Layer { layer, .. } => {
+                Ok(format!("(layer-while-held {})", self.layer_name(layer)?))
+            }
+            PlainKey::LayerSwitch {
+                kind, layer, span, ..
+            } => {
+                let layer = self.layer_name(layer)?;
+
+                match kind {
+                    LayerSwitchKind::Default(_) => Ok(format!("(layer-switch {layer})")),
+                    LayerSwitchKind::OneShot(_) => Ok(format!(
+                        "(one-shot {} (layer-while-held {layer}))",
+                        self.option_d("oneshot_timeout", "2000")
+                    )),
+                    LayerSwitchKind::Toggle(_) => Err(AppError::UnsupportedByBackend {
+                        span: *span,
+                        backend: "kanata".to_string(),
+                        what: "toggling layers".to_string(),
+                    }
+                    .into()),
+                }
+            }
+            PlainKey::Char { c, span, .. } => {
+                if let Some(k) = CHAR_KEYCODES.get(c) {
+                    return Ok(k.clone());
+                }
+
+                Err(AppError::UnknownKey {
+                    span: *span,
+                    key: *c,
+                }
+                .into())
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let keycode = self.keycode(key)?;
+                let modifiers = modifiers.iter().map(modifier_prefix).join("");
+
+                Ok(format!("{modifiers}{keycode}"))
+            }
+        }
+    }
+
+    /// The key name for a key, for keys that can be combined with modifiers
+    fn keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode.clone());
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    fn layer_name(&self, layer: &Ident<'_>) -> miette::Result<String> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        if !layer_map.contains_key(layer.s) {
+            return Err(unknown_layer(layer_map, layer).into());
+        }
+
+        Ok(layer.s.to_string())
+    }
+
+    /// The keys of the laptop keyboard that make up the layout, either given
+    /// as an option or lined up with the bottom rows of an ANSI keyboard
+    fn defsrc(&self) -> miette::Result<Vec<Vec<String>>> {
+        let layout = &self.metadata.layout;
+        let row_lens = (0..layout.height)
+            .map(|y| layout.row_len(y))
+            .collect::<Vec<_>>();
+
+        if let Some(defsrc) = self.option("defsrc") {
+            let mut keys = defsrc.split_whitespace();
+            let expected = row_lens.iter().sum::<usize>();
+            let got = keys.clone().count();
+
+            if got != expected {
+                return Err(AppError::InvalidOption {
+                    option_name: "defsrc".to_string(),
+                    backend: "kanata".to_string(),
+                    problem: format!(
+                        "It should list one key for each of the {expected} keys of the layout, but it lists {got}"
+                    ),
+                }
+                .into());
+            }
+
+            return Ok(row_lens
+                .iter()
+                .map(|len| keys.by_ref().take(*len).map(str::to_owned).collect())
+                .collect());
+        }
+
+        let first_row = ANSI_ROWS.len().saturating_sub(row_lens.len().max(4));
+        let rows = ANSI_ROWS.get(first_row..first_row + row_lens.len());
+
+        let defsrc = rows.and_then(|rows| {
+            rows.iter()
+                .zip(&row_lens)
+                .map(|(row, len)| {
+                    let keys = row.split_whitespace().collect::<Vec<_>>();
+                    keys.get(..*len)
+                        .map(|keys| keys.iter().map(|k| k.to_string()).collect())
+                })
+                .collect::<Option<Vec<_>>>()
+        });
+
+        defsrc.ok_or_else(|| {
+            AppError::OptionRequired {
+                option_name: "defsrc".to_string(),
+                backend: "kanata".to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn map_layer(&mut self, layer: &'a LayerMeta<'a>, errors: &mut Errors) -> MappedLayer {
+        let group = format!("{}-chords", layer.name);
+        let chord_key = |idx: usize| format!("k{idx}");
+
+        let mut chords = Vec::new();
+        for chord in &layer.chords {
+            let keys = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().position(|k| k.layout_pos == *pos))
+                .map(chord_key)
+                .collect();
+
+            if let Some(action) = errors.take(self.map_key(&chord.key)) {
+                chords.push((keys, action));
+            }
+        }
+
+        let mut rows = Vec::new();
+        let mut idx = 0;
+        for (_, row) in &layer.keys.iter().group_by(|k| k.layout_pos.1) {
+            let mut mapped_row = Vec::new();
+
+            for key in row {
+                let in_chord = layer
+                    .chords
+                    .iter()
+                    .any(|c| c.layout_positions.contains(&key.layout_pos));
+
+                if in_chord {
+                    // pressed on its own the key does what it normally would,
+                    // chords can't fall through to the layer below
+                    let action = match &key.effective {
+                        Some(effective) => errors.take(self.map_key(effective)),
+                        None => Some("XX".to_owned()),
+                    };
+
+                    if let Some(action) = action {
+                        chords.push((vec![chord_key(idx)], action));
+                        mapped_row.push(format!("(chord {group} {})", chord_key(idx)));
+                    }
+                } else if let Some(action) = errors.take(self.map_key(&key.key)) {
+                    mapped_row.push(action);
+                }
+
+                idx += 1;
+            }
+
+            rows.push(mapped_row);
+        }
+
+        MappedLayer {
+            name: layer.name.to_string(),
+            rows,
+            chords: (!chords.is_empty()).then_some(MappedChords { group, chords }),
+        }
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let mut errors = Errors::default();
+        let defsrc = errors.take(self.defsrc());
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .map(|layer| self.map_layer(layer, &mut errors))
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+        let defsrc = defsrc.unwrap();
+
+        writeln!(
+            out,
+            ";; Generated by keylayout_lang, edit the layout instead"
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(out, "(defsrc").unwrap();
+        for row in defsrc {
+            writeln!(out, "  {}", row.join(" ")).unwrap();
+        }
+        writeln!(out, ")").unwrap();
+
+        if !self.aliases.is_empty() {
+            writeln!(out).unwrap();
+            writeln!(out, "(defalias").unwrap();
+            for (definition, name) in &self.aliases {
+                writeln!(out, "  {name} {definition}").unwrap();
+            }
+            writeln!(out, ")").unwrap();
+        }
+
+        for chords in layers.iter().filter_map(|l| l.chords.as_ref()) {
+            writeln!(out).unwrap();
+            writeln!(
+                out,
+                "(defchords {} {}",
+                chords.group,
+                self.option_d("chord_timeout", "50")
+            )
+            .unwrap();
+            for (keys, action) in &chords.chords {
+                writeln!(out, "  ({}) {action}", keys.join(" ")).unwrap();
+            }
+            writeln!(out, ")").unwrap();
+        }
+
+        for layer in &layers {
+            writeln!(out).unwrap();
+            writeln!(out, "(deflayer {}", layer.name).unwrap();
+            for row in &layer.rows {
+                writeln!(out, "  {}", row.join(" ")).unwrap();
+            }
+            writeln!(out, ")").unwrap();
+        }
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "kanata")
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        aliases: IndexMap::new(),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+fn modifier_prefix(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "C-",
+        Modifier::Shift(_) => "S-",
+        Modifier::Alt(_) => "A-",
+        Modifier::Gui(_) => "M-",
+    }
+}
+
+/// Rows of an ANSI keyboard from the number row down
+const ANSI_ROWS: [&str; 5] = [
+    "grv 1 2 3 4 5 6 7 8 9 0 min eql",
+    "q w e r t y u i o p lbrc rbrc bksl",
+    "a s d f g h j k l scln apos",
+    "z x c v b n m comm . /",
+    "lctl lmet lalt spc ralt rmet rctl",
+];
+
+fn predefined_named_keys() -> HashMap<String, String> {
+    let mut keys = NAMED_KEYCODES.clone();
+
+    keys.insert("n".to_owned(), "XX".to_owned());
+
+    keys
+}
+
+static NAMED_KEYCODES: Lazy<HashMap<String, String>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, String> {
+    keycodes::named_keycodes(|k| &k.kanata)
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+}
+
+static CHAR_KEYCODES: Lazy<HashMap<char, String>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, String> {
+    let mut keys = HashMap::new();
+
+    for k in ('a'..='z').chain('0'..='9') {
+        keys.insert(k, k.to_string());
+    }
+
+    let unshifted = [
+        ('-', "min"),
+        ('=', "eql"),
+        ('[', "lbrc"),
+        (']', "rbrc"),
+        ('\\', "bksl"),
+        (';', "scln"),
+        ('\'', "apos"),
+        ('`', "grv"),
+        (',', "comm"),
+        ('.', "."),
+        ('/', "/"),
+    ];
+
+    let shifted = [
+        ('!', "1"),
+        ('@', "2"),
+        ('#', "3"),
+        ('$', "4"),
+        ('%', "5"),
+        ('^', "6"),
+        ('&', "7"),
+        ('*', "8"),
+        ('(', "9"),
+        (')', "0"),
+        ('_', "min"),
+        ('+', "eql"),
+        ('{', "lbrc"),
+        ('}', "rbrc"),
+        ('|', "bksl"),
+        (':', "scln"),
+        ('"', "apos"),
+        ('~', "grv"),
+        ('<', "comm"),
+        ('>', "."),
+        ('?', "/"),
+    ];
+
+    keys.extend(unshifted.map(|(c, k)| (c, k.to_string())));
+    keys.extend(shifted.map(|(c, k)| (c, format!("S-{k}"))));
+
+    keys
+}
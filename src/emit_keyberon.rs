@@ -0,0 +1,579 @@
+use std::{collections::HashMap, io::Write};
+
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{
+        unknown_layer, unknown_named_key, LayerMeta, MatrixPosition, Metadata, OptionKey,
+        ResolvedChord,
+    },
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+/// A firmware built on keyberon, decides which options block is read and
+/// what the options default to
+pub struct Preset {
+    pub options: OptionKey,
+    pub backend: &'static str,
+    pub defaults: &'static [(&'static str, &'static str)],
+}
+
+pub const KEYBERON: Preset = Preset {
+    options: OptionKey::Keyberon,
+    backend: "keyberon",
+    defaults: &[],
+};
+
+#[derive(Clone, Debug)]
+struct MatrixKey(String);
+
+/// How chords are turned into key presses by the firmware
+enum ChordStrategy<'a> {
+    /// Keyberon's own `Chording`, fed from a static of `ChordDef`s
+    Keyberon {
+        chords_static: &'a str,
+    },
+    /// A function building the firmware's own chorder through a macro
+    Macro {
+        chorder_type: &'a str,
+        chords_macro: &'a str,
+    },
+    None,
+}
+
+struct Emit<'a> {
+    named_keys: HashMap<String, MatrixKey>,
+    extra_allocated_rows: u8,
+    extra_allocated_cols: u8,
+    chord_table: HashMap<Vec<MatrixPosition>, MatrixPosition>,
+
+    preset: &'a Preset,
+    metadata: &'a Metadata<'a>,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata
+            .get_option(self.preset.options, key)
+            .or_else(|| {
+                self.preset
+                    .defaults
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| *v)
+            })
+    }
+
+    fn option_d<'d: 'a>(&self, key: &str, default: &'d str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn required_option(&self, key: &str) -> miette::Result<&'a str> {
+        self.option(key).ok_or_else(|| {
+            AppError::OptionRequired {
+                option_name: key.to_string(),
+                backend: self.preset.backend.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn chord_strategy(&self) -> miette::Result<ChordStrategy<'a>> {
+        match self.option_d("chords", "keyberon") {
+            "keyberon" => Ok(ChordStrategy::Keyberon {
+                chords_static: self.option_d("chords_static", "CHORDS"),
+            }),
+            "macro" => Ok(ChordStrategy::Macro {
+                chorder_type: self.required_option("chorder_type")?,
+                chords_macro: self.required_option("chords_macro")?,
+            }),
+            "none" => Ok(ChordStrategy::None),
+            other => Err(AppError::InvalidOption {
+                option_name: "chords".to_string(),
+                backend: self.preset.backend.to_string(),
+                problem: format!(
+                    "Unknown chord strategy {other}, it should be one of keyberon, macro or none"
+                ),
+            }
+            .into()),
+        }
+    }
+
+    fn allocate_extra_key(&mut self, positions: Vec<MatrixPosition>) -> MatrixPosition {
+        if self.extra_allocated_rows == 0 {
+            self.extra_allocated_rows = 1;
+        }
+
+        let pos = MatrixPosition(
+            self.extra_allocated_cols,
+            self.extra_allocated_rows + self.metadata.layout.height - 1,
+        );
+
+        self.extra_allocated_cols += 1;
+
+        if self.extra_allocated_cols >= self.metadata.layout.width {
+            self.extra_allocated_cols = 0;
+            self.extra_allocated_rows += 1;
+        }
+
+        self.chord_table.insert(positions, pos);
+
+        pos
+    }
+
+    fn process_chord(&mut self, chord: &ResolvedChord<'a>) -> MatrixPosition {
+        // the same keys pressed together on different layers share a position
+        let mut positions = chord.matrix_positions.clone();
+        positions.sort();
+
+        self.chord_table
+            .get(&positions)
+            .copied()
+            .unwrap_or_else(|| self.allocate_extra_key(positions))
+    }
+
+    fn process_layer(
+        &mut self,
+        layer: &'a LayerMeta<'a>,
+        chords: &ChordStrategy<'_>,
+        errors: &mut Errors,
+    ) -> HashMap<MatrixPosition, &'a Key<'a>> {
+        let _layer_idx = *self.metadata.layers.layer_map.get(layer.name).unwrap() as u8;
+
+        let mut matrix = HashMap::new();
+        for chord in &layer.chords {
+            if let ChordStrategy::None = chords {
+                errors.push(AppError::UnsupportedByBackend {
+                    span: chord.key.span(),
+                    backend: self.preset.backend.to_string(),
+                    what: "chords when the chords option is none".to_string(),
+                });
+                continue;
+            }
+
+            let pos = self.process_chord(chord);
+            matrix.insert(pos, &chord.key);
+        }
+
+        for key in &layer.keys {
+            matrix.insert(key.matrix_pos, &key.key);
+        }
+
+        matrix
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<MatrixKey> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span: _,
+            } => {
+                let tap = self.map_plain_key(tap)?.0;
+                let hold = self.map_plain_key(hold)?.0;
+
+                let config = match at {
+                    ModTapType::Permissive(_) => "PermissiveHold",
+                    ModTapType::OnOtherKey(_) => "HoldOnOtherKeyPress",
+                };
+
+                let a = format!(
+                    r#"::keyberon::action::Action::HoldTap(
+    &::keyberon::action::HoldTapAction {{
+        timeout: {},
+        hold: {hold},
+        tap: {tap},
+        config: ::keyberon::action::HoldTapConfig::{},
+        tap_hold_interval: {},
+    }})"#,
+                    timeout.as_ref().map_or_else(
+                        || self.option_d("hold_tap_timeout", "400").to_string(),
+                        |t| t.timeout.to_string()
+                    ),
+                    config,
+                    self.option_d("hold_tap_interval", "200")
+                );
+
+                Ok(MatrixKey(a))
+            }
+        }
+    }
+
+    fn map_plain_key(&mut self, p: &PlainKey<'_>) -> miette::Result<MatrixKey> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                return Err(unknown_named_key(self.named_keys.keys(), name).into());
+            }
+            PlainKey::Trans(_) => Ok(MatrixKey("::keyberon::action::Action::Trans".to_owned())),
+            PlainKey::Layer {
+                left_square: _,
+                layer,
+                right_square: _,
+                span: _,
+            } => {
+                let idx = self.layer_index(layer)?;
+                Ok(MatrixKey(format!(
+                    "::keyberon::action::Action::Layer({idx})"
+                )))
+            }
+            PlainKey::LayerSwitch {
+                kind,
+                left_paren: _,
+                layer,
+                right_paren: _,
+                span,
+            } => {
+                let idx = self.layer_index(layer)?;
+
+                // keyberon can only change the default layer itself, the others
+                // have to be handled by the firmware through a custom action
+                let (action, what) = match kind {
+                    LayerSwitchKind::Default(_) => {
+                        return Ok(MatrixKey(format!(
+                            "::keyberon::action::Action::DefaultLayer({idx})"
+                        )))
+                    }
+                    LayerSwitchKind::Toggle(_) => ("toggle_layer_action", "toggling layers"),
+                    LayerSwitchKind::OneShot(_) => ("oneshot_layer_action", "one-shot layers"),
+                };
+
+                let Some(template) = self.option(action) else {
+                    return Err(AppError::LayerSwitchActionRequired {
+                        span: *span,
+                        option_name: action.to_string(),
+                        backend: self.preset.backend.to_string(),
+                        what: what.to_string(),
+                    }
+                    .into());
+                };
+
+                Ok(MatrixKey(template.replace("{layer}", &idx.to_string())))
+            }
+            PlainKey::Char {
+                left_quote: _,
+                c,
+                right_quote: _,
+                span,
+            } => {
+                if let Some(a) = CHAR_KEYS.get(c) {
+                    return Ok(a.clone());
+                }
+
+                return Err(AppError::UnknownKey {
+                    span: *span,
+                    key: *c,
+                }
+                .into());
+            }
+            PlainKey::Modified {
+                modifiers,
+                key,
+                span: _,
+            } => {
+                let mut keycodes = modifiers.iter().map(modifier_code).collect::<Vec<_>>();
+                keycodes.extend(self.keycodes(key)?);
+
+                Ok(codes(&keycodes))
+            }
+        }
+    }
+
+    /// The keycodes a key sends, for keys that can be combined with modifiers
+    fn keycodes(&mut self, p: &PlainKey<'_>) -> miette::Result<Vec<String>> {
+        let keycodes = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c),
+            _ => None,
+        };
+
+        if let Some(keycodes) = keycodes {
+            return Ok(keycodes.clone());
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    fn layer_index(&self, layer: &Ident<'_>) -> miette::Result<usize> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        layer_map
+            .get(layer.s)
+            .copied()
+            .ok_or_else(|| unknown_layer(layer_map, layer).into())
+    }
+
+    fn map_keys(
+        &mut self,
+        matrix: HashMap<MatrixPosition, &'a Key<'a>>,
+        errors: &mut Errors,
+    ) -> HashMap<MatrixPosition, MatrixKey> {
+        matrix
+            .into_iter()
+            .filter_map(|(k, v)| Some((k, errors.take(self.map_key(v))?)))
+            .collect()
+    }
+
+    fn render_chords(&self, chords: &ChordStrategy<'_>, out: &mut impl Write) {
+        let chords_of = |positions: &[MatrixPosition]| {
+            positions
+                .iter()
+                .map(|p| format!("({}, {})", p.1, p.0))
+                .join(", ")
+        };
+
+        match chords {
+            ChordStrategy::Keyberon { chords_static } => {
+                writeln!(
+                    out,
+                    "pub static {chords_static}: [::keyberon::chording::ChordDef; {}] = [",
+                    self.chord_table.len()
+                )
+                .unwrap();
+
+                for (positions, map) in &self.chord_table {
+                    let positions = chords_of(positions);
+
+                    writeln!(out, "    (({}, {}), &[{positions}]),", map.1, map.0).unwrap();
+                }
+
+                writeln!(out, "];").unwrap();
+            }
+            ChordStrategy::Macro {
+                chorder_type,
+                chords_macro,
+            } => {
+                writeln!(out, "pub fn chorder() -> {chorder_type} {{").unwrap();
+                writeln!(out, "    {chords_macro}!(").unwrap();
+
+                for (positions, map) in &self.chord_table {
+                    let positions = chords_of(positions);
+
+                    writeln!(out, "        [{positions}] => [({}, {})],", map.1, map.0).unwrap();
+                }
+
+                writeln!(out, "    )").unwrap();
+                writeln!(out, "}}").unwrap();
+            }
+            ChordStrategy::None => {}
+        }
+    }
+
+    fn render_matrix(&self, matrix: HashMap<MatrixPosition, MatrixKey>, out: &mut impl Write) {
+        writeln!(out, "  [").unwrap();
+        for y in 0..(self.metadata.layout.height + self.extra_allocated_rows) {
+            write!(out, "    [").unwrap();
+            for x in 0..self.metadata.layout.width {
+                if let Some(k) = matrix.get(&MatrixPosition(x, y)) {
+                    write!(out, "{}, ", k.0).unwrap();
+                } else {
+                    write!(out, "::keyberon::action::Action::NoOp, ").unwrap();
+                }
+            }
+            writeln!(out, "],").unwrap();
+        }
+        writeln!(out, "  ],").unwrap();
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let chords = self.chord_strategy()?;
+        let mut layer_matrices = Vec::new();
+        let mut errors = Errors::default();
+
+        for layer in &self.metadata.layers.layers {
+            let matrix = self.process_layer(layer, &chords, &mut errors);
+
+            layer_matrices.push(self.map_keys(matrix, &mut errors));
+        }
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        self.render_chords(&chords, out);
+
+        let cols = self.metadata.layout.width;
+        let rows = self.metadata.layout.height + self.extra_allocated_rows;
+        let num_layers = layer_matrices.len();
+        writeln!(
+            out,
+            "pub static {}: ::keyberon::layout::Layers<{cols}, {rows}, {num_layers}, {}> = [",
+            self.option_d("layers_static", "LAYERS"),
+            self.option_d("custom_event", "()")
+        )
+        .unwrap();
+
+        for matrix in layer_matrices {
+            self.render_matrix(matrix, out);
+        }
+
+        writeln!(out, "];").unwrap();
+
+        Ok(())
+    }
+}
+
+/// Keyberon has no actions for `toggle(x)` or `oneshot(x)`, so those are
+/// emitted from the `toggle_layer_action` and `oneshot_layer_action` options,
+/// which are templates for the firmware's own action with `{layer}` in place of
+/// the layer index, e.g. `"::keyberon::action::Action::Custom(super::CustomEvent::ToggleLayer({layer}))"`
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    preset: &'a Preset,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .filter(|d| d.name.s == "keyberon")
+                .next()
+                .map(|d| (k.name.s.to_string(), MatrixKey(d.output.text.to_string())))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        preset,
+        metadata,
+
+        named_keys,
+        extra_allocated_rows: 0,
+        extra_allocated_cols: 0,
+        chord_table: HashMap::new(),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+fn kc(name: &str) -> String {
+    format!("::keyberon::key_code::KeyCode::{name}")
+}
+
+fn pl(key: String) -> MatrixKey {
+    MatrixKey(format!("::keyberon::action::Action::KeyCode({})", key))
+}
+
+/// An action pressing all of the given keycodes together
+fn codes(codes: &[String]) -> MatrixKey {
+    match codes {
+        [code] => pl(kc(code)),
+        codes => MatrixKey(format!(
+            "::keyberon::action::Action::MultipleKeyCodes(&[{}].as_slice())",
+            codes.iter().map(|c| kc(c)).join(", ")
+        )),
+    }
+}
+
+fn one(code: &str) -> Vec<String> {
+    vec![code.to_owned()]
+}
+
+fn sh(code: &str) -> Vec<String> {
+    vec!["LShift".to_owned(), code.to_owned()]
+}
+
+fn modifier_code(modifier: &Modifier) -> String {
+    match modifier {
+        Modifier::Ctrl(_) => "LCtrl",
+        Modifier::Shift(_) => "LShift",
+        Modifier::Alt(_) => "LAlt",
+        Modifier::Gui(_) => "LGui",
+    }
+    .to_owned()
+}
+
+fn predefined_named_keys() -> HashMap<String, MatrixKey> {
+    let mut keys: HashMap<_, _> = NAMED_KEYCODES
+        .iter()
+        .map(|(k, v)| (k.clone(), codes(v)))
+        .collect();
+
+    keys.insert(
+        "n".to_owned(),
+        MatrixKey("::keyberon::action::Action::NoOp".to_owned()),
+    );
+
+    keys
+}
+
+static NAMED_KEYCODES: Lazy<HashMap<String, Vec<String>>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, Vec<String>> {
+    keycodes::named_keycodes(|k| &k.keyberon)
+        .map(|(k, v)| (k.to_owned(), one(v)))
+        .collect()
+}
+
+static CHAR_KEYS: Lazy<HashMap<char, MatrixKey>> =
+    Lazy::new(|| CHAR_KEYCODES.iter().map(|(c, v)| (*c, codes(v))).collect());
+
+static CHAR_KEYCODES: Lazy<HashMap<char, Vec<String>>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    for k in 'a'..='z' {
+        keys.insert(k, one(&k.to_ascii_uppercase().to_string()));
+    }
+
+    for k in '0'..='9' {
+        keys.insert(k, one(&format!("Kb{k}")));
+    }
+
+    keys.extend([
+        ('!', sh("Kb1")),
+        ('@', sh("Kb2")),
+        ('#', sh("Kb3")),
+        ('$', sh("Kb4")),
+        ('%', sh("Kb5")),
+        ('^', sh("Kb6")),
+        ('&', sh("Kb7")),
+        ('*', sh("Kb8")),
+        ('(', sh("Kb9")),
+        (')', sh("Kb0")),
+        ('-', one("Minus")),
+        ('_', sh("Minus")),
+        ('=', one("Equal")),
+        ('+', sh("Equal")),
+        ('[', one("LBracket")),
+        ('{', sh("LBracket")),
+        (']', one("RBracket")),
+        ('}', sh("RBracket")),
+        ('\\', one("Bslash")),
+        ('|', sh("Bslash")),
+        (';', one("SColon")),
+        (':', sh("SColon")),
+        ('\'', one("Quote")),
+        ('"', sh("Quote")),
+        ('`', one("Grave")),
+        ('~', sh("Grave")),
+        (',', one("Comma")),
+        ('<', sh("Comma")),
+        ('.', one("Dot")),
+        ('>', sh("Dot")),
+        ('/', one("Slash")),
+        ('?', sh("Slash")),
+    ]);
+
+    keys
+}
@@ -2,12 +2,12 @@ use std::{collections::HashMap, io::Write};
 
 use indexmap::IndexMap;
 use itertools::Itertools;
-use ngrammatic::CorpusBuilder;
 
 use crate::{
-    errors::AppError,
-    process::Metadata,
-    syntax::{File, Key, KeyOrChord, PlainKey},
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_named_key, Metadata},
+    syntax::{File, Key, LayerSwitchKind, Modifier, PlainKey},
 };
 
 #[derive(Debug, serde::Serialize)]
@@ -39,6 +39,8 @@ struct KeySpec {
     tap: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     hold: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    type_: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -46,7 +48,7 @@ struct CombosSpec(Vec<ComboSpec>);
 
 #[derive(Debug, serde::Serialize)]
 struct ComboSpec {
-    key_positions: (usize, usize),
+    key_positions: Vec<usize>,
     key: KeySpec,
     layers: Vec<String>,
 }
@@ -70,53 +72,76 @@ pub fn emit<'a>(
 
     named_keys.extend(predefined_named_keys());
 
-    let convert_plain_key = |k: &PlainKey<'a>| -> miette::Result<Option<String>> {
+    let convert_unmodified = |k: &PlainKey<'a>| -> miette::Result<Option<String>> {
         match k {
             PlainKey::Named(name) => {
                 if let Some(k) = named_keys.get(name.s) {
                     return Ok(k.clone());
                 }
 
-                let mut possible_names = CorpusBuilder::new().case_insensitive().finish();
-
-                for name in named_keys.keys() {
-                    possible_names.add_text(name);
-                }
-
-                let similar = possible_names
-                    .search(name.s, 0.40)
-                    .into_iter()
-                    .map(|s| s.text)
-                    .join(", ");
-
-                return Err(AppError::UnknownNamedKey {
-                    span: name.span,
-                    key: name.s.to_string(),
-                    similar,
-                }
-                .into());
+                return Err(unknown_named_key(named_keys.keys(), name).into());
             }
+            PlainKey::Trans(_) => Ok(None),
             PlainKey::Layer {
                 left_square: _,
                 layer,
                 right_square: _,
                 span: _,
             } => Ok(Some(layer.s.to_string())),
+            PlainKey::LayerSwitch {
+                kind,
+                left_paren: _,
+                layer,
+                right_paren: _,
+                span: _,
+            } => Ok(Some(format!("{} {}", layer_switch_label(kind), layer.s))),
             PlainKey::Char {
                 left_quote: _,
                 c,
                 right_quote: _,
                 span: _,
             } => Ok(Some(format!("{} ", c))),
+            PlainKey::Modified { .. } => {
+                unreachable!("the parser only allows modifiers on named and char keys")
+            }
         }
     };
 
+    let convert_plain_key = |k: &PlainKey<'a>| -> miette::Result<Option<String>> {
+        let PlainKey::Modified {
+            modifiers,
+            key,
+            span: _,
+        } = k
+        else {
+            return convert_unmodified(k);
+        };
+
+        let legend = convert_unmodified(key)?.unwrap_or_default();
+        let modifiers = modifiers.iter().map(modifier_label).join("+");
+
+        Ok(Some(format!("{modifiers}+{}", legend.trim())))
+    };
+
     let convert_key = |k: &Key<'a>| -> miette::Result<KeySpec> {
         match k {
+            Key::Plain(PlainKey::Trans(_)) => Ok(KeySpec {
+                tap: None,
+                hold: None,
+                type_: Some("trans".to_string()),
+            }),
+            // shown the same way keymap-drawer shows these when parsing qmk/zmk
+            Key::Plain(PlainKey::LayerSwitch { kind, layer, .. }) => Ok(KeySpec {
+                tap: Some(layer.s.to_string()),
+                hold: Some(layer_switch_label(kind).to_string()),
+                type_: None,
+            }),
             Key::Plain(k) => Ok(KeySpec {
                 tap: convert_plain_key(k)?,
                 hold: None,
+                type_: None,
             }),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
             Key::ModTap {
                 tap,
                 at: _,
@@ -126,38 +151,54 @@ pub fn emit<'a>(
             } => Ok(KeySpec {
                 tap: convert_plain_key(tap)?,
                 hold: convert_plain_key(hold)?,
+                type_: None,
             }),
         }
     };
 
     let mut combos = Vec::new();
     let mut layers = IndexMap::new();
+    let mut errors = Errors::default();
 
-    for layer in &file.layers {
+    // work from the processed layers so that anything inherited is included
+    for layer in &metadata.layers.layers {
         let mut layer_r = Vec::new();
-        let mut idx = 0;
-        for row in &layer.rows {
-            let mut row_r = Vec::new();
-            for key in &row.items {
-                match key {
-                    KeyOrChord::Key(k) => {
-                        let key_r = convert_key(k)?;
-                        row_r.push(key_r);
-
-                        idx += 1;
-                    }
-                    KeyOrChord::Chord(c) => combos.push(ComboSpec {
-                        key_positions: (idx - 1, idx),
-                        key: convert_key(&c.key)?,
-                        layers: vec![layer.name.s.to_string()],
-                    }),
-                };
-            }
-            layer_r.push(row_r);
+        for (_, row) in &layer.keys.iter().group_by(|k| k.layout_pos.1) {
+            layer_r.push(
+                row.filter_map(|k| errors.take(convert_key(&k.key)))
+                    .collect(),
+            );
+        }
+
+        for chord in &layer.chords {
+            let key_positions = chord
+                .layout_positions
+                .iter()
+                .map(|pos| {
+                    layer
+                        .keys
+                        .iter()
+                        .position(|k| k.layout_pos == *pos)
+                        .unwrap()
+                })
+                .collect();
+
+            let Some(key) = errors.take(convert_key(&chord.key)) else {
+                continue;
+            };
+
+            combos.push(ComboSpec {
+                key_positions,
+                key,
+                layers: vec![layer.name.to_string()],
+            });
         }
-        layers.insert(layer.name.s.to_string(), LayerSpec(layer_r));
+
+        layers.insert(layer.name.to_string(), LayerSpec(layer_r));
     }
 
+    errors.finish()?;
+
     let get_option = |k| -> miette::Result<&str> {
         if let Some(r) = metadata.get_option(crate::process::OptionKey::KeymapDrawer, k) {
             Ok(r)
@@ -187,38 +228,30 @@ pub fn emit<'a>(
     Ok(())
 }
 
-fn predefined_named_keys() -> HashMap<String, Option<String>> {
-    let mut keys: HashMap<_, _> = [
-        ("esc", Some("Escape".to_string())),
-        ("space", Some("Space".to_string())),
-        ("bspace", Some("BSpace".to_string())),
-        ("del", Some("Delete".to_string())),
-        ("lshift", Some("LShift".to_string())),
-        ("rshift", Some("RShift".to_string())),
-        ("lctrl", Some("LCtrl".to_string())),
-        ("rctrl", Some("RCtrl".to_string())),
-        ("lalt", Some("LAlt".to_string())),
-        ("ralt", Some("RAlt".to_string())),
-        ("lgui", Some("LGui".to_string())),
-        ("rgui", Some("RGui".to_string())),
-        ("enter", Some("Enter".to_string())),
-        ("tab", Some("Tab".to_string())),
-        ("n", None),
-        ("pgup", Some("PgUp".to_string())),
-        ("pgdown", Some("PgDown".to_string())),
-        ("volup", Some("VolUp".to_string())),
-        ("voldown", Some("VolDown".to_string())),
-        ("left", Some("Left".to_string())),
-        ("up", Some("Up".to_string())),
-        ("right", Some("Right".to_string())),
-        ("down", Some("Down".to_string())),
-        ("end", Some("End".to_string())),
-    ]
-    .into_iter()
-    .map(|(k, v)| (k.to_string(), v))
-    .collect();
-
-    keys.extend((1..=10).map(|n| (format!("f{n}"), Some(format!("F{n}")))));
+pub fn modifier_label(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "Ctrl",
+        Modifier::Shift(_) => "Shift",
+        Modifier::Alt(_) => "Alt",
+        Modifier::Gui(_) => "Gui",
+    }
+}
+
+pub fn layer_switch_label(kind: &LayerSwitchKind) -> &'static str {
+    match kind {
+        LayerSwitchKind::Toggle(_) => "toggle",
+        LayerSwitchKind::OneShot(_) => "sticky",
+        LayerSwitchKind::Default(_) => "default",
+    }
+}
+
+pub fn predefined_named_keys() -> HashMap<String, Option<String>> {
+    let mut keys: HashMap<_, _> = keycodes::KEYCODES
+        .iter()
+        .map(|k| (k.name.clone(), Some(k.legend.clone())))
+        .collect();
+
+    keys.insert("n".to_owned(), None);
 
     keys
 }
@@ -0,0 +1,422 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+};
+
+use indexmap::IndexMap;
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_layer, unknown_named_key, LayerMeta, Metadata},
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Which of KMK's modules the keymap uses
+    modules: BTreeSet<&'static str>,
+    /// Chords of the base layer, by positions, KMK matches them on every
+    /// layer
+    chords: IndexMap<Vec<usize>, String>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+struct MappedLayer {
+    name: String,
+    rows: Vec<Vec<String>>,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata
+            .get_option(crate::process::OptionKey::Kmk, key)
+    }
+
+    fn option_d<'d: 'a>(&self, key: &str, default: &'d str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span: _,
+            } => {
+                let tap = self.map_plain_key(tap)?;
+
+                // permissive holds only hold once the other key is released
+                let tap_interrupted = match at {
+                    ModTapType::Permissive(_) => "True",
+                    ModTapType::OnOtherKey(_) => "False",
+                };
+                let tap_time = timeout.as_ref().map_or_else(
+                    || self.option_d("hold_tap_timeout", "300").to_string(),
+                    |t| t.timeout.to_string(),
+                );
+                let config = format!(
+                    "prefer_hold=True, tap_interrupted={tap_interrupted}, tap_time={tap_time}"
+                );
+
+                if let PlainKey::Layer { layer, .. } = hold {
+                    let layer = self.layer_name(layer)?;
+                    self.modules.insert("Layers");
+
+                    return Ok(format!("KC.LT({layer}, {tap}, {config})"));
+                }
+
+                let hold = self.map_plain_key(hold)?;
+                self.modules.insert("HoldTap");
+
+                Ok(format!("KC.HT({tap}, {hold}, {config})"))
+            }
+        }
+    }
+
+    fn map_plain_key(&mut self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok("KC.TRNS".to_owned()),
+            PlainKey::Layer { layer, .. } => {
+                let layer = self.layer_name(layer)?;
+                self.modules.insert("Layers");
+
+                Ok(format!("KC.MO({layer})"))
+            }
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                let layer = self.layer_name(layer)?;
+                self.modules.insert("Layers");
+
+                Ok(match kind {
+                    LayerSwitchKind::Toggle(_) => format!("KC.TG({layer})"),
+                    LayerSwitchKind::OneShot(_) => {
+                        self.modules.insert("OneShot");
+                        format!("KC.OS(KC.MO({layer}))")
+                    }
+                    LayerSwitchKind::Default(_) => format!("KC.DF({layer})"),
+                })
+            }
+            PlainKey::Char { c, span, .. } => {
+                if let Some(k) = CHAR_KEYCODES.get(c) {
+                    return Ok(k.clone());
+                }
+
+                Err(AppError::UnknownKey {
+                    span: *span,
+                    key: *c,
+                }
+                .into())
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let keycode = self.keycode(key)?;
+
+                Ok(modifiers.iter().rev().fold(keycode, |keycode, modifier| {
+                    format!("{}({keycode})", modifier_function(modifier))
+                }))
+            }
+        }
+    }
+
+    /// The keycode a key sends, for keys that can be combined with modifiers
+    fn keycode(&mut self, p: &PlainKey<'_>) -> miette::Result<String> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode.clone());
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    fn layer_name(&self, layer: &Ident<'_>) -> miette::Result<String> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        if !layer_map.contains_key(layer.s) {
+            return Err(unknown_layer(layer_map, layer).into());
+        }
+
+        Ok(layer_constant_name(layer.s))
+    }
+
+    fn map_layer(
+        &mut self,
+        layer: &'a LayerMeta<'a>,
+        is_base: bool,
+        errors: &mut Errors,
+    ) -> MappedLayer {
+        let mut rows = Vec::new();
+        for (_, row) in &layer.keys.iter().group_by(|k| k.layout_pos.1) {
+            rows.push(
+                row.filter_map(|k| errors.take(self.map_key(&k.key)))
+                    .collect(),
+            );
+        }
+
+        for chord in &layer.chords {
+            let Some(output) = errors.take(self.map_key(&chord.key)) else {
+                continue;
+            };
+
+            let mut positions = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().position(|k| k.layout_pos == *pos))
+                .collect::<Vec<_>>();
+            positions.sort();
+
+            // KMK's combos are on every layer, so other layers can only have
+            // the same ones as the base layer
+            if is_base {
+                self.modules.insert("Combos");
+                self.chords.insert(positions, output);
+            } else if self.chords.get(&positions) != Some(&output) {
+                errors.push(AppError::UnsupportedByBackend {
+                    span: chord.key.span(),
+                    backend: "kmk".to_string(),
+                    what: "chords on other layers that the first layer doesn't have".to_string(),
+                });
+            }
+        }
+
+        MappedLayer {
+            name: layer.name.to_string(),
+            rows,
+        }
+    }
+
+    fn render_setup(&self, out: &mut impl Write) {
+        writeln!(out).unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "def setup(keyboard):").unwrap();
+        writeln!(
+            out,
+            "    \"\"\"Add the modules this keymap needs and use it on the keyboard\"\"\""
+        )
+        .unwrap();
+
+        for module in &self.modules {
+            if *module == "Combos" {
+                writeln!(out, "    combos_module = Combos()").unwrap();
+                writeln!(out, "    combos_module.combos = combos").unwrap();
+                writeln!(out, "    keyboard.modules.append(combos_module)").unwrap();
+            } else {
+                writeln!(out, "    keyboard.modules.append({module}())").unwrap();
+            }
+        }
+
+        writeln!(out, "    keyboard.keymap = keymap").unwrap();
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let mut errors = Errors::default();
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(idx, layer)| self.map_layer(layer, idx == 0, &mut errors))
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        writeln!(
+            out,
+            "# Generated by keylayout_lang, edit the layout instead"
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "from kmk.keys import KC").unwrap();
+        for module in &self.modules {
+            let (path, names) = match *module {
+                "Combos" => ("combos", "Chord, Combos"),
+                "HoldTap" => ("holdtap", "HoldTap"),
+                "Layers" => ("layers", "Layers"),
+                "OneShot" => ("oneshot", "OneShot"),
+                _ => unreachable!(),
+            };
+
+            writeln!(out, "from kmk.modules.{path} import {names}").unwrap();
+        }
+        writeln!(out).unwrap();
+
+        for (idx, layer) in layers.iter().enumerate() {
+            writeln!(out, "{} = {idx}", layer_constant_name(&layer.name)).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        writeln!(out, "keymap = [").unwrap();
+        for layer in &layers {
+            writeln!(out, "    # {}", layer.name).unwrap();
+            writeln!(out, "    [").unwrap();
+            for row in &layer.rows {
+                writeln!(out, "        {},", row.join(", ")).unwrap();
+            }
+            writeln!(out, "    ],").unwrap();
+        }
+        writeln!(out, "]").unwrap();
+
+        if !self.chords.is_empty() {
+            writeln!(out).unwrap();
+            writeln!(out, "combos = [").unwrap();
+            for (positions, output) in &self.chords {
+                writeln!(
+                    out,
+                    "    Chord(({}), {output}, match_coord=True),",
+                    positions.iter().join(", ")
+                )
+                .unwrap();
+            }
+            writeln!(out, "]").unwrap();
+        }
+
+        self.render_setup(out);
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "kmk")
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        modules: BTreeSet::new(),
+        chords: IndexMap::new(),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+/// KMK refers to layers by number, so each gets a constant
+fn layer_constant_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn modifier_function(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "KC.LCTL",
+        Modifier::Shift(_) => "KC.LSFT",
+        Modifier::Alt(_) => "KC.LALT",
+        Modifier::Gui(_) => "KC.LGUI",
+    }
+}
+
+fn predefined_named_keys() -> HashMap<String, String> {
+    let mut keys = NAMED_KEYCODES.clone();
+
+    keys.insert("n".to_owned(), "KC.NO".to_owned());
+
+    keys
+}
+
+static NAMED_KEYCODES: Lazy<HashMap<String, String>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, String> {
+    keycodes::named_keycodes(|k| &k.kmk)
+        .map(|(k, v)| (k.to_owned(), format!("KC.{v}")))
+        .collect()
+}
+
+static CHAR_KEYCODES: Lazy<HashMap<char, String>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, String> {
+    let mut keys = HashMap::new();
+
+    for k in 'a'..='z' {
+        keys.insert(k, format!("KC.{}", k.to_ascii_uppercase()));
+    }
+
+    for k in '0'..='9' {
+        keys.insert(k, format!("KC.N{k}"));
+    }
+
+    let symbols = [
+        ('!', "EXLM"),
+        ('@', "AT"),
+        ('#', "HASH"),
+        ('$', "DLR"),
+        ('%', "PERC"),
+        ('^', "CIRC"),
+        ('&', "AMPR"),
+        ('*', "ASTR"),
+        ('(', "LPRN"),
+        (')', "RPRN"),
+        ('-', "MINS"),
+        ('_', "UNDS"),
+        ('=', "EQL"),
+        ('+', "PLUS"),
+        ('[', "LBRC"),
+        ('{', "LCBR"),
+        (']', "RBRC"),
+        ('}', "RCBR"),
+        ('\\', "BSLS"),
+        ('|', "PIPE"),
+        (';', "SCLN"),
+        (':', "COLN"),
+        ('\'', "QUOT"),
+        ('"', "DQUO"),
+        ('`', "GRV"),
+        ('~', "TILD"),
+        (',', "COMM"),
+        ('<', "LABK"),
+        ('.', "DOT"),
+        ('>', "RABK"),
+        ('/', "SLSH"),
+        ('?', "QUES"),
+    ];
+
+    keys.extend(symbols.map(|(c, k)| (c, format!("KC.{k}"))));
+
+    keys
+}
@@ -0,0 +1,572 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_layer, unknown_named_key, LayerMeta, Metadata},
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Per key settings for mod-taps, QMK only lets these be set by keycode
+    tapping_terms: BTreeMap<String, u32>,
+    permissive_holds: BTreeMap<String, bool>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+/// A layer ready to be written out
+struct MappedLayer {
+    name: String,
+    rows: Vec<Vec<String>>,
+    combos: Vec<MappedCombo>,
+}
+
+struct MappedCombo {
+    keys: Vec<String>,
+    output: String,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata
+            .get_option(crate::process::OptionKey::Qmk, key)
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span,
+            } => {
+                let tap_code = self.tap_keycode(tap)?;
+
+                let keycode = match hold {
+                    PlainKey::Named(name) if MOD_BITS.contains_key(name.s) => {
+                        format!("MT({}, {tap_code})", MOD_BITS[name.s])
+                    }
+                    PlainKey::Layer { layer, .. } => {
+                        format!("LT({}, {tap_code})", self.layer_name(layer)?)
+                    }
+                    _ => {
+                        return Err(AppError::UnsupportedByBackend {
+                            span: hold.span(),
+                            backend: "qmk".to_string(),
+                            what: "holding keys other than modifiers and layers".to_string(),
+                        }
+                        .into())
+                    }
+                };
+
+                // qmk looks these up by keycode, so the same mod-tap in two
+                // places can only have one timeout and flavor
+                if let Some(timeout) = timeout {
+                    if *self
+                        .tapping_terms
+                        .entry(keycode.clone())
+                        .or_insert(timeout.timeout)
+                        != timeout.timeout
+                    {
+                        return Err(AppError::UnsupportedByBackend {
+                            span: timeout.span,
+                            backend: "qmk".to_string(),
+                            what: "the same mod-tap with different timeouts".to_string(),
+                        }
+                        .into());
+                    }
+                }
+
+                let permissive = matches!(at, ModTapType::Permissive(_));
+                if *self
+                    .permissive_holds
+                    .entry(keycode.clone())
+                    .or_insert(permissive)
+                    != permissive
+                {
+                    return Err(AppError::UnsupportedByBackend {
+                        span: *span,
+                        backend: "qmk".to_string(),
+                        what: "the same mod-tap with different flavors".to_string(),
+                    }
+                    .into());
+                }
+
+                Ok(keycode)
+            }
+        }
+    }
+
+    fn map_plain_key(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok("KC_TRNS".to_owned()),
+            PlainKey::Layer { layer, .. } => Ok(format!("MO({})", self.layer_name(layer)?)),
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                let layer = self.layer_name(layer)?;
+
+                Ok(match kind {
+                    LayerSwitchKind::Toggle(_) => format!("TG({layer})"),
+                    LayerSwitchKind::OneShot(_) => format!("OSL({layer})"),
+                    LayerSwitchKind::Default(_) => format!("DF({layer})"),
+                })
+            }
+            PlainKey::Char { c, span, .. } => {
+                if let Some(k) = CHAR_KEYCODES.get(c) {
+                    return Ok(k.0.to_owned());
+                }
+
+                Err(AppError::UnknownKey {
+                    span: *span,
+                    key: *c,
+                }
+                .into())
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let keycode = self.keycode(key)?;
+
+                Ok(modifiers.iter().rev().fold(keycode, |keycode, modifier| {
+                    format!("{}({keycode})", modifier_function(modifier))
+                }))
+            }
+        }
+    }
+
+    /// The keycode a key sends, for keys that can be combined with modifiers
+    fn keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s).copied(),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c).map(|k| k.0),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode.to_owned());
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    /// QMK can only tap basic keycodes in `MT()` and `LT()`
+    fn tap_keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s).copied(),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES
+                .get(c)
+                .filter(|(_, shifted)| !shifted)
+                .map(|k| k.0),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode.to_owned());
+        }
+
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnsupportedByBackend {
+            span: p.span(),
+            backend: "qmk".to_string(),
+            what: "tapping anything but basic keycodes in a mod-tap".to_string(),
+        }
+        .into())
+    }
+
+    fn layer_name(&self, layer: &Ident<'_>) -> miette::Result<String> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        if !layer_map.contains_key(layer.s) {
+            return Err(unknown_layer(layer_map, layer).into());
+        }
+
+        Ok(layer_enum_name(layer.s))
+    }
+
+    fn map_layer(&mut self, layer: &'a LayerMeta<'a>, errors: &mut Errors) -> MappedLayer {
+        let mut rows = Vec::new();
+        for (_, row) in &layer.keys.iter().group_by(|k| k.layout_pos.1) {
+            rows.push(
+                row.filter_map(|k| errors.take(self.map_key(&k.key)))
+                    .collect(),
+            );
+        }
+
+        let mut combos = Vec::new();
+        for chord in &layer.chords {
+            // combos are triggered by keycodes rather than positions, which
+            // are whatever the keys end up as on this layer
+            let keys = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().find(|k| k.layout_pos == *pos))
+                .map(|k| self.map_key(k.effective.as_ref().unwrap_or(&k.key)))
+                .collect::<miette::Result<Vec<_>>>();
+
+            let output = self.map_key(&chord.key);
+
+            if let (Some(keys), Some(output)) = (errors.take(keys), errors.take(output)) {
+                combos.push(MappedCombo { keys, output });
+            }
+        }
+
+        MappedLayer {
+            name: layer_enum_name(layer.name),
+            rows,
+            combos,
+        }
+    }
+
+    fn render_keymaps(&self, layers: &[MappedLayer], layout: &str, out: &mut impl Write) {
+        writeln!(out, "enum layers {{").unwrap();
+        for layer in layers {
+            writeln!(out, "    {},", layer.name).unwrap();
+        }
+        writeln!(out, "}};").unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(
+            out,
+            "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {{"
+        )
+        .unwrap();
+        for layer in layers {
+            writeln!(out, "    [{}] = {layout}(", layer.name).unwrap();
+            let rows = layer
+                .rows
+                .iter()
+                .map(|row| row.join(", "))
+                .join(",\n        ");
+            writeln!(out, "        {rows}").unwrap();
+            writeln!(out, "    ),").unwrap();
+        }
+        writeln!(out, "}};").unwrap();
+    }
+
+    fn render_combos(&self, layers: &[MappedLayer], out: &mut impl Write) {
+        let combos = layers
+            .iter()
+            .flat_map(|l| l.combos.iter().map(move |c| (&l.name, c)))
+            .collect::<Vec<_>>();
+
+        if combos.is_empty() {
+            return;
+        }
+
+        writeln!(out).unwrap();
+        for (idx, (_, combo)) in combos.iter().enumerate() {
+            writeln!(
+                out,
+                "const uint16_t PROGMEM combo_{idx}[] = {{{}, COMBO_END}};",
+                combo.keys.join(", ")
+            )
+            .unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "combo_t key_combos[] = {{").unwrap();
+        for (idx, (_, combo)) in combos.iter().enumerate() {
+            writeln!(out, "    COMBO(combo_{idx}, {}),", combo.output).unwrap();
+        }
+        writeln!(out, "}};").unwrap();
+
+        // chords belong to a layer, but qmk combos work on every layer
+        writeln!(out).unwrap();
+        writeln!(out, "static const uint8_t combo_layers[] = {{").unwrap();
+        for (layer, _) in &combos {
+            writeln!(out, "    {layer},").unwrap();
+        }
+        writeln!(out, "}};").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "bool combo_should_trigger(uint16_t combo_index, combo_t *combo, uint16_t keycode, keyrecord_t *record) {{"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    return get_highest_layer(layer_state | default_layer_state) == combo_layers[combo_index];"
+        )
+        .unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn render_per_key<T: std::fmt::Display>(
+        &self,
+        signature: &str,
+        settings: &BTreeMap<String, T>,
+        default: &str,
+        out: &mut impl Write,
+    ) {
+        writeln!(out).unwrap();
+        writeln!(out, "{signature} {{").unwrap();
+        writeln!(out, "    switch (keycode) {{").unwrap();
+        for (keycode, value) in settings {
+            writeln!(out, "        case {keycode}:").unwrap();
+            writeln!(out, "            return {value};").unwrap();
+        }
+        writeln!(out, "        default:").unwrap();
+        writeln!(out, "            return {default};").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let Some(layout) = self.option("layout") else {
+            return Err(AppError::OptionRequired {
+                option_name: "layout".to_string(),
+                backend: "qmk".to_string(),
+            }
+            .into());
+        };
+
+        let mut errors = Errors::default();
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .map(|layer| self.map_layer(layer, &mut errors))
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        // the per key functions only work if they're switched on
+        let mut defines = Vec::new();
+        if layers.iter().any(|l| !l.combos.is_empty()) {
+            defines.push("COMBO_SHOULD_TRIGGER");
+        }
+        if !self.tapping_terms.is_empty() {
+            defines.push("TAPPING_TERM_PER_KEY");
+        }
+        if !self.permissive_holds.is_empty() {
+            defines.push("PERMISSIVE_HOLD_PER_KEY");
+            defines.push("HOLD_ON_OTHER_KEY_PRESS_PER_KEY");
+        }
+
+        writeln!(
+            out,
+            "// Generated by keylayout_lang, edit the layout instead"
+        )
+        .unwrap();
+        if !defines.is_empty() {
+            writeln!(out, "//").unwrap();
+            writeln!(out, "// Needs the following in config.h:").unwrap();
+            for define in defines {
+                writeln!(out, "//   #define {define}").unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "#include QMK_KEYBOARD_H").unwrap();
+        writeln!(out).unwrap();
+
+        self.render_keymaps(&layers, layout, out);
+        self.render_combos(&layers, out);
+
+        if !self.tapping_terms.is_empty() {
+            self.render_per_key(
+                "uint16_t get_tapping_term(uint16_t keycode, keyrecord_t *record)",
+                &self.tapping_terms,
+                "TAPPING_TERM",
+                out,
+            );
+        }
+
+        if !self.permissive_holds.is_empty() {
+            let on_other_key = self
+                .permissive_holds
+                .iter()
+                .map(|(k, permissive)| (k.clone(), !permissive))
+                .collect::<BTreeMap<_, _>>();
+
+            self.render_per_key(
+                "bool get_permissive_hold(uint16_t keycode, keyrecord_t *record)",
+                &self.permissive_holds,
+                "false",
+                out,
+            );
+            self.render_per_key(
+                "bool get_hold_on_other_key_press(uint16_t keycode, keyrecord_t *record)",
+                &on_other_key,
+                "false",
+                out,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "qmk")
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        tapping_terms: BTreeMap::new(),
+        permissive_holds: BTreeMap::new(),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+/// Layers are referred to through an enum, following the usual QMK style
+fn layer_enum_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    format!("_{name}")
+}
+
+fn modifier_function(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "LCTL",
+        Modifier::Shift(_) => "LSFT",
+        Modifier::Alt(_) => "LALT",
+        Modifier::Gui(_) => "LGUI",
+    }
+}
+
+pub fn predefined_named_keys() -> HashMap<String, String> {
+    let mut keys: HashMap<_, _> = NAMED_KEYCODES
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    keys.insert("n".to_owned(), "KC_NO".to_owned());
+
+    keys
+}
+
+pub static MOD_BITS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("lctrl", "MOD_LCTL"),
+        ("rctrl", "MOD_RCTL"),
+        ("lshift", "MOD_LSFT"),
+        ("rshift", "MOD_RSFT"),
+        ("lalt", "MOD_LALT"),
+        ("ralt", "MOD_RALT"),
+        ("lgui", "MOD_LGUI"),
+        ("rgui", "MOD_RGUI"),
+    ])
+});
+
+pub static NAMED_KEYCODES: Lazy<HashMap<String, &'static str>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, &'static str> {
+    keycodes::named_keycodes(|k| &k.qmk)
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect()
+}
+
+/// Keycodes for chars, and whether they're a shifted alias
+pub static CHAR_KEYCODES: Lazy<HashMap<char, (&'static str, bool)>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, (&'static str, bool)> {
+    const LETTERS: [&str; 26] = [
+        "KC_A", "KC_B", "KC_C", "KC_D", "KC_E", "KC_F", "KC_G", "KC_H", "KC_I", "KC_J", "KC_K",
+        "KC_L", "KC_M", "KC_N", "KC_O", "KC_P", "KC_Q", "KC_R", "KC_S", "KC_T", "KC_U", "KC_V",
+        "KC_W", "KC_X", "KC_Y", "KC_Z",
+    ];
+    const DIGITS: [&str; 10] = [
+        "KC_0", "KC_1", "KC_2", "KC_3", "KC_4", "KC_5", "KC_6", "KC_7", "KC_8", "KC_9",
+    ];
+
+    let mut keys = HashMap::new();
+
+    keys.extend(('a'..='z').zip(LETTERS).map(|(c, k)| (c, (k, false))));
+    keys.extend(('0'..='9').zip(DIGITS).map(|(c, k)| (c, (k, false))));
+
+    keys.extend(
+        [
+            ('-', "KC_MINS"),
+            ('=', "KC_EQL"),
+            ('[', "KC_LBRC"),
+            (']', "KC_RBRC"),
+            ('\\', "KC_BSLS"),
+            (';', "KC_SCLN"),
+            ('\'', "KC_QUOT"),
+            ('`', "KC_GRV"),
+            (',', "KC_COMM"),
+            ('.', "KC_DOT"),
+            ('/', "KC_SLSH"),
+        ]
+        .map(|(c, k)| (c, (k, false))),
+    );
+
+    keys.extend(
+        [
+            ('!', "KC_EXLM"),
+            ('@', "KC_AT"),
+            ('#', "KC_HASH"),
+            ('$', "KC_DLR"),
+            ('%', "KC_PERC"),
+            ('^', "KC_CIRC"),
+            ('&', "KC_AMPR"),
+            ('*', "KC_ASTR"),
+            ('(', "KC_LPRN"),
+            (')', "KC_RPRN"),
+            ('_', "KC_UNDS"),
+            ('+', "KC_PLUS"),
+            ('{', "KC_LCBR"),
+            ('}', "KC_RCBR"),
+            ('|', "KC_PIPE"),
+            (':', "KC_COLN"),
+            ('"', "KC_DQUO"),
+            ('~', "KC_TILD"),
+            ('<', "KC_LT"),
+            ('>', "KC_GT"),
+            ('?', "KC_QUES"),
+        ]
+        .map(|(c, k)| (c, (k, true))),
+    );
+
+    keys
+}
@@ -0,0 +1,569 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+};
+
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_layer, unknown_named_key, LayerMeta, MatrixPosition, Metadata},
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Which of rmk's macros the keymap uses, so only those are imported
+    macros: BTreeSet<&'static str>,
+    /// RMK configures tap-hold for the whole keyboard, so every mod-tap has
+    /// to agree on these
+    permissive_hold: Option<bool>,
+    /// `Some(None)` once a mod-tap is seen that leaves it to RMK's default
+    hold_timeout: Option<Option<u32>>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+struct MappedLayer {
+    name: String,
+    matrix: HashMap<MatrixPosition, String>,
+    combos: Vec<MappedCombo>,
+}
+
+struct MappedCombo {
+    keys: Vec<String>,
+    output: String,
+}
+
+impl<'a> Emit<'a> {
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span,
+            } => {
+                let tap_code = self.tap_keycode(tap)?;
+
+                let permissive = matches!(at, ModTapType::Permissive(_));
+                if *self.permissive_hold.get_or_insert(permissive) != permissive {
+                    return Err(AppError::UnsupportedByBackend {
+                        span: *span,
+                        backend: "rmk".to_string(),
+                        what: "mod-taps with different flavors".to_string(),
+                    }
+                    .into());
+                }
+
+                let hold_timeout = timeout.as_ref().map(|t| t.timeout);
+                if *self.hold_timeout.get_or_insert(hold_timeout) != hold_timeout {
+                    return Err(AppError::UnsupportedByBackend {
+                        span: timeout.as_ref().map_or(*span, |t| t.span),
+                        backend: "rmk".to_string(),
+                        what: "mod-taps with different timeouts".to_string(),
+                    }
+                    .into());
+                }
+
+                match hold {
+                    PlainKey::Named(name) if HOLD_MODIFIERS.contains_key(name.s) => {
+                        self.macros.insert("mt");
+
+                        Ok(format!("mt!({tap_code}, {})", HOLD_MODIFIERS[name.s]))
+                    }
+                    PlainKey::Layer { layer, .. } => {
+                        let idx = self.layer_index(layer)?;
+                        self.macros.insert("lt");
+
+                        Ok(format!("lt!({idx}, {tap_code})"))
+                    }
+                    _ => Err(AppError::UnsupportedByBackend {
+                        span: hold.span(),
+                        backend: "rmk".to_string(),
+                        what: "holding keys other than modifiers and layers".to_string(),
+                    }
+                    .into()),
+                }
+            }
+        }
+    }
+
+    fn map_plain_key(&mut self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    let k = k.clone();
+                    self.use_macro_of(&k);
+                    return Ok(k);
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => {
+                self.macros.insert("a");
+                Ok("a!(Transparent)".to_owned())
+            }
+            PlainKey::Layer { layer, .. } => {
+                let idx = self.layer_index(layer)?;
+                self.macros.insert("mo");
+
+                Ok(format!("mo!({idx})"))
+            }
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                let idx = self.layer_index(layer)?;
+                let name = match kind {
+                    LayerSwitchKind::Toggle(_) => "tg",
+                    LayerSwitchKind::OneShot(_) => "osl",
+                    LayerSwitchKind::Default(_) => "df",
+                };
+                self.macros.insert(name);
+
+                Ok(format!("{name}!({idx})"))
+            }
+            PlainKey::Char { c, span, .. } => {
+                let Some((code, shifted)) = CHAR_KEYCODES.get(c) else {
+                    return Err(AppError::UnknownKey {
+                        span: *span,
+                        key: *c,
+                    }
+                    .into());
+                };
+
+                if *shifted {
+                    self.macros.insert("shifted");
+                    Ok(format!("shifted!({code})"))
+                } else {
+                    self.macros.insert("k");
+                    Ok(format!("k!({code})"))
+                }
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let (code, shifted) = self.keycode(key)?;
+
+                let has = |f: fn(&Modifier) -> bool| modifiers.iter().any(f);
+                let combination = modifier_combination(
+                    false,
+                    has(|m| matches!(m, Modifier::Gui(_))),
+                    has(|m| matches!(m, Modifier::Alt(_))),
+                    shifted || has(|m| matches!(m, Modifier::Shift(_))),
+                    has(|m| matches!(m, Modifier::Ctrl(_))),
+                );
+                self.macros.insert("wm");
+
+                Ok(format!("wm!({code}, {combination})"))
+            }
+        }
+    }
+
+    /// Keys mapped to a plain string still need their macro imported
+    fn use_macro_of(&mut self, key: &str) {
+        for name in ["k", "a"] {
+            if key.starts_with(&format!("{name}!(")) {
+                self.macros.insert(name);
+            }
+        }
+    }
+
+    /// The keycode a key sends and whether it needs shift, for keys that can
+    /// be combined with modifiers
+    fn keycode(&mut self, p: &PlainKey<'_>) -> miette::Result<(&'static str, bool)> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s).map(|k| (*k, false)),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c).copied(),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode);
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    /// `mt!` and `lt!` can only tap a plain keycode
+    fn tap_keycode(&mut self, p: &PlainKey<'_>) -> miette::Result<&'static str> {
+        match self.keycode(p) {
+            Ok((code, false)) => Ok(code),
+            Ok((_, true)) | Err(_) => {
+                self.map_plain_key(p)?;
+
+                Err(AppError::UnsupportedByBackend {
+                    span: p.span(),
+                    backend: "rmk".to_string(),
+                    what: "tapping anything but plain keycodes in a mod-tap".to_string(),
+                }
+                .into())
+            }
+        }
+    }
+
+    fn layer_index(&self, layer: &Ident<'_>) -> miette::Result<usize> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        layer_map
+            .get(layer.s)
+            .copied()
+            .ok_or_else(|| unknown_layer(layer_map, layer).into())
+    }
+
+    fn map_layer(&mut self, layer: &'a LayerMeta<'a>, errors: &mut Errors) -> MappedLayer {
+        // keys go where the layout's remapping puts them in the matrix
+        let matrix = layer
+            .keys
+            .iter()
+            .filter_map(|k| Some((k.matrix_pos, errors.take(self.map_key(&k.key))?)))
+            .collect();
+
+        let mut combos = Vec::new();
+        for chord in &layer.chords {
+            // combos are triggered by the actions of their keys on this layer
+            let keys = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().find(|k| k.layout_pos == *pos))
+                .map(|k| self.map_key(k.effective.as_ref().unwrap_or(&k.key)))
+                .collect::<miette::Result<Vec<_>>>();
+
+            let output = self.map_key(&chord.key);
+
+            if let (Some(keys), Some(output)) = (errors.take(keys), errors.take(output)) {
+                combos.push(MappedCombo { keys, output });
+            }
+        }
+
+        MappedLayer {
+            name: layer.name.to_string(),
+            matrix,
+            combos,
+        }
+    }
+
+    fn render_keymap(&self, layers: &[MappedLayer], out: &mut impl Write) {
+        writeln!(out, "#[rustfmt::skip]").unwrap();
+        writeln!(
+            out,
+            "pub const fn get_default_keymap() -> [[[KeyAction; COL]; ROW]; NUM_LAYER] {{"
+        )
+        .unwrap();
+        writeln!(out, "    [").unwrap();
+        for layer in layers {
+            writeln!(out, "        // {}", layer.name).unwrap();
+            writeln!(out, "        layer!([").unwrap();
+            for y in 0..self.metadata.layout.height {
+                let row = (0..self.metadata.layout.width)
+                    .map(|x| {
+                        layer
+                            .matrix
+                            .get(&MatrixPosition(x, y))
+                            .map_or("a!(No)", String::as_str)
+                    })
+                    .join(", ");
+
+                writeln!(out, "            [{row}],").unwrap();
+            }
+            writeln!(out, "        ]),").unwrap();
+        }
+        writeln!(out, "    ]").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn render_combos(&self, layers: &[MappedLayer], out: &mut impl Write) {
+        let combos = layers
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, l)| l.combos.iter().map(move |c| (idx, c)))
+            .collect::<Vec<_>>();
+
+        if combos.is_empty() {
+            return;
+        }
+
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "pub fn get_default_combos() -> [Combo; {}] {{",
+            combos.len()
+        )
+        .unwrap();
+        writeln!(out, "    [").unwrap();
+        for (layer, combo) in combos {
+            writeln!(
+                out,
+                "        Combo::new([{}], {}, Some({layer})),",
+                combo.keys.join(", "),
+                combo.output
+            )
+            .unwrap();
+        }
+        writeln!(out, "    ]").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let mut errors = Errors::default();
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .map(|layer| self.map_layer(layer, &mut errors))
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        // empty spots in the matrix are filled with `a!(No)`
+        let matrix_size =
+            self.metadata.layout.width as usize * self.metadata.layout.height as usize;
+        if layers.iter().any(|l| l.matrix.len() < matrix_size) {
+            self.macros.insert("a");
+        }
+        self.macros.insert("layer");
+
+        let mut tap_hold = Vec::new();
+        if let Some(permissive) = self.permissive_hold {
+            tap_hold.push(format!("permissive_hold = {permissive}"));
+        }
+        if let Some(Some(timeout)) = self.hold_timeout {
+            tap_hold.push(format!("hold_timeout = \"{timeout}ms\""));
+        }
+
+        writeln!(
+            out,
+            "// Generated by keylayout_lang, edit the layout instead"
+        )
+        .unwrap();
+        if !tap_hold.is_empty() {
+            writeln!(out, "//").unwrap();
+            writeln!(out, "// Needs the following in keyboard.toml:").unwrap();
+            writeln!(out, "//   [behavior.tap_hold]").unwrap();
+            for setting in tap_hold {
+                writeln!(out, "//   {setting}").unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+
+        writeln!(out, "use rmk::action::KeyAction;").unwrap();
+        if layers.iter().any(|l| !l.combos.is_empty()) {
+            writeln!(out, "use rmk::combo::Combo;").unwrap();
+        }
+        if self.macros.contains("mt") || self.macros.contains("wm") {
+            writeln!(out, "use rmk::keycode::ModifierCombination;").unwrap();
+        }
+        writeln!(out, "use rmk::{{{}}};", self.macros.iter().join(", ")).unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(
+            out,
+            "pub(crate) const COL: usize = {};",
+            self.metadata.layout.width
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "pub(crate) const ROW: usize = {};",
+            self.metadata.layout.height
+        )
+        .unwrap();
+        writeln!(out, "pub(crate) const NUM_LAYER: usize = {};", layers.len()).unwrap();
+        writeln!(out).unwrap();
+
+        self.render_keymap(&layers, out);
+        self.render_combos(&layers, out);
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "rmk")
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        macros: BTreeSet::new(),
+        permissive_hold: None,
+        hold_timeout: None,
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+/// Arguments in the order `ModifierCombination::new_from` takes them
+fn modifier_combination(right: bool, gui: bool, alt: bool, shift: bool, ctrl: bool) -> String {
+    format!("ModifierCombination::new_from({right}, {gui}, {alt}, {shift}, {ctrl})")
+}
+
+fn predefined_named_keys() -> HashMap<String, String> {
+    let mut keys: HashMap<_, _> = NAMED_KEYCODES
+        .iter()
+        .map(|(k, v)| (k.to_string(), format!("k!({v})")))
+        .collect();
+
+    keys.insert("n".to_owned(), "a!(No)".to_owned());
+
+    keys
+}
+
+static HOLD_MODIFIERS: Lazy<HashMap<&'static str, String>> = Lazy::new(|| {
+    HashMap::from([
+        (
+            "lctrl",
+            modifier_combination(false, false, false, false, true),
+        ),
+        (
+            "rctrl",
+            modifier_combination(true, false, false, false, true),
+        ),
+        (
+            "lshift",
+            modifier_combination(false, false, false, true, false),
+        ),
+        (
+            "rshift",
+            modifier_combination(true, false, false, true, false),
+        ),
+        (
+            "lalt",
+            modifier_combination(false, false, true, false, false),
+        ),
+        (
+            "ralt",
+            modifier_combination(true, false, true, false, false),
+        ),
+        (
+            "lgui",
+            modifier_combination(false, true, false, false, false),
+        ),
+        (
+            "rgui",
+            modifier_combination(true, true, false, false, false),
+        ),
+    ])
+});
+
+static NAMED_KEYCODES: Lazy<HashMap<String, &'static str>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, &'static str> {
+    keycodes::named_keycodes(|k| &k.rmk)
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect()
+}
+
+/// Keycodes for chars, and whether they need shift
+static CHAR_KEYCODES: Lazy<HashMap<char, (&'static str, bool)>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, (&'static str, bool)> {
+    const LETTERS: [&str; 26] = [
+        "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R",
+        "S", "T", "U", "V", "W", "X", "Y", "Z",
+    ];
+    const DIGITS: [&str; 10] = [
+        "Kc0", "Kc1", "Kc2", "Kc3", "Kc4", "Kc5", "Kc6", "Kc7", "Kc8", "Kc9",
+    ];
+
+    let mut keys = HashMap::new();
+
+    keys.extend(('a'..='z').zip(LETTERS).map(|(c, k)| (c, (k, false))));
+    keys.extend(('0'..='9').zip(DIGITS).map(|(c, k)| (c, (k, false))));
+
+    let pairs = [
+        ('-', '_', "Minus"),
+        ('=', '+', "Equal"),
+        ('[', '{', "LeftBracket"),
+        (']', '}', "RightBracket"),
+        ('\\', '|', "Backslash"),
+        (';', ':', "Semicolon"),
+        ('\'', '"', "Quote"),
+        ('`', '~', "Grave"),
+        (',', '<', "Comma"),
+        ('.', '>', "Dot"),
+        ('/', '?', "Slash"),
+    ];
+
+    for (c, shifted, k) in pairs {
+        keys.insert(c, (k, false));
+        keys.insert(shifted, (k, true));
+    }
+
+    let shifted_digits = ['!', '@', '#', '$', '%', '^', '&', '*', '(', ')'];
+    keys.extend(
+        shifted_digits
+            .into_iter()
+            .zip(DIGITS[1..].iter().chain(&DIGITS[..1]))
+            .map(|(c, k)| (c, (*k, true))),
+    );
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{input::Input as _, Parser as _};
+
+    use super::*;
+    use crate::parse;
+
+    fn emit_layer(keys: &str) -> miette::Result<String> {
+        let text = format!("layout {{\n  2k;\n}}\n\nlayer base {{\n  {keys};\n}}\n");
+        let file = parse::file()
+            .parse(text.as_str().with_context(0))
+            .into_result()
+            .unwrap();
+        let metadata = Metadata::process(&file)?;
+
+        let mut out = Vec::new();
+        emit(&file, &metadata, &mut out)?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn unset_timeouts_conflict_with_explicit_ones() {
+        for keys in ["'a'@[150]lshift 'b'@lshift", "'a'@lshift 'b'@[150]lshift"] {
+            let error = emit_layer(keys).unwrap_err();
+
+            assert_eq!(
+                error.to_string(),
+                "rmk doesn't support mod-taps with different timeouts"
+            );
+        }
+    }
+
+    #[test]
+    fn matching_timeouts_are_set_for_the_keyboard() {
+        let out = emit_layer("'a'@[150]lshift 'b'@[150]lshift").unwrap();
+        assert!(out.contains("hold_timeout = \"150ms\""));
+
+        let out = emit_layer("'a'@lshift 'b'@lshift").unwrap();
+        assert!(!out.contains("hold_timeout"));
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::Write;
+
+use crate::{
+    errors::Errors,
+    legend::{LayerLegends, Legend, Legends},
+    process::Metadata,
+    syntax::File,
+};
+
+const KEY_SIZE: f64 = 56.0;
+const KEY_SPACING: f64 = 60.0;
+const CHORD_SIZE: f64 = 28.0;
+const MARGIN: f64 = 12.0;
+const TITLE_HEIGHT: f64 = 28.0;
+
+const STYLE: &str = "
+    rect { stroke: #c9cccf; stroke-width: 1; }
+    rect.key { fill: #fdfdfd; }
+    rect.trans { fill: #f4f4f4; }
+    rect.chord { fill: #e6eef7; }
+    text { text-anchor: middle; dominant-baseline: middle; font-family: sans-serif; font-size: 14px; fill: #24292e; }
+    text.hold { font-size: 10px; fill: #6a737d; }
+    text.chord { font-size: 10px; }
+    text.title { text-anchor: start; font-size: 16px; font-weight: bold; }
+";
+
+struct Emit<'a> {
+    legends: Legends,
+
+    metadata: &'a Metadata<'a>,
+}
+
+impl<'a> Emit<'a> {
+    fn render_layer(&self, layer: &LayerLegends, top: f64, out: &mut impl Write) {
+        writeln!(out, r#"  <g transform="translate(0, {top})">"#).unwrap();
+        writeln!(
+            out,
+            r#"    <text class="title" x="{MARGIN}" y="{}">{}</text>"#,
+            TITLE_HEIGHT / 2.0,
+            escape(&layer.name)
+        )
+        .unwrap();
+
+        for (phys, legend) in &layer.keys {
+            let (x, y) = key_centre(*phys);
+            let class = if legend.trans { "trans" } else { "key" };
+
+            writeln!(
+                out,
+                r#"    <rect class="{class}" x="{}" y="{}" width="{KEY_SIZE}" height="{KEY_SIZE}" rx="6"/>"#,
+                x - KEY_SIZE / 2.0,
+                y - KEY_SIZE / 2.0,
+            )
+            .unwrap();
+            self.render_legend(legend, x, y, KEY_SIZE, "tap", out);
+        }
+
+        for (positions, legend) in &layer.chords {
+            // chords sit in the middle of their keys
+            let count = positions.len() as f64;
+            let x = positions.iter().map(|&p| key_centre(p).0).sum::<f64>() / count;
+            let y = positions.iter().map(|&p| key_centre(p).1).sum::<f64>() / count;
+
+            writeln!(
+                out,
+                r#"    <rect class="chord" x="{}" y="{}" width="{CHORD_SIZE}" height="{CHORD_SIZE}" rx="4"/>"#,
+                x - CHORD_SIZE / 2.0,
+                y - CHORD_SIZE / 2.0,
+            )
+            .unwrap();
+            self.render_legend(legend, x, y, CHORD_SIZE, "chord", out);
+        }
+
+        writeln!(out, "  </g>").unwrap();
+    }
+
+    fn render_legend(
+        &self,
+        legend: &Legend,
+        x: f64,
+        y: f64,
+        size: f64,
+        class: &str,
+        out: &mut impl Write,
+    ) {
+        if let Some(tap) = &legend.tap {
+            writeln!(
+                out,
+                r#"    <text class="{class}" x="{x}" y="{y}">{}</text>"#,
+                escape(tap)
+            )
+            .unwrap();
+        }
+
+        if let Some(hold) = &legend.hold {
+            writeln!(
+                out,
+                r#"    <text class="hold" x="{x}" y="{}">{}</text>"#,
+                y + size / 2.0 - 8.0,
+                escape(hold)
+            )
+            .unwrap();
+        }
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let mut errors = Errors::default();
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .map(|layer| {
+                self.legends
+                    .layer(&self.metadata.layout, layer, &mut errors)
+            })
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        let phys = &self.metadata.layout.phys_to_matrix;
+        let columns = phys.keys().map(|p| p.0).max().map_or(0, |x| x + 1);
+        let rows = phys.keys().map(|p| p.1).max().map_or(0, |y| y + 1);
+
+        let layer_height = TITLE_HEIGHT + f64::from(rows) * KEY_SPACING + MARGIN;
+        let width = 2.0 * MARGIN + f64::from(columns) * KEY_SPACING;
+        let height = MARGIN + layers.len() as f64 * layer_height;
+
+        writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+        )
+        .unwrap();
+        writeln!(out, "  <style>{STYLE}  </style>").unwrap();
+
+        for (idx, layer) in layers.iter().enumerate() {
+            self.render_layer(layer, MARGIN + idx as f64 * layer_height, out);
+        }
+
+        writeln!(out, "</svg>").unwrap();
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut e = Emit {
+        metadata,
+
+        legends: Legends::new(file),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+/// Where the middle of the key at a physical position is, within its layer
+fn key_centre((x, y): (u8, u8)) -> (f64, f64) {
+    (
+        MARGIN + (f64::from(x) + 0.5) * KEY_SPACING,
+        TITLE_HEIGHT + (f64::from(y) + 0.5) * KEY_SPACING,
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
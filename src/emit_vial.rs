@@ -0,0 +1,559 @@
+use std::{collections::HashMap, io::Write};
+
+use indexmap::IndexSet;
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    emit_qmk::{predefined_named_keys, CHAR_KEYCODES, NAMED_KEYCODES},
+    errors::{AppError, Errors},
+    process::{
+        unknown_layer, unknown_named_key, KeyAt, LayerMeta, MatrixPosition, Metadata, OptionKey,
+        ResolvedChord,
+    },
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey, Span},
+};
+
+/// The parts of a `.vil` file that Vial restores a layout from
+#[derive(Debug, serde::Serialize)]
+struct Vil {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u64>,
+    layout: Vec<Vec<Vec<VilKey>>>,
+    encoder_layout: Vec<()>,
+    layout_options: i32,
+    #[serde(rename = "macro")]
+    macro_: Vec<()>,
+    vial_protocol: u32,
+    via_protocol: u32,
+    tap_dance: Vec<TapDance>,
+    combo: Vec<Combo>,
+    key_override: Vec<()>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum VilKey {
+    Key(String),
+    /// Spots in the matrix without a key are `-1`
+    Missing(i32),
+}
+
+/// On tap, on hold, on double tap, on tap and hold and the tapping term
+#[derive(Debug, PartialEq, Eq, Hash, serde::Serialize)]
+struct TapDance(String, String, String, String, u32);
+
+/// Up to four keys, padded with `KC_NO`, and the output
+#[derive(Debug, PartialEq, Eq, Hash, serde::Serialize)]
+struct Combo(String, String, String, String, String);
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Mod-taps that don't fit in a keycode are made from tap dances
+    tap_dances: IndexSet<TapDance>,
+    /// Whether the `flavor` option says mod-taps are permissive, if it's set
+    permissive: Option<bool>,
+    /// The first mod-tap that needed the `flavor` option when it wasn't set
+    needs_flavor: Option<Span>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata.get_option(OptionKey::Vial, key)
+    }
+
+    fn option_d<'d: 'a>(&self, key: &str, default: &'d str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span,
+            } => {
+                self.check_flavor(at, *span)?;
+
+                if timeout.is_none() {
+                    if let Some(tap_code) = self.tap_keycode(tap) {
+                        match hold {
+                            PlainKey::Named(name) if MOD_TAPS.contains_key(name.s) => {
+                                return Ok(format!("{}({tap_code})", MOD_TAPS[name.s]));
+                            }
+                            PlainKey::Layer { layer, .. } => {
+                                return Ok(format!("LT{}({tap_code})", self.layer_index(layer)?));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
+                let tap = self.map_plain_key(tap)?;
+                let hold = self.map_plain_key(hold)?;
+                let tapping_term = match timeout {
+                    Some(t) => t.timeout,
+                    None => self.tapping_term()?,
+                };
+
+                let (idx, _) = self.tap_dances.insert_full(TapDance(
+                    tap,
+                    hold,
+                    "KC_NO".to_owned(),
+                    "KC_NO".to_owned(),
+                    tapping_term,
+                ));
+
+                Ok(format!("TD({idx})"))
+            }
+        }
+    }
+
+    fn map_plain_key(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok("KC_TRNS".to_owned()),
+            PlainKey::Layer { layer, .. } => Ok(format!("MO({})", self.layer_index(layer)?)),
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                let idx = self.layer_index(layer)?;
+
+                Ok(match kind {
+                    LayerSwitchKind::Toggle(_) => format!("TG({idx})"),
+                    LayerSwitchKind::OneShot(_) => format!("OSL({idx})"),
+                    LayerSwitchKind::Default(_) => format!("DF({idx})"),
+                })
+            }
+            PlainKey::Char { c, span, .. } => {
+                if let Some(k) = CHAR_KEYCODES.get(c) {
+                    return Ok(k.0.to_owned());
+                }
+
+                Err(AppError::UnknownKey {
+                    span: *span,
+                    key: *c,
+                }
+                .into())
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let keycode = self.keycode(key)?;
+
+                Ok(modifiers.iter().rev().fold(keycode, |keycode, modifier| {
+                    format!("{}({keycode})", modifier_function(modifier))
+                }))
+            }
+        }
+    }
+
+    /// The keycode a key sends, for keys that can be combined with modifiers
+    fn keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        let keycode = match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s).copied(),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES.get(c).map(|k| k.0),
+            _ => None,
+        };
+
+        if let Some(keycode) = keycode {
+            return Ok(keycode.to_owned());
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Err(AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    /// Mod-tap and layer-tap keycodes can only tap basic keycodes
+    fn tap_keycode(&self, p: &PlainKey<'_>) -> Option<&'static str> {
+        match p {
+            PlainKey::Named(name) => NAMED_KEYCODES.get(name.s).copied(),
+            PlainKey::Char { c, .. } => CHAR_KEYCODES
+                .get(c)
+                .filter(|(_, shifted)| !shifted)
+                .map(|k| k.0),
+            _ => None,
+        }
+    }
+
+    fn tapping_term(&self) -> miette::Result<u32> {
+        self.option_d("tapping_term", "200").parse().map_err(|_| {
+            AppError::InvalidOption {
+                option_name: "tapping_term".to_string(),
+                backend: "vial".to_string(),
+                problem: "It should be a number of milliseconds".to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Vial sets the mod-tap flavor for the whole keyboard in its settings,
+    /// which aren't part of the layout, so the `flavor` option says which one
+    /// the keyboard is set to
+    fn flavor(&self) -> miette::Result<Option<bool>> {
+        match self.option("flavor") {
+            Some("permissive") => Ok(Some(true)),
+            Some("on_other_key") => Ok(Some(false)),
+            Some(_) => Err(AppError::InvalidOption {
+                option_name: "flavor".to_string(),
+                backend: "vial".to_string(),
+                problem: "It should be permissive or on_other_key, as set in Vial's settings"
+                    .to_string(),
+            }
+            .into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Mod-taps need to be the flavor the keyboard is set to
+    fn check_flavor(&mut self, at: &ModTapType, span: Span) -> miette::Result<()> {
+        let Some(permissive) = self.permissive else {
+            self.needs_flavor.get_or_insert(span);
+            return Ok(());
+        };
+
+        if permissive != matches!(at, ModTapType::Permissive(_)) {
+            return Err(AppError::UnsupportedByBackend {
+                span,
+                backend: "vial".to_string(),
+                what: "mod-taps of a different flavor to the flavor option".to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn layer_index(&self, layer: &Ident<'_>) -> miette::Result<usize> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        layer_map
+            .get(layer.s)
+            .copied()
+            .ok_or_else(|| unknown_layer(layer_map, layer).into())
+    }
+
+    /// What's different about a chord of another layer to the base layer's
+    /// combos: either the base layer has the same chord but this layer has
+    /// different keys where it's pressed, or the chord itself is different
+    fn unshared_chord(
+        &mut self,
+        layer: &'a LayerMeta<'a>,
+        chord: &'a ResolvedChord<'a>,
+        output: &str,
+    ) -> (Span, &'static str) {
+        let base = &self.metadata.layers.layers[0];
+        let base_chord = base
+            .chords
+            .iter()
+            .find(|c| c.layout_positions == chord.layout_positions);
+
+        if base_chord.is_some_and(|c| self.map_key(&c.key).is_ok_and(|o| o == output)) {
+            let differing = chord.layout_positions.iter().find_map(|pos| {
+                let key = layer.keys.iter().find(|k| k.layout_pos == *pos)?;
+                let base_key = base.keys.iter().find(|k| k.layout_pos == *pos)?;
+
+                let code = self.map_key(key.effective.as_ref().unwrap_or(&key.key));
+                let base_code = self.map_key(base_key.effective.as_ref().unwrap_or(&base_key.key));
+
+                (code.ok() != base_code.ok()).then(|| key.key.span())
+            });
+
+            if let Some(span) = differing {
+                return (
+                    span,
+                    "keys of chords that are different to the first layer's",
+                );
+            }
+        }
+
+        (
+            chord.key.span(),
+            "chords on other layers that the first layer doesn't have",
+        )
+    }
+
+    fn map_layer(
+        &mut self,
+        layer: &'a LayerMeta<'a>,
+        is_base: bool,
+        combos: &mut IndexSet<Combo>,
+        errors: &mut Errors,
+    ) -> Vec<Vec<VilKey>> {
+        let mut keys = HashMap::new();
+        for key in &layer.keys {
+            if let Some(k) = errors.take(self.map_key(&key.key)) {
+                keys.insert(key.matrix_pos, k);
+            }
+        }
+
+        let layout = &self.metadata.layout;
+        let used = layout
+            .layout_to_matrix
+            .values()
+            .filter_map(|k| match k {
+                KeyAt::Located(pos) => Some(*pos),
+                KeyAt::Space => None,
+            })
+            .collect::<Vec<_>>();
+
+        let matrix = (0..layout.height)
+            .map(|y| {
+                (0..layout.width)
+                    .map(|x| {
+                        let pos = MatrixPosition(x, y);
+
+                        match keys.remove(&pos) {
+                            Some(k) => VilKey::Key(k),
+                            None if used.contains(&pos) => VilKey::Key("KC_NO".to_owned()),
+                            None => VilKey::Missing(-1),
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        for chord in &layer.chords {
+            if chord.layout_positions.len() > 4 {
+                errors.push(AppError::UnsupportedByBackend {
+                    span: chord.key.span(),
+                    backend: "vial".to_string(),
+                    what: "chords of more than four keys".to_string(),
+                });
+                continue;
+            }
+
+            // combos are triggered by keycodes rather than positions, which
+            // are whatever the keys end up as on this layer
+            let chord_keys = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().find(|k| k.layout_pos == *pos))
+                .map(|k| self.map_key(k.effective.as_ref().unwrap_or(&k.key)))
+                .collect::<miette::Result<Vec<_>>>();
+
+            let output = self.map_key(&chord.key);
+
+            if let (Some(chord_keys), Some(output)) = (errors.take(chord_keys), errors.take(output))
+            {
+                let (a, b, c, d) = chord_keys
+                    .into_iter()
+                    .pad_using(4, |_| "KC_NO".to_owned())
+                    .collect_tuple()
+                    .unwrap();
+
+                let combo = Combo(a, b, c, d, output);
+
+                // Vial's combos are on every layer, so other layers can only
+                // have the same ones as the base layer
+                if is_base {
+                    combos.insert(combo);
+                } else if !combos.contains(&combo) {
+                    let (span, what) = self.unshared_chord(layer, chord, &combo.4);
+
+                    errors.push(AppError::UnsupportedByBackend {
+                        span,
+                        backend: "vial".to_string(),
+                        what: what.to_string(),
+                    });
+                }
+            }
+        }
+
+        matrix
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let uid = self
+            .option("uid")
+            .map(|uid| {
+                uid.parse().map_err(|_| AppError::InvalidOption {
+                    option_name: "uid".to_string(),
+                    backend: "vial".to_string(),
+                    problem: "It should be the keyboard's uid as a number".to_string(),
+                })
+            })
+            .transpose()?;
+
+        self.permissive = self.flavor()?;
+
+        let mut errors = Errors::default();
+        let mut combos = IndexSet::new();
+        let layout = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(idx, layer)| self.map_layer(layer, idx == 0, &mut combos, &mut errors))
+            .collect::<Vec<_>>();
+
+        if let Some(span) = self.needs_flavor {
+            errors.push(AppError::KeyNeedsOption {
+                span,
+                option_name: "flavor".to_string(),
+                backend: "vial".to_string(),
+            });
+        }
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        let vil = Vil {
+            version: 1,
+            uid,
+            layout,
+            encoder_layout: Vec::new(),
+            layout_options: -1,
+            macro_: Vec::new(),
+            vial_protocol: 6,
+            via_protocol: 9,
+            tap_dance: std::mem::take(&mut self.tap_dances).into_iter().collect(),
+            combo: combos.into_iter().collect(),
+            key_override: Vec::new(),
+        };
+
+        serde_json::to_writer_pretty(&mut *out, &vil).unwrap();
+        writeln!(out).unwrap();
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    // Vial uses QMK's keycodes, so QMK outputs do unless there's a Vial one
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "vial")
+                .or_else(|| k.outputs.iter().find(|d| d.name.s == "qmk"))
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        tap_dances: IndexSet::new(),
+        permissive: None,
+        needs_flavor: None,
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+fn modifier_function(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "LCTL",
+        Modifier::Shift(_) => "LSFT",
+        Modifier::Alt(_) => "LALT",
+        Modifier::Gui(_) => "LGUI",
+    }
+}
+
+static MOD_TAPS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("lctrl", "LCTL_T"),
+        ("rctrl", "RCTL_T"),
+        ("lshift", "LSFT_T"),
+        ("rshift", "RSFT_T"),
+        ("lalt", "LALT_T"),
+        ("ralt", "RALT_T"),
+        ("lgui", "LGUI_T"),
+        ("rgui", "RGUI_T"),
+    ])
+});
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{input::Input as _, Parser as _};
+
+    use super::*;
+    use crate::parse;
+
+    fn emit_str(text: &str) -> miette::Result<String> {
+        let file = parse::file()
+            .parse(text.with_context(0))
+            .into_result()
+            .unwrap();
+        let metadata = Metadata::process(&file)?;
+
+        let mut out = Vec::new();
+        emit(&file, &metadata, &mut out)?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    /// The error of a layout that has exactly one, and the text it points at
+    fn single_error(text: &str) -> (AppError, &str) {
+        let error = emit_str(text).unwrap_err().downcast::<AppError>().unwrap();
+
+        let span = match &error {
+            AppError::KeyNeedsOption { span, .. } | AppError::UnsupportedByBackend { span, .. } => {
+                span.0
+            }
+            other => panic!("unexpected error {other:?}"),
+        };
+
+        (error, &text[span.offset()..span.offset() + span.len()])
+    }
+
+    const BASE: &str = "layout {\n  3k;\n}\n\noptions vial {\n  flavor: \"permissive\";\n}\n\nlayer base {\n  'a' >esc< 'b' 'c';\n}\n";
+
+    #[test]
+    fn missing_flavor_is_reported_once() {
+        let text = "layout {\n  2k;\n}\n\nlayer base {\n  'a'@lshift 'b'@lctrl;\n}\n";
+        let (error, at) = single_error(text);
+
+        assert!(matches!(error, AppError::KeyNeedsOption { .. }));
+        assert_eq!(at, "'a'@lshift");
+    }
+
+    #[test]
+    fn layers_can_extend_the_base_layer() {
+        emit_str(&format!(
+            "{BASE}\nlayer fn extends base {{\n  . . 'd';\n}}\n"
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn chords_only_on_other_layers_point_at_the_chord() {
+        let text = format!("{BASE}\nlayer fn extends base {{\n  . . >tab< .;\n}}\n");
+        let (_, at) = single_error(&text);
+
+        assert_eq!(at, "tab");
+    }
+
+    #[test]
+    fn different_chord_keys_point_at_the_key() {
+        let text = format!("{BASE}\nlayer fn extends base {{\n  'x' . .;\n}}\n");
+        let (_, at) = single_error(&text);
+
+        assert_eq!(at, "'x'");
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+use itertools::Itertools;
+use locspan::Spanned;
+use once_cell::sync::Lazy;
+
+use crate::{
+    errors::{AppError, Errors},
+    keycodes,
+    process::{unknown_layer, unknown_named_key, LayerMeta, Metadata},
+    syntax::{File, Ident, Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+struct Emit<'a> {
+    named_keys: HashMap<String, String>,
+    /// Hold-tap behaviours needed by the keymap, by name
+    hold_taps: BTreeMap<String, HoldTap>,
+
+    metadata: &'a Metadata<'a>,
+}
+
+/// A `zmk,behavior-hold-tap` node, `&mt` and `&lt` can only be configured
+/// once for the whole keymap so every combination used gets its own
+struct HoldTap {
+    hold_behavior: &'static str,
+    flavor: &'static str,
+    tapping_term: String,
+}
+
+struct MappedLayer {
+    name: String,
+    rows: Vec<Vec<String>>,
+    combos: Vec<MappedCombo>,
+}
+
+struct MappedCombo {
+    key_positions: Vec<usize>,
+    binding: String,
+}
+
+impl<'a> Emit<'a> {
+    fn option(&self, key: &str) -> Option<&'a str> {
+        self.metadata
+            .get_option(crate::process::OptionKey::Zmk, key)
+    }
+
+    fn option_d<'d: 'a>(&self, key: &str, default: &'d str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn map_key(&mut self, key: &'a Key<'a>) -> miette::Result<String> {
+        match key {
+            Key::Plain(p) => self.map_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span: _,
+            } => {
+                let tap = self.tap_keycode(tap)?;
+
+                let (kind, hold_behavior, hold) = match hold {
+                    PlainKey::Layer { layer, .. } => ("lt", "&mo", self.layer_name(layer)?),
+                    hold => ("mt", "&kp", self.tap_keycode(hold)?),
+                };
+
+                let (flavor, flavor_name) = match at {
+                    ModTapType::Permissive(_) => ("balanced", "balanced"),
+                    ModTapType::OnOtherKey(_) => ("hold-preferred", "hold_preferred"),
+                };
+
+                let mut name = format!("{kind}_{flavor_name}");
+                let tapping_term = match timeout {
+                    Some(timeout) => {
+                        name = format!("{name}_{}", timeout.timeout);
+                        timeout.timeout.to_string()
+                    }
+                    None => self.option_d("hold_tap_timeout", "200").to_string(),
+                };
+
+                self.hold_taps.entry(name.clone()).or_insert(HoldTap {
+                    hold_behavior,
+                    flavor,
+                    tapping_term,
+                });
+
+                Ok(format!("&{name} {hold} {tap}"))
+            }
+        }
+    }
+
+    fn map_plain_key(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok("&trans".to_owned()),
+            PlainKey::Layer { layer, .. } => Ok(format!("&mo {}", self.layer_name(layer)?)),
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                let layer = self.layer_name(layer)?;
+
+                // zmk has no default layer, `&to` is the closest as it turns
+                // off everything else
+                Ok(match kind {
+                    LayerSwitchKind::Toggle(_) => format!("&tog {layer}"),
+                    LayerSwitchKind::OneShot(_) => format!("&sl {layer}"),
+                    LayerSwitchKind::Default(_) => format!("&to {layer}"),
+                })
+            }
+            PlainKey::Char { .. } | PlainKey::Modified { .. } => {
+                Ok(format!("&kp {}", self.keycode(p)?))
+            }
+        }
+    }
+
+    /// The keycode a key sends, for keys that can be combined with modifiers
+    fn keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        self.try_keycode(p)?
+            .ok_or_else(|| AppError::UnmodifiableKey { span: p.span() }.into())
+    }
+
+    /// Hold-taps pass keycodes to `&kp`, so only keys with one can be used
+    fn tap_keycode(&self, p: &PlainKey<'_>) -> miette::Result<String> {
+        self.try_keycode(p)?.ok_or_else(|| {
+            AppError::UnsupportedByBackend {
+                span: p.span(),
+                backend: "zmk".to_string(),
+                what: "hold-taps with keys other than keycodes and layers".to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// The keycode of a key, or `None` for keys that exist but don't have one
+    fn try_keycode(&self, p: &PlainKey<'_>) -> miette::Result<Option<String>> {
+        match p {
+            PlainKey::Named(name) => {
+                if let Some(keycode) = NAMED_KEYCODES.get(name.s) {
+                    return Ok(Some(keycode.clone()));
+                }
+            }
+            PlainKey::Char { c, span, .. } => {
+                return match CHAR_KEYCODES.get(c) {
+                    Some(keycode) => Ok(Some(keycode.clone())),
+                    None => Err(AppError::UnknownKey {
+                        span: *span,
+                        key: *c,
+                    }
+                    .into()),
+                }
+            }
+            PlainKey::Modified { modifiers, key, .. } => {
+                let keycode = self.keycode(key)?;
+
+                return Ok(Some(
+                    modifiers.iter().rev().fold(keycode, |keycode, modifier| {
+                        format!("{}({keycode})", modifier_function(modifier))
+                    }),
+                ));
+            }
+            _ => {}
+        }
+
+        // report keys that don't exist at all as such
+        self.map_plain_key(p)?;
+
+        Ok(None)
+    }
+
+    fn layer_name(&self, layer: &Ident<'_>) -> miette::Result<String> {
+        let layer_map = &self.metadata.layers.layer_map;
+
+        if !layer_map.contains_key(layer.s) {
+            return Err(unknown_layer(layer_map, layer).into());
+        }
+
+        Ok(layer_define_name(layer.s))
+    }
+
+    fn map_layer(&mut self, layer: &'a LayerMeta<'a>, errors: &mut Errors) -> MappedLayer {
+        let mut rows = Vec::new();
+        for (_, row) in &layer.keys.iter().group_by(|k| k.layout_pos.1) {
+            rows.push(
+                row.filter_map(|k| errors.take(self.map_key(&k.key)))
+                    .collect(),
+            );
+        }
+
+        let mut combos = Vec::new();
+        for chord in &layer.chords {
+            let key_positions = chord
+                .layout_positions
+                .iter()
+                .filter_map(|pos| layer.keys.iter().position(|k| k.layout_pos == *pos))
+                .collect();
+
+            if let Some(binding) = errors.take(self.map_key(&chord.key)) {
+                combos.push(MappedCombo {
+                    key_positions,
+                    binding,
+                });
+            }
+        }
+
+        MappedLayer {
+            name: layer.name.to_string(),
+            rows,
+            combos,
+        }
+    }
+
+    fn render_behaviors(&self, out: &mut impl Write) {
+        if self.hold_taps.is_empty() {
+            return;
+        }
+
+        writeln!(out, "    behaviors {{").unwrap();
+        for (name, hold_tap) in &self.hold_taps {
+            writeln!(out, "        {name}: {name} {{").unwrap();
+            writeln!(out, "            compatible = \"zmk,behavior-hold-tap\";").unwrap();
+            writeln!(out, "            #binding-cells = <2>;").unwrap();
+            writeln!(out, "            flavor = \"{}\";", hold_tap.flavor).unwrap();
+            writeln!(
+                out,
+                "            tapping-term-ms = <{}>;",
+                hold_tap.tapping_term
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "            bindings = <{}>, <&kp>;",
+                hold_tap.hold_behavior
+            )
+            .unwrap();
+            writeln!(out, "        }};").unwrap();
+        }
+        writeln!(out, "    }};").unwrap();
+        writeln!(out).unwrap();
+    }
+
+    fn render_combos(&self, layers: &[MappedLayer], out: &mut impl Write) {
+        let combos = layers
+            .iter()
+            .flat_map(|l| l.combos.iter().map(move |c| (&l.name, c)))
+            .collect::<Vec<_>>();
+
+        if combos.is_empty() {
+            return;
+        }
+
+        writeln!(out, "    combos {{").unwrap();
+        writeln!(out, "        compatible = \"zmk,combos\";").unwrap();
+        for (idx, (layer, combo)) in combos.iter().enumerate() {
+            writeln!(out).unwrap();
+            writeln!(out, "        combo_{idx} {{").unwrap();
+            writeln!(
+                out,
+                "            key-positions = <{}>;",
+                combo.key_positions.iter().join(" ")
+            )
+            .unwrap();
+            writeln!(out, "            bindings = <{}>;", combo.binding).unwrap();
+            writeln!(out, "            layers = <{}>;", layer_define_name(layer)).unwrap();
+            writeln!(out, "        }};").unwrap();
+        }
+        writeln!(out, "    }};").unwrap();
+        writeln!(out).unwrap();
+    }
+
+    fn render_keymap(&self, layers: &[MappedLayer], out: &mut impl Write) {
+        writeln!(out, "    keymap {{").unwrap();
+        writeln!(out, "        compatible = \"zmk,keymap\";").unwrap();
+        for layer in layers {
+            writeln!(out).unwrap();
+            writeln!(out, "        {}_layer {{", layer_node_name(&layer.name)).unwrap();
+            writeln!(out, "            display-name = \"{}\";", layer.name).unwrap();
+            writeln!(out, "            bindings = <").unwrap();
+            for row in &layer.rows {
+                writeln!(out, "                {}", row.join(" ")).unwrap();
+            }
+            writeln!(out, "            >;").unwrap();
+            writeln!(out, "        }};").unwrap();
+        }
+        writeln!(out, "    }};").unwrap();
+    }
+
+    fn process(&mut self, out: &mut impl Write) -> miette::Result<()> {
+        let mut errors = Errors::default();
+        let layers = self
+            .metadata
+            .layers
+            .layers
+            .iter()
+            .map(|layer| self.map_layer(layer, &mut errors))
+            .collect::<Vec<_>>();
+
+        // check every key before writing anything out
+        errors.finish()?;
+
+        writeln!(
+            out,
+            "// Generated by keylayout_lang, edit the layout instead"
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "#include <behaviors.dtsi>").unwrap();
+        writeln!(out, "#include <dt-bindings/zmk/keys.h>").unwrap();
+        writeln!(out).unwrap();
+
+        for (idx, layer) in layers.iter().enumerate() {
+            writeln!(out, "#define {} {idx}", layer_define_name(&layer.name)).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        writeln!(out, "/ {{").unwrap();
+        self.render_behaviors(out);
+        self.render_combos(&layers, out);
+        self.render_keymap(&layers, out);
+        writeln!(out, "}};").unwrap();
+
+        Ok(())
+    }
+}
+
+pub fn emit<'a>(
+    file: &'a File<'a>,
+    metadata: &'a Metadata<'a>,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let mut named_keys = file
+        .custom_keys
+        .iter()
+        .filter_map(|k| {
+            k.outputs
+                .iter()
+                .find(|d| d.name.s == "zmk")
+                .map(|d| (k.name.s.to_string(), d.output.text.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    named_keys.extend(predefined_named_keys());
+
+    let mut e = Emit {
+        metadata,
+
+        named_keys,
+        hold_taps: BTreeMap::new(),
+    };
+
+    e.process(out)?;
+
+    Ok(())
+}
+
+/// Layers are referred to by number through a `#define` for each
+fn layer_define_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    format!("L_{name}")
+}
+
+fn layer_node_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn modifier_function(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Ctrl(_) => "LC",
+        Modifier::Shift(_) => "LS",
+        Modifier::Alt(_) => "LA",
+        Modifier::Gui(_) => "LG",
+    }
+}
+
+pub fn predefined_named_keys() -> HashMap<String, String> {
+    let mut keys: HashMap<_, _> = NAMED_KEYCODES
+        .iter()
+        .map(|(k, v)| (k.to_string(), format!("&kp {v}")))
+        .collect();
+
+    keys.insert("n".to_owned(), "&none".to_owned());
+
+    keys
+}
+
+pub static NAMED_KEYCODES: Lazy<HashMap<String, String>> = Lazy::new(named_keycodes);
+
+fn named_keycodes() -> HashMap<String, String> {
+    keycodes::named_keycodes(|k| &k.zmk)
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+}
+
+pub static CHAR_KEYCODES: Lazy<HashMap<char, String>> = Lazy::new(char_keycodes);
+
+fn char_keycodes() -> HashMap<char, String> {
+    let mut keys = HashMap::new();
+
+    for k in 'a'..='z' {
+        keys.insert(k, k.to_ascii_uppercase().to_string());
+    }
+
+    for k in '0'..='9' {
+        keys.insert(k, format!("N{k}"));
+    }
+
+    keys.extend(
+        [
+            ('!', "EXCL"),
+            ('@', "AT"),
+            ('#', "HASH"),
+            ('$', "DLLR"),
+            ('%', "PRCNT"),
+            ('^', "CARET"),
+            ('&', "AMPS"),
+            ('*', "STAR"),
+            ('(', "LPAR"),
+            (')', "RPAR"),
+            ('-', "MINUS"),
+            ('_', "UNDER"),
+            ('=', "EQUAL"),
+            ('+', "PLUS"),
+            ('[', "LBKT"),
+            ('{', "LBRC"),
+            (']', "RBKT"),
+            ('}', "RBRC"),
+            ('\\', "BSLH"),
+            ('|', "PIPE"),
+            (';', "SEMI"),
+            (':', "COLON"),
+            ('\'', "SQT"),
+            ('"', "DQT"),
+            ('`', "GRAVE"),
+            ('~', "TILDE"),
+            (',', "COMMA"),
+            ('<', "LT"),
+            ('.', "DOT"),
+            ('>', "GT"),
+            ('/', "SLASH"),
+            ('?', "QMARK"),
+        ]
+        .map(|(c, k)| (c, k.to_string())),
+    );
+
+    keys
+}
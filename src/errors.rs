@@ -10,6 +10,44 @@ pub enum AppError {
     #[diagnostic(code(io_error), help("I couldn't read or write a file"))]
     IOError(#[from] io::Error),
 
+    #[error("Couldn't include {path}")]
+    #[diagnostic(
+        code(include_failed),
+        help("Includes are relative to the file they're in")
+    )]
+    IncludeFailed {
+        #[label(primary, "Included here")]
+        span: Span,
+
+        path: String,
+
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("No layout defined")]
+    #[diagnostic(
+        code(missing_layout),
+        help("Add a `layout {{ ... }}` block to this file or one it includes")
+    )]
+    MissingLayout {
+        #[label(primary, "In this file")]
+        span: Span,
+    },
+
+    #[error("Multiple layouts defined")]
+    #[diagnostic(
+        code(duplicate_layout),
+        help("Only one file out of a file and its includes can define the layout")
+    )]
+    DuplicateLayout {
+        #[label(primary, "This layout")]
+        span: Span,
+
+        #[label("Was already defined here")]
+        other_span: Span,
+    },
+
     #[error("Overlapping keys on layout")]
     #[diagnostic(
         code(overlapping_keys),
@@ -34,6 +72,26 @@ pub enum AppError {
     },
 
 
+    #[error("Repeated combo position")]
+    #[diagnostic(
+        code(repeated_combo_position),
+        help("Each key of a combo can only be pressed once")
+    )]
+    RepeatedComboPosition {
+        #[label(primary, "This position")]
+        span: Span,
+
+        #[label("Is already in the combo here")]
+        other_span: Span,
+    },
+
+    #[error("Combo of a single key")]
+    #[diagnostic(code(combo_too_small), help("Combos need at least two different keys"))]
+    ComboTooSmall {
+        #[label(primary, "This combo")]
+        span: Span,
+    },
+
     #[error("Badly positioned chord")]
     #[diagnostic(
         code(bad_chord_positioning),
@@ -59,6 +117,16 @@ pub enum AppError {
         key: char,
     },
 
+    #[error("Modifiers can't be added to this key")]
+    #[diagnostic(
+        code(unmodifiable_key),
+        help("Only keys that send plain keycodes can be combined with modifiers")
+    )]
+    UnmodifiableKey {
+        #[label(primary, "This key")]
+        span: Span,
+    },
+
     #[error("Unknown named key: {key}")]
     #[diagnostic(
         code(unknown_named_key),
@@ -87,6 +155,31 @@ pub enum AppError {
         similar: String,
     },
 
+    #[error("Layer extends one declared after it")]
+    #[diagnostic(
+        code(layer_extends_later),
+        help("Layers can only extend layers declared before them, try moving {parent} up")
+    )]
+    LayerExtendsLater {
+        #[label(primary, "Extended here")]
+        span: Span,
+
+        #[label("Is declared here")]
+        parent_span: Span,
+
+        parent: String,
+    },
+
+    #[error("Nothing to inherit")]
+    #[diagnostic(
+        code(nothing_to_inherit),
+        help("`.` keeps whatever the layer named by `extends` has in the same position")
+    )]
+    NothingToInherit {
+        #[label(primary, "There's nothing for this to keep")]
+        span: Span,
+    },
+
     #[error("Inconsistent matrix width")]
     #[diagnostic(
         code(bad_matrix_width),
@@ -100,6 +193,64 @@ pub enum AppError {
         expected: u8,
     },
 
+    #[error("Layout is too large")]
+    #[diagnostic(
+        code(layout_too_large),
+        help("Layouts can be at most 255 keys wide and 255 rows tall")
+    )]
+    LayoutTooLarge {
+        #[label(primary, "This goes past the limit")]
+        span: Span,
+    },
+
+    #[error("Layer has the wrong number of rows")]
+    #[diagnostic(
+        code(wrong_row_count),
+        help("A layer needs a row for each row of the layout, unless it extends another layer")
+    )]
+    WrongRowCount {
+        #[label(primary, "This layer should have {expected} rows, but it has {got}")]
+        span: Span,
+
+        got: usize,
+        expected: usize,
+    },
+
+    #[error("Layer row has the wrong number of keys")]
+    #[diagnostic(
+        code(wrong_key_count),
+        help("Each row of a layer needs a key for every key in that row of the layout, unless the layer extends another")
+    )]
+    WrongKeyCount {
+        #[label(primary, "This row should have {expected} keys, but it has {got}")]
+        bad_row: Span,
+
+        got: usize,
+        expected: usize,
+    },
+
+    #[error("Invalid option {option_name} for {backend}")]
+    #[diagnostic(code(invalid_option), help("{problem}"))]
+    InvalidOption {
+        option_name: String,
+        backend: String,
+        problem: String,
+    },
+
+    #[error("{backend} doesn't support {what}")]
+    #[diagnostic(code(unsupported_by_backend))]
+    UnsupportedByBackend {
+        #[label(primary, "This key")]
+        span: Span,
+
+        backend: String,
+        what: String,
+    },
+
+    #[error("Couldn't import {path}")]
+    #[diagnostic(code(import_failed), help("{problem}"))]
+    ImportFailed { path: String, problem: String },
+
     #[error("An option is required")]
     #[diagnostic(
         code(required_option),
@@ -109,4 +260,105 @@ pub enum AppError {
         option_name: String,
         backend: String,
     },
+
+    #[error("An option is required")]
+    #[diagnostic(
+        code(required_option),
+        help("The option {option_name} is required for {backend} by keys like this one")
+    )]
+    KeyNeedsOption {
+        #[label(primary, "This key")]
+        span: Span,
+
+        option_name: String,
+        backend: String,
+    },
+
+    #[error("{backend} has no action for {what}")]
+    #[diagnostic(
+        code(layer_switch_action_required),
+        help("Set the option {option_name} to the firmware's own action for it, with {{layer}} where the layer index goes")
+    )]
+    LayerSwitchActionRequired {
+        #[label(primary, "This key")]
+        span: Span,
+
+        option_name: String,
+        backend: String,
+        what: String,
+    },
+}
+
+#[derive(Error, Debug, miette::Diagnostic)]
+#[error("Found {} problems", .errors.len())]
+#[diagnostic(code(many_errors))]
+pub struct ManyErrors {
+    #[related]
+    errors: Vec<miette::Report>,
+}
+
+/// Everything a single backend had a problem with
+#[derive(Error, Debug, miette::Diagnostic)]
+#[error("The {backend} backend can't handle this layout")]
+#[diagnostic(code(backend_failed))]
+pub struct BackendFailed {
+    backend: String,
+    #[related]
+    errors: Vec<miette::Report>,
+}
+
+impl BackendFailed {
+    pub fn new(backend: impl Into<String>, errors: Errors) -> Self {
+        Self {
+            backend: backend.into(),
+            errors: errors.0,
+        }
+    }
+}
+
+/// Somewhere to put errors so that everything wrong can be reported at once,
+/// rather than stopping at the first problem
+#[derive(Debug, Default)]
+pub struct Errors(Vec<miette::Report>);
+
+impl Errors {
+    pub fn push(&mut self, error: impl Into<miette::Report>) {
+        match error.into().downcast::<ManyErrors>() {
+            Ok(many) => self.0.extend(many.errors),
+            Err(error) => self.0.push(error),
+        }
+    }
+
+    /// Keep hold of the error of a result, if it has one
+    pub fn take<T>(&mut self, result: miette::Result<T>) -> Option<T> {
+        result.map_err(|e| self.push(e)).ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Drop the errors that aren't problems in the situation at hand
+    pub fn retain(&mut self, keep: impl FnMut(&miette::Report) -> bool) {
+        self.0.retain(keep);
+    }
+
+    pub fn finish(self) -> miette::Result<()> {
+        self.finish_with(())
+    }
+
+    /// Everything collected as a single error, or `value` if there weren't any
+    pub fn finish_with<T>(mut self, value: T) -> miette::Result<T> {
+        match self.0.len() {
+            0 => Ok(value),
+            1 => Err(self.0.remove(0)),
+            _ => Err(ManyErrors { errors: self.0 }.into()),
+        }
+    }
+}
+
+impl Extend<miette::Report> for Errors {
+    fn extend<I: IntoIterator<Item = miette::Report>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
 }
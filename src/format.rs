@@ -6,7 +6,10 @@ use std::{
 use itertools::Itertools;
 use locspan::Spanned;
 
-use crate::{process::Metadata, syntax::File};
+use crate::{
+    process::Metadata,
+    syntax::{File, Span},
+};
 
 #[derive(Default, Debug, debug3::Debug, Clone)]
 pub struct KeySpacing {
@@ -33,6 +36,17 @@ impl<'a> Format<'a> {
             .map(|(p, l)| (*l, *p))
             .collect::<HashMap<_, _>>();
 
+        // comments that stay on the line of a key are lined up along with it
+        let comment_widths = file
+            .layers
+            .iter()
+            .flat_map(|l| &l.rows)
+            .flat_map(|r| r.comment_widths())
+            .map(|(span, width)| (span.0.offset(), width))
+            .collect::<HashMap<_, _>>();
+        let width_of =
+            |span: Span| span.len() + comment_widths.get(&span.0.offset()).copied().unwrap_or(0);
+
         for layer in &meta.layers.layers {
             let layout_to_key = layer
                 .keys
@@ -43,22 +57,24 @@ impl<'a> Format<'a> {
             let layout_to_chord = layer
                 .chords
                 .iter()
+                .filter_map(|c| c.inline.as_ref())
                 .map(|c| (c.left_layout, c))
                 .collect::<HashMap<_, _>>();
 
             for (x, y) in (0..meta.layout.width).cartesian_product(0..meta.layout.height) {
                 if let Some(layout_pos) = phys_to_layout.get(&(x, y)) {
+                    // processing reports layers that are missing keys
                     let Some(key_node) = layout_to_key.get(layout_pos) else {
-                        panic!("Key does not exist on layout: {:?} of layer {}", layout_pos, layer.name);
+                        continue;
                     };
 
                     let spacing = &mut column_widths[x as usize];
 
-                    spacing.key_width = spacing.key_width.max(key_node.key.span().len());
+                    spacing.key_width = spacing.key_width.max(width_of(key_node.key.span()));
 
                     if let Some(chord_node) = layout_to_chord.get(&(x, y)) {
                         spacing.chord_width =
-                            spacing.chord_width.max(chord_node.chord.span().len());
+                            spacing.chord_width.max(width_of(chord_node.chord.span()));
                     }
                 } else {
                     empties.insert((x, y));
@@ -0,0 +1,610 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    io::Write,
+};
+
+use locspan::Spanned;
+use miette::SourceSpan;
+use typed_arena::Arena;
+
+use crate::{
+    format,
+    process::{LayoutMeta, Metadata},
+    syntax::{
+        Chord, Combo, ComboPosition, CustomKey, CustomKeyOutput, File, Ident, Key, KeyOrChord,
+        Layer, LayerRow, LayerSwitchKind, Layout, LayoutDefn, LayoutRow, ModTapTimeout, ModTapType,
+        Modifier, Options, OptionsFor, OptionsItem, PlainKey, Span, Text, Token, Trivia,
+    },
+};
+
+/// Builds the syntax tree of a layout read from some other format.
+///
+/// There's no source for spans to point into, so they only have a length:
+/// that of the node once printed, which is what the formatter lines keys up
+/// with.
+pub struct Builder<'a> {
+    strings: &'a Arena<String>,
+}
+
+fn span(len: usize) -> Span {
+    Span(SourceSpan::new(0.into(), len))
+}
+
+/// A token of a tree being built, sized to fit its text
+pub fn token<const T: &'static str>() -> Token<T> {
+    Token(span(T.len()))
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(strings: &'a Arena<String>) -> Self {
+        Self { strings }
+    }
+
+    /// Keep hold of a string for as long as the layout being built
+    pub fn str(&self, s: impl Into<String>) -> &'a str {
+        self.strings.alloc(s.into())
+    }
+
+    pub fn ident(&self, s: impl Into<String>) -> Ident<'a> {
+        let s = self.str(s);
+
+        Ident {
+            s,
+            span: span(s.len()),
+        }
+    }
+
+    pub fn text(&self, text: impl Into<String>) -> Text<'a> {
+        let text = text.into();
+        let len = format!("{text:?}").len();
+
+        Text {
+            left_quote: token(),
+            text: Cow::Owned(text),
+            right_quote: token(),
+            span: span(len),
+        }
+    }
+
+    pub fn named(&self, name: impl Into<String>) -> PlainKey<'a> {
+        PlainKey::Named(self.ident(name))
+    }
+
+    pub fn char(&self, c: char) -> PlainKey<'a> {
+        PlainKey::Char {
+            left_quote: token(),
+            c,
+            right_quote: token(),
+            span: span(c.len_utf8() + 2),
+        }
+    }
+
+    pub fn trans(&self) -> PlainKey<'a> {
+        PlainKey::Trans(token())
+    }
+
+    pub fn layer(&self, layer: impl Into<String>) -> PlainKey<'a> {
+        let layer = self.ident(layer);
+
+        PlainKey::Layer {
+            left_square: token(),
+            span: span(layer.span.len() + 2),
+            layer,
+            right_square: token(),
+        }
+    }
+
+    pub fn layer_switch(&self, kind: LayerSwitchKind, layer: impl Into<String>) -> PlainKey<'a> {
+        let layer = self.ident(layer);
+        let kind_len = match &kind {
+            LayerSwitchKind::Toggle(t) => t.span().len(),
+            LayerSwitchKind::OneShot(t) => t.span().len(),
+            LayerSwitchKind::Default(t) => t.span().len(),
+        };
+
+        PlainKey::LayerSwitch {
+            span: span(kind_len + layer.span.len() + 2),
+            kind,
+            left_paren: token(),
+            layer,
+            right_paren: token(),
+        }
+    }
+
+    /// Only named and char keys can be modified
+    pub fn modified(&self, modifiers: Vec<Modifier>, key: PlainKey<'a>) -> PlainKey<'a> {
+        if modifiers.is_empty() {
+            return key;
+        }
+
+        PlainKey::Modified {
+            span: span(modifiers.len() * 2 + key.span().len()),
+            modifiers,
+            key: Box::new(key),
+        }
+    }
+
+    pub fn mod_tap(
+        &self,
+        tap: PlainKey<'a>,
+        at: ModTapType,
+        timeout: Option<u32>,
+        hold: PlainKey<'a>,
+    ) -> Key<'a> {
+        let timeout = timeout.map(|timeout| ModTapTimeout {
+            left_square: token(),
+            timeout,
+            right_square: token(),
+            span: span(timeout.to_string().len() + 2),
+        });
+        let at_len = match &at {
+            ModTapType::Permissive(t) => t.span().len(),
+            ModTapType::OnOtherKey(t) => t.span().len(),
+        };
+
+        Key::ModTap {
+            span: span(
+                tap.span().len()
+                    + at_len
+                    + timeout.as_ref().map_or(0, |t| t.span.len())
+                    + hold.span().len(),
+            ),
+            tap,
+            at,
+            timeout,
+            hold,
+        }
+    }
+
+    pub fn options(&self, for_: OptionsFor, items: Vec<(&str, String)>) -> Options<'a> {
+        let items = items
+            .into_iter()
+            .map(|(name, value)| OptionsItem {
+                leading: Trivia::default(),
+                name: self.ident(name),
+                after_name: Trivia::default(),
+                colon: token(),
+                after_colon: Trivia::default(),
+                value: self.text(value),
+                after_value: Trivia::default(),
+                semi: token(),
+                span: span(0),
+            })
+            .collect();
+
+        Options {
+            leading: Trivia::default(),
+            options_token: token(),
+            after_options: Trivia::default(),
+            for_,
+            after_for: Trivia::default(),
+            left_curly: token(),
+            items,
+            trailing: Trivia::default(),
+            right_curly: token(),
+            span: span(0),
+        }
+    }
+
+    pub fn custom_key(&self, name: &'a str, outputs: Vec<(&str, String)>) -> CustomKey<'a> {
+        let outputs = outputs
+            .into_iter()
+            .map(|(backend, output)| CustomKeyOutput {
+                leading: Trivia::default(),
+                out_token: token(),
+                after_out: Trivia::default(),
+                name: self.ident(backend),
+                after_name: Trivia::default(),
+                colon: token(),
+                after_colon: Trivia::default(),
+                output: self.text(output),
+                after_output: Trivia::default(),
+                semi: token(),
+                span: span(0),
+            })
+            .collect();
+
+        CustomKey {
+            leading: Trivia::default(),
+            key_token: token(),
+            after_key: Trivia::default(),
+            name: Ident {
+                s: name,
+                span: span(name.len()),
+            },
+            after_name: Trivia::default(),
+            left_curly: token(),
+            outputs,
+            trailing: Trivia::default(),
+            right_curly: token(),
+            span: span(0),
+        }
+    }
+
+    pub fn layout(&self, rows: Vec<Vec<LayoutDefn>>) -> Layout<'a> {
+        let rows = rows
+            .into_iter()
+            .map(|items| LayoutRow {
+                leading: Trivia::default(),
+                before_items: Trivia::default(),
+                items,
+                after_items: Vec::new(),
+                semi: token(),
+                span: span(0),
+            })
+            .collect();
+
+        Layout {
+            leading: Trivia::default(),
+            layout_token: token(),
+            after_layout: Trivia::default(),
+            left_curly: token(),
+            rows,
+            trailing: Trivia::default(),
+            right_curly: token(),
+            span: span(0),
+        }
+    }
+
+    pub fn keys(&self, count: u8) -> LayoutDefn {
+        LayoutDefn::Keys {
+            count,
+            k: token(),
+            span: span(count.to_string().len() + 1),
+        }
+    }
+
+    /// A key in a different column of the matrix to where it physically is
+    pub fn remapped(&self, position: u8) -> LayoutDefn {
+        LayoutDefn::RemappedKey {
+            left_bracket: token(),
+            position,
+            right_bracket: token(),
+            span: span(position.to_string().len() + 2),
+        }
+    }
+
+    pub fn spaces(&self, count: u8) -> LayoutDefn {
+        LayoutDefn::Spaces {
+            count,
+            s: token(),
+            span: span(count.to_string().len() + 1),
+        }
+    }
+
+    /// A layout with rows of the given numbers of keys, the shorter ones
+    /// padded with spaces on the right
+    pub fn layout_of_rows(&self, row_lens: &[usize]) -> Layout<'a> {
+        let width = row_lens.iter().copied().max().unwrap_or(0);
+        let rows = row_lens
+            .iter()
+            .map(|&len| {
+                let mut row = vec![self.keys(len as u8)];
+                if len < width {
+                    row.push(self.spaces((width - len) as u8));
+                }
+
+                row
+            })
+            .collect();
+
+        self.layout(rows)
+    }
+
+    /// The layout to put `key_count` keys into, along with the number of keys
+    /// in each of its rows. Without an existing layout to use the keys are
+    /// split into rows of `columns` keys.
+    pub fn layout_for(
+        &self,
+        existing: Option<Layout<'a>>,
+        columns: u8,
+        key_count: usize,
+    ) -> miette::Result<(Layout<'a>, Vec<usize>)> {
+        let layout = existing.unwrap_or_else(|| {
+            let columns = usize::from(columns);
+            let remainder = key_count % columns;
+
+            let mut row_lens = vec![columns; key_count / columns];
+            if remainder > 0 {
+                row_lens.push(remainder);
+            }
+
+            self.layout_of_rows(&row_lens)
+        });
+
+        let meta = LayoutMeta::process(&layout)?;
+        let row_lens = (0..meta.height).map(|y| meta.row_len(y)).collect();
+
+        Ok((layout, row_lens))
+    }
+
+    pub fn chord(&self, key: Key<'a>) -> KeyOrChord<'a> {
+        KeyOrChord::Chord(Chord {
+            right_angle: token(),
+            span: span(key.span().len() + 2),
+            key,
+            left_angle: token(),
+        })
+    }
+
+    /// A `combo` of the keys at `(row, column)` positions of a layer
+    pub fn combo(&self, positions: &[(u8, u8)], key: Key<'a>) -> Combo<'a> {
+        let positions = positions
+            .iter()
+            .map(|&(row, column)| ComboPosition {
+                row,
+                colon: token(),
+                column,
+                span: span(row.to_string().len() + column.to_string().len() + 1),
+            })
+            .collect();
+
+        Combo {
+            leading: Trivia::default(),
+            combo_token: token(),
+            after_combo: Trivia::default(),
+            positions,
+            after_positions: Vec::new(),
+            arrow: token(),
+            after_arrow: Trivia::default(),
+            key,
+            after_key: Trivia::default(),
+            semi: token(),
+            span: span(0),
+        }
+    }
+
+    pub fn layer_block(
+        &self,
+        name: impl Into<String>,
+        rows: Vec<Vec<KeyOrChord<'a>>>,
+        combos: Vec<Combo<'a>>,
+    ) -> Layer<'a> {
+        let rows = rows
+            .into_iter()
+            .map(|items| LayerRow {
+                leading: Trivia::default(),
+                before_items: Trivia::default(),
+                items,
+                after_items: Vec::new(),
+                semi: token(),
+                span: span(0),
+            })
+            .collect();
+
+        Layer {
+            leading: Trivia::default(),
+            layer_token: token(),
+            after_layer: Trivia::default(),
+            name: self.ident(name),
+            after_name: Trivia::default(),
+            extends: None,
+            left_curly: token(),
+            rows,
+            combos,
+            trailing: Trivia::default(),
+            right_curly: token(),
+            span: span(0),
+        }
+    }
+
+    /// A layer of `keys` in rows of `row_lens`, with each of its combos
+    /// written as a chord between two keys next to each other where it can
+    /// be, and as a `combo` otherwise
+    pub fn layer_with_combos(
+        &self,
+        name: &'a str,
+        keys: Vec<Key<'a>>,
+        row_lens: &[usize],
+        combos: Vec<LayerCombo<'a>>,
+    ) -> Result<Layer<'a>, String> {
+        // where each key ends up, as `(row, column)`
+        let coords = row_lens
+            .iter()
+            .enumerate()
+            .flat_map(|(row, len)| (0..*len).map(move |column| (row as u8, column as u8)))
+            .collect::<Vec<_>>();
+
+        if coords.len() != keys.len() {
+            return Err(format!(
+                "Layer {name} has {} keys, but the layout has {}",
+                keys.len(),
+                coords.len()
+            ));
+        }
+
+        let mut inline = BTreeMap::new();
+        let mut combo_items = Vec::new();
+
+        for combo in combos {
+            let Some(positions) = combo
+                .positions
+                .iter()
+                .map(|p| coords.get(*p).copied())
+                .collect::<Option<Vec<_>>>()
+            else {
+                return Err(format!(
+                    "A combo on {name} uses keys past the end of the layer"
+                ));
+            };
+
+            if let [(row_a, col_a), (row_b, col_b)] = positions.as_slice() {
+                let left = (*row_a, (*col_a).min(*col_b));
+
+                if row_a == row_b && col_a.abs_diff(*col_b) == 1 && !inline.contains_key(&left) {
+                    inline.insert(left, combo.key);
+                    continue;
+                }
+            }
+
+            combo_items.push(self.combo(&positions, combo.key));
+        }
+
+        let mut keys = keys.into_iter();
+        let rows = row_lens
+            .iter()
+            .enumerate()
+            .map(|(row, len)| {
+                let mut items = Vec::new();
+
+                for (column, key) in keys.by_ref().take(*len).enumerate() {
+                    items.push(KeyOrChord::Key(key));
+
+                    if let Some(chord) = inline.remove(&(row as u8, column as u8)) {
+                        items.push(self.chord(chord));
+                    }
+                }
+
+                items
+            })
+            .collect();
+
+        Ok(self.layer_block(name, rows, combo_items))
+    }
+
+    pub fn file(
+        &self,
+        layout: Layout<'a>,
+        options: Vec<Options<'a>>,
+        custom_keys: Vec<CustomKey<'a>>,
+        layers: Vec<Layer<'a>>,
+    ) -> File<'a> {
+        File {
+            includes: Vec::new(),
+            layout: Some(layout),
+            options,
+            custom_keys,
+            layers,
+            trailing: Trivia::default(),
+            span: span(0),
+        }
+    }
+}
+
+/// A combo on a layer, by the index of each key in the layer
+pub struct LayerCombo<'a> {
+    pub positions: Vec<usize>,
+    pub key: Key<'a>,
+}
+
+/// Custom keys for whatever an import has no equivalent for, each outputting
+/// what was there originally
+pub struct CustomKeys<'a> {
+    backend: &'static str,
+    /// Predefined keys would take precedence over custom keys with the same
+    /// name
+    predefined: HashSet<String>,
+    /// What each key outputs, by name
+    keys: BTreeMap<&'a str, String>,
+}
+
+impl<'a> CustomKeys<'a> {
+    pub fn new(backend: &'static str, predefined: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            backend,
+            predefined: predefined.into_iter().collect(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// The key that outputs `output`, named after `name` if that's free
+    pub fn key(&mut self, builder: &Builder<'a>, name: &str, output: &str) -> PlainKey<'a> {
+        if let Some((name, _)) = self.keys.iter().find(|(_, o)| *o == output) {
+            return builder.named(*name);
+        }
+
+        let base = to_ident(name);
+        let name = (1..)
+            .map(|n| {
+                if n == 1 {
+                    base.clone()
+                } else {
+                    format!("{base}_{n}")
+                }
+            })
+            .find(|name| !self.predefined.contains(name) && !self.keys.contains_key(name.as_str()))
+            .unwrap();
+
+        let name = builder.str(name);
+        self.keys.insert(name, output.to_owned());
+
+        builder.named(name)
+    }
+
+    pub fn into_custom_keys(self, builder: &Builder<'a>) -> Vec<CustomKey<'a>> {
+        self.keys
+            .into_iter()
+            .map(|(name, output)| builder.custom_key(name, vec![(self.backend, output)]))
+            .collect()
+    }
+}
+
+/// Make a name from some other format into one the layout language accepts
+pub fn to_ident(name: &str) -> String {
+    let ident = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_");
+
+    match ident.chars().next() {
+        None => "key".to_owned(),
+        Some(c) if c.is_ascii_digit() => format!("_{ident}"),
+        Some(_) => ident,
+    }
+}
+
+/// Split a keycode like `MT(MOD_LSFT, KC_A)` into the function and its
+/// arguments
+pub fn split_call(keycode: &str) -> Option<(&str, Vec<&str>)> {
+    let (function, rest) = keycode.trim().split_once('(')?;
+    let args = rest.strip_suffix(')')?;
+
+    let mut depth = 0;
+    let mut start = 0;
+    let mut split = Vec::new();
+
+    for (idx, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(args[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(args[start..].trim());
+
+    Some((function.trim(), split))
+}
+
+/// Split the keys of a layer into rows of the given lengths, if there are the
+/// right number of them
+pub fn split_rows<T>(keys: Vec<T>, row_lens: &[usize]) -> Option<Vec<Vec<T>>> {
+    if keys.len() != row_lens.iter().sum::<usize>() {
+        return None;
+    }
+
+    let mut keys = keys.into_iter();
+
+    Some(
+        row_lens
+            .iter()
+            .map(|&len| keys.by_ref().take(len).collect())
+            .collect(),
+    )
+}
+
+/// Check an imported layout the same way a written one would be, then write
+/// it out formatted
+pub fn write(file: &File<'_>, out: &mut impl Write) -> miette::Result<()> {
+    let metadata = Metadata::process(file)?;
+
+    format::format(file, &metadata, out);
+
+    Ok(())
+}
@@ -0,0 +1,339 @@
+use std::{collections::HashMap, path::Path};
+
+use indexmap::IndexMap;
+use serde_yaml::Value;
+
+use crate::{
+    emit_keymap_drawer::{layer_switch_label, modifier_label, predefined_named_keys},
+    errors::AppError,
+    import::{to_ident, token, Builder, CustomKeys, LayerCombo},
+    syntax::{File, Key, LayerSwitchKind, Layout, ModTapType, Modifier, OptionsFor, PlainKey},
+};
+
+/// The parts of a keymap-drawer YAML file that make up a layout
+#[derive(Debug, serde::Deserialize)]
+struct Spec {
+    #[serde(default)]
+    layout: LayoutSpec,
+    layers: IndexMap<String, Vec<RowOrKey>>,
+    #[serde(default)]
+    combos: Vec<ComboSpec>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct LayoutSpec {
+    qmk_info_json: Option<String>,
+    qmk_keyboard: Option<String>,
+    qmk_layout: Option<String>,
+}
+
+/// Layers can be given either as rows of keys or as one list of them
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum RowOrKey {
+    Row(Vec<KeySpec>),
+    Key(KeySpec),
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum KeySpec {
+    Full {
+        #[serde(default, alias = "t")]
+        tap: Option<Value>,
+        #[serde(default, alias = "h")]
+        hold: Option<Value>,
+        #[serde(default, rename = "type")]
+        type_: Option<String>,
+    },
+    /// Just the tap legend, or nothing
+    Legend(Value),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ComboSpec {
+    #[serde(alias = "p")]
+    key_positions: Vec<usize>,
+    #[serde(alias = "k")]
+    key: KeySpec,
+    /// Combos on no layers in particular are on all of them
+    #[serde(default, alias = "l")]
+    layers: Vec<String>,
+}
+
+/// The text of a legend, which YAML might have read as a number or boolean
+fn legend(value: &Value) -> Option<String> {
+    let legend = match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => return None,
+    };
+
+    (!legend.trim().is_empty()).then_some(legend)
+}
+
+struct Import<'a, 'b> {
+    builder: &'b Builder<'a>,
+    /// The name each layer gets, by its name in the YAML
+    layer_names: HashMap<String, &'a str>,
+    named_keys: HashMap<String, String>,
+    custom_keys: CustomKeys<'a>,
+}
+
+impl<'a, 'b> Import<'a, 'b> {
+    fn map_key(&mut self, spec: &KeySpec) -> Key<'a> {
+        let b = self.builder;
+        let (tap, hold, type_) = match spec {
+            KeySpec::Full { tap, hold, type_ } => (
+                tap.as_ref().and_then(legend),
+                hold.as_ref().and_then(legend),
+                type_.as_deref(),
+            ),
+            KeySpec::Legend(tap) => (legend(tap), None, None),
+        };
+
+        // `▽` is what keymap-drawer shows transparent keys as
+        if type_ == Some("trans") || tap.as_deref().map(str::trim) == Some("▽") {
+            return Key::Plain(b.trans());
+        }
+
+        let Some(hold) = hold else {
+            return Key::Plain(self.tap_key(tap.as_deref()));
+        };
+
+        // layer switches are shown as the layer, held by the kind of switch
+        if let Some(layer) = tap.as_deref().and_then(|t| self.layer_name(t)) {
+            let kind = [
+                LayerSwitchKind::Toggle(token()),
+                LayerSwitchKind::OneShot(token()),
+                LayerSwitchKind::Default(token()),
+            ]
+            .into_iter()
+            .find(|kind| layer_switch_label(kind) == hold.trim());
+
+            if let Some(kind) = kind {
+                return Key::Plain(b.layer_switch(kind, layer));
+            }
+        }
+
+        let tap = self.tap_key(tap.as_deref());
+        let hold = match self.layer_name(&hold) {
+            Some(layer) => b.layer(layer),
+            None => self
+                .plain_key(&hold)
+                .unwrap_or_else(|| self.custom_key(&hold)),
+        };
+
+        // which kind of mod-tap a key was isn't kept in the YAML, so they're
+        // all read back as permissive
+        b.mod_tap(tap, ModTapType::Permissive(token()), None, hold)
+    }
+
+    /// What's shown on a key, which is either a layer it switches to or a key
+    /// that's pressed
+    fn tap_key(&mut self, tap: Option<&str>) -> PlainKey<'a> {
+        let Some(tap) = tap else {
+            return self.builder.named("n");
+        };
+
+        if let Some(layer) = self.layer_name(tap) {
+            return self.builder.layer(layer);
+        }
+
+        self.plain_key(tap).unwrap_or_else(|| self.custom_key(tap))
+    }
+
+    /// Legends of named and char keys, possibly with modifiers like
+    /// `Ctrl+Shift+t`
+    fn plain_key(&self, legend: &str) -> Option<PlainKey<'a>> {
+        let mut modifiers = Vec::new();
+        let mut legend = legend.trim();
+
+        'modifiers: loop {
+            for modifier in [
+                Modifier::Ctrl(token()),
+                Modifier::Shift(token()),
+                Modifier::Alt(token()),
+                Modifier::Gui(token()),
+            ] {
+                let rest = legend
+                    .strip_prefix(modifier_label(&modifier))
+                    .and_then(|rest| rest.strip_prefix('+'))
+                    .filter(|rest| !rest.is_empty());
+
+                if let Some(rest) = rest {
+                    modifiers.push(modifier);
+                    legend = rest;
+                    continue 'modifiers;
+                }
+            }
+
+            break;
+        }
+
+        let mut chars = legend.chars();
+        let key = match (chars.next(), chars.next()) {
+            // keymap-drawer shows letters in upper case
+            (Some(c), None) => self.builder.char(c.to_ascii_lowercase()),
+            _ => self.builder.named(self.named_keys.get(legend)?.clone()),
+        };
+
+        Some(self.builder.modified(modifiers, key))
+    }
+
+    fn layer_name(&self, legend: &str) -> Option<&'a str> {
+        self.layer_names.get(legend.trim()).copied()
+    }
+
+    fn custom_key(&mut self, legend: &str) -> PlainKey<'a> {
+        let legend = legend.trim();
+
+        self.custom_keys.key(self.builder, legend, legend)
+    }
+}
+
+/// Read a keymap-drawer YAML file into a layout. The keys are laid out in rows
+/// of `layout` if one is given, then in the rows of the YAML if it has them,
+/// and otherwise in rows of `columns` keys.
+pub fn import<'a>(
+    builder: &Builder<'a>,
+    path: &Path,
+    layout: Option<Layout<'a>>,
+    columns: u8,
+) -> miette::Result<File<'a>> {
+    let import_failed = |problem: String| AppError::ImportFailed {
+        path: path.to_string_lossy().into_owned(),
+        problem,
+    };
+
+    let text = std::fs::read_to_string(path).map_err(AppError::IOError)?;
+    let spec: Spec = serde_yaml::from_str(&text).map_err(|e| import_failed(e.to_string()))?;
+
+    let mut layer_names = HashMap::new();
+    let mut names: Vec<&str> = Vec::new();
+    for original in spec.layers.keys() {
+        let name = to_ident(original);
+
+        // names need to be unique, which they may not be once made into
+        // identifiers
+        let name = if names.contains(&name.as_str()) {
+            format!("{name}_{}", names.len())
+        } else {
+            name
+        };
+
+        let name = builder.str(name);
+        names.push(name);
+        layer_names.insert(original.trim().to_owned(), name);
+    }
+
+    let mut import = Import {
+        builder,
+        layer_names,
+        named_keys: predefined_named_keys()
+            .into_iter()
+            .filter_map(|(name, legend)| Some((legend?, name)))
+            .chain(
+                [
+                    (Modifier::Ctrl(token()), "lctrl"),
+                    (Modifier::Shift(token()), "lshift"),
+                    (Modifier::Alt(token()), "lalt"),
+                    (Modifier::Gui(token()), "lgui"),
+                ]
+                .map(|(m, name)| (modifier_label(&m).to_owned(), name.to_owned())),
+            )
+            .collect(),
+        custom_keys: CustomKeys::new("keymap_drawer", predefined_named_keys().into_keys()),
+    };
+
+    let mut layer_keys = Vec::new();
+    let mut yaml_row_lens = None;
+    for items in spec.layers.values() {
+        let mut keys = Vec::new();
+        let mut row_lens = Vec::new();
+
+        for item in items {
+            match item {
+                RowOrKey::Row(row) => {
+                    keys.extend(row.iter().map(|k| import.map_key(k)));
+                    row_lens.push(row.len());
+                }
+                RowOrKey::Key(key) => keys.push(import.map_key(key)),
+            }
+        }
+
+        // the first layer's rows are the ones used for all of them
+        if yaml_row_lens.is_none() && row_lens.iter().sum::<usize>() == keys.len() {
+            row_lens.retain(|len| *len > 0);
+            yaml_row_lens = Some(row_lens);
+        }
+
+        layer_keys.push(keys);
+    }
+
+    let key_count = layer_keys.first().map_or(0, Vec::len);
+    let layout = layout.or_else(|| {
+        yaml_row_lens
+            .filter(|lens| !lens.is_empty())
+            .map(|lens| builder.layout_of_rows(&lens))
+    });
+    let (layout, row_lens) = builder.layout_for(layout, columns, key_count)?;
+
+    let mut layer_combos = (0..layer_keys.len())
+        .map(|_| Vec::new())
+        .collect::<Vec<_>>();
+    for combo in &spec.combos {
+        let layers = if combo.layers.is_empty() {
+            (0..layer_keys.len()).collect()
+        } else {
+            combo
+                .layers
+                .iter()
+                .map(|l| spec.layers.get_index_of(l.trim()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    import_failed(format!(
+                        "A combo is on layers that don't exist: {}",
+                        combo.layers.join(", ")
+                    ))
+                })?
+        };
+
+        for idx in layers {
+            let key = import.map_key(&combo.key);
+
+            layer_combos[idx].push(LayerCombo {
+                positions: combo.key_positions.clone(),
+                key,
+            });
+        }
+    }
+
+    let mut layers = Vec::new();
+    for ((name, keys), combos) in names.iter().zip(layer_keys).zip(layer_combos) {
+        let layer = builder
+            .layer_with_combos(name, keys, &row_lens, combos)
+            .map_err(import_failed)?;
+
+        layers.push(layer);
+    }
+
+    let options = [
+        ("qmk_info_json", spec.layout.qmk_info_json),
+        ("qmk_keyboard", spec.layout.qmk_keyboard),
+        ("qmk_layout", spec.layout.qmk_layout),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect::<Vec<_>>();
+    let options = if options.is_empty() {
+        Vec::new()
+    } else {
+        vec![builder.options(OptionsFor::KeymapDrawer(token()), options)]
+    };
+
+    let custom_keys = import.custom_keys.into_custom_keys(builder);
+
+    Ok(builder.file(layout, options, custom_keys, layers))
+}
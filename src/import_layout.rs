@@ -0,0 +1,263 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use indexmap::IndexMap;
+use itertools::Itertools;
+use serde_json::Value;
+
+use crate::{
+    errors::AppError,
+    import::Builder,
+    syntax::{File, LayoutDefn},
+};
+
+/// The parts of a QMK `info.json` that say where keys are
+#[derive(Debug, serde::Deserialize)]
+struct InfoJson {
+    layouts: IndexMap<String, InfoLayout>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InfoLayout {
+    layout: Vec<InfoKey>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InfoKey {
+    matrix: Option<(usize, usize)>,
+    x: f64,
+}
+
+/// A key at a position of the matrix, `x` keys from the left edge of the
+/// keyboard
+#[derive(Debug)]
+struct PlacedKey {
+    row: usize,
+    column: usize,
+    x: f64,
+}
+
+/// The keys of one of the layouts of a QMK `info.json`, which can be left
+/// out if there's only one
+fn info_json_keys(info: InfoJson, name: Option<&str>) -> Result<Vec<PlacedKey>, String> {
+    let layout = match name {
+        Some(name) => info.layouts.get(name).ok_or_else(|| {
+            format!(
+                "There's no layout called {name}, there's {}",
+                info.layouts.keys().join(", ")
+            )
+        })?,
+        None => match info.layouts.values().collect::<Vec<_>>().as_slice() {
+            [layout] => *layout,
+            _ => {
+                return Err(format!(
+                    "Pick one of the layouts with `--name`: {}",
+                    info.layouts.keys().join(", ")
+                ))
+            }
+        },
+    };
+
+    layout
+        .layout
+        .iter()
+        .enumerate()
+        .map(|(idx, key)| {
+            let (row, column) = key
+                .matrix
+                .ok_or_else(|| format!("Key {idx} of the layout has no matrix position"))?;
+
+            Ok(PlacedKey {
+                row,
+                column,
+                x: key.x,
+            })
+        })
+        .collect()
+}
+
+/// The keys of keyboard-layout-editor JSON, which each need to have their
+/// matrix position as their first legend, `row,column`, like VIA expects.
+/// Rotation isn't taken into account.
+fn kle_keys(rows: &[Value]) -> Result<Vec<PlacedKey>, String> {
+    let mut keys = Vec::new();
+
+    // the first item can be an object with the name of the keyboard and such,
+    // rather than a row
+    for row in rows.iter().filter_map(Value::as_array) {
+        let mut x = 0.0;
+        let mut width = 1.0;
+
+        for item in row {
+            match item {
+                // changes to the next key
+                Value::Object(props) => {
+                    x += props.get("x").and_then(Value::as_f64).unwrap_or(0.0);
+                    width = props.get("w").and_then(Value::as_f64).unwrap_or(width);
+                }
+                Value::String(legends) => {
+                    let position = legends.split('\n').next().unwrap_or_default();
+                    let (row, column) = position
+                        .split_once(',')
+                        .and_then(|(r, c)| Some((r.trim().parse().ok()?, c.trim().parse().ok()?)))
+                        .ok_or_else(|| {
+                            format!("The key {legends:?} doesn't have a `row,column` legend")
+                        })?;
+
+                    keys.push(PlacedKey { row, column, x });
+
+                    x += width;
+                    width = 1.0;
+                }
+                _ => return Err(format!("Didn't expect {item} in a row")),
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    /// A key in the same column of the matrix as it physically is
+    Key,
+    Remapped(u8),
+    Space,
+}
+
+/// Lay the keys out in a row for each row of the matrix, with a column for
+/// each place a key starts (to the nearest key) across the whole keyboard.
+/// Matrix rows without any keys are left out, as a layer couldn't have a row
+/// for them, so the rows after them move up but keep their columns
+fn layout_rows(builder: &Builder<'_>, keys: &[PlacedKey]) -> Result<Vec<Vec<LayoutDefn>>, String> {
+    let mut seen = HashSet::new();
+    for key in keys {
+        if !seen.insert((key.row, key.column)) {
+            return Err(format!(
+                "More than one key is at {},{} of the matrix",
+                key.row, key.column
+            ));
+        }
+    }
+
+    let starts = keys
+        .iter()
+        .map(|k| k.x.round() as i64)
+        .sorted()
+        .dedup()
+        .collect::<Vec<_>>();
+    let height = keys.iter().map(|k| k.row + 1).max().unwrap_or(0);
+
+    // the matrix column of the key at each physical column, of each row
+    let mut rows = vec![BTreeMap::new(); height];
+    for key in keys.iter().sorted_by(|a, b| a.x.total_cmp(&b.x)) {
+        let row = &mut rows[key.row];
+
+        // keys that round to the same place go one after another
+        let start = starts.binary_search(&(key.x.round() as i64)).unwrap();
+        let phys = row
+            .last_key_value()
+            .map_or(start, |(prev, _)| start.max(prev + 1));
+
+        row.insert(phys, key.column);
+    }
+
+    let width = rows
+        .iter()
+        .filter_map(|row| row.last_key_value().map(|(phys, _)| phys + 1))
+        .max()
+        .unwrap_or(0);
+
+    let too_large = |what: &str| format!("The layout has too many {what} to be written");
+    if height > usize::from(u8::MAX) {
+        return Err(too_large("rows"));
+    }
+    if width > usize::from(u8::MAX) || keys.iter().any(|k| k.column > usize::from(u8::MAX)) {
+        return Err(too_large("columns"));
+    }
+
+    let layout = rows
+        .iter()
+        .filter(|row| !row.is_empty())
+        .map(|row| {
+            let cells = (0..width).map(|phys| match row.get(&phys) {
+                Some(&column) if column == phys => Cell::Key,
+                Some(&column) => Cell::Remapped(column as u8),
+                None => Cell::Space,
+            });
+
+            cells
+                .group_by(|cell| *cell)
+                .into_iter()
+                .map(|(cell, run)| match cell {
+                    Cell::Key => builder.keys(run.count() as u8),
+                    Cell::Remapped(column) => builder.remapped(column),
+                    Cell::Space => builder.spaces(run.count() as u8),
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(layout)
+}
+
+/// Read where the keys of a keyboard are from a QMK `info.json`, using the
+/// layout called `name`, or from keyboard-layout-editor JSON, into a `layout`
+/// block
+pub fn import<'a>(
+    builder: &Builder<'a>,
+    path: &Path,
+    name: Option<&str>,
+) -> miette::Result<File<'a>> {
+    let import_failed = |problem: String| AppError::ImportFailed {
+        path: path.to_string_lossy().into_owned(),
+        problem,
+    };
+
+    let text = std::fs::read_to_string(path).map_err(AppError::IOError)?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| import_failed(e.to_string()))?;
+
+    let keys = match json {
+        Value::Array(rows) => kle_keys(&rows),
+        Value::Object(_) => serde_json::from_value(json)
+            .map_err(|e| e.to_string())
+            .and_then(|info| info_json_keys(info, name)),
+        _ => Err("This isn't a QMK info.json or keyboard-layout-editor JSON".to_string()),
+    }
+    .map_err(import_failed)?;
+
+    let layout = builder.layout(layout_rows(builder, &keys).map_err(import_failed)?);
+
+    Ok(builder.file(layout, Vec::new(), Vec::new(), Vec::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use typed_arena::Arena;
+
+    use super::*;
+
+    #[test]
+    fn matrix_rows_without_keys_are_left_out() {
+        let strings = Arena::new();
+        let builder = Builder::new(&strings);
+        let key = |row, column, x| PlacedKey { row, column, x };
+
+        // nothing is wired to row 1, and row 2 skips the matrix's column 0
+        let keys = [key(0, 0, 0.0), key(0, 1, 1.0), key(2, 1, 0.0)];
+
+        let rows = layout_rows(&builder, &keys)
+            .unwrap()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|d| d.to_doc().pretty(80).to_string())
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(rows, ["2k", "[1] 1s"]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    emit_qmk::{predefined_named_keys, CHAR_KEYCODES, MOD_BITS, NAMED_KEYCODES},
+    errors::AppError,
+    import::{split_call, split_rows, token, Builder, CustomKeys},
+    syntax::{
+        File, Key, KeyOrChord, LayerSwitchKind, Layout, ModTapType, Modifier, OptionsFor, PlainKey,
+    },
+};
+
+/// The parts of a QMK `keymap.json` that make up a layout
+#[derive(Debug, serde::Deserialize)]
+struct KeymapJson {
+    layout: String,
+    layers: Vec<Vec<String>>,
+}
+
+struct Import<'a, 'b> {
+    builder: &'b Builder<'a>,
+    layer_names: Vec<&'a str>,
+    named_keys: HashMap<&'static str, String>,
+    char_keys: HashMap<&'static str, char>,
+    custom_keys: CustomKeys<'a>,
+}
+
+impl<'a, 'b> Import<'a, 'b> {
+    fn map_key(&mut self, keycode: &str) -> Key<'a> {
+        match self.known_key(keycode) {
+            Some(key) => key,
+            None => Key::Plain(self.custom_key(keycode)),
+        }
+    }
+
+    fn known_key(&self, keycode: &str) -> Option<Key<'a>> {
+        let b = self.builder;
+        let Some((function, args)) = split_call(keycode) else {
+            return self.plain_key(keycode).map(Key::Plain);
+        };
+
+        match (function, args.as_slice()) {
+            ("MO", [layer]) => Some(Key::Plain(b.layer(self.layer_name(layer)?))),
+            ("TG", [layer]) => self.layer_switch(LayerSwitchKind::Toggle(token()), layer),
+            ("OSL", [layer]) => self.layer_switch(LayerSwitchKind::OneShot(token()), layer),
+            ("DF", [layer]) => self.layer_switch(LayerSwitchKind::Default(token()), layer),
+            ("LT", [layer, tap]) => {
+                let hold = b.layer(self.layer_name(layer)?);
+
+                Some(self.mod_tap(self.tap_key(tap)?, hold))
+            }
+            ("MT", [mods, tap]) => {
+                let hold = MOD_BITS
+                    .iter()
+                    .find(|(_, bits)| *bits == mods)
+                    .map(|(name, _)| b.named(*name))?;
+
+                Some(self.mod_tap(self.tap_key(tap)?, hold))
+            }
+            (function, [tap]) if function.ends_with("_T") => {
+                // `LSFT_T(KC_A)` is shorthand for `MT(MOD_LSFT, KC_A)`
+                let mods = format!("MOD_{}", function.trim_end_matches("_T"));
+                let hold = MOD_BITS
+                    .iter()
+                    .find(|(_, bits)| **bits == mods)
+                    .map(|(name, _)| b.named(*name))?;
+
+                Some(self.mod_tap(self.tap_key(tap)?, hold))
+            }
+            _ => self.modified_key(keycode).map(Key::Plain),
+        }
+    }
+
+    fn plain_key(&self, keycode: &str) -> Option<PlainKey<'a>> {
+        let b = self.builder;
+
+        match keycode {
+            "KC_TRNS" | "KC_TRANSPARENT" | "_______" => Some(b.trans()),
+            "XXXXXXX" => Some(b.named("n")),
+            _ => {
+                if let Some(c) = self.char_keys.get(keycode) {
+                    return Some(b.char(*c));
+                }
+
+                self.named_keys
+                    .get(keycode)
+                    .map(|name| b.named(name.clone()))
+            }
+        }
+    }
+
+    /// Keys wrapped in modifier functions, `LCTL(LSFT(KC_T))`
+    fn modified_key(&self, keycode: &str) -> Option<PlainKey<'a>> {
+        let mut modifiers = Vec::new();
+        let mut keycode = keycode;
+
+        while let Some((function, args)) = split_call(keycode) {
+            let modifier = match function {
+                "LCTL" | "C" => Modifier::Ctrl(token()),
+                "LSFT" | "S" => Modifier::Shift(token()),
+                "LALT" | "A" => Modifier::Alt(token()),
+                "LGUI" | "G" => Modifier::Gui(token()),
+                _ => return None,
+            };
+            let [inner] = args.as_slice() else {
+                return None;
+            };
+
+            modifiers.push(modifier);
+            keycode = inner;
+        }
+
+        let key = self.plain_key(keycode)?;
+
+        match key {
+            PlainKey::Named(_) | PlainKey::Char { .. } => {
+                Some(self.builder.modified(modifiers, key))
+            }
+            _ => None,
+        }
+    }
+
+    /// Mod-taps can only tap basic keycodes
+    fn tap_key(&self, keycode: &str) -> Option<PlainKey<'a>> {
+        match self.plain_key(keycode)? {
+            key @ (PlainKey::Named(_) | PlainKey::Char { .. }) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn mod_tap(&self, tap: PlainKey<'a>, hold: PlainKey<'a>) -> Key<'a> {
+        // the qmk backend makes every mod-tap permissive, so that's what
+        // they're read back as
+        let at = ModTapType::Permissive(token());
+
+        self.builder.mod_tap(tap, at, None, hold)
+    }
+
+    fn layer_switch(&self, kind: LayerSwitchKind, layer: &str) -> Option<Key<'a>> {
+        let layer = self.layer_name(layer)?;
+
+        Some(Key::Plain(self.builder.layer_switch(kind, layer)))
+    }
+
+    /// keymap.json refers to layers by index
+    fn layer_name(&self, layer: &str) -> Option<&'a str> {
+        let idx = layer.trim().parse::<usize>().ok()?;
+
+        self.layer_names.get(idx).copied()
+    }
+
+    fn custom_key(&mut self, keycode: &str) -> PlainKey<'a> {
+        let name = keycode.strip_prefix("KC_").unwrap_or(keycode);
+
+        self.custom_keys.key(self.builder, name, keycode)
+    }
+}
+
+/// Read a QMK `keymap.json` into a layout, with its keys laid out in rows of
+/// `layout` if one is given
+pub fn import<'a>(
+    builder: &Builder<'a>,
+    path: &Path,
+    layout: Option<Layout<'a>>,
+    columns: u8,
+) -> miette::Result<File<'a>> {
+    let import_failed = |problem: String| AppError::ImportFailed {
+        path: path.to_string_lossy().into_owned(),
+        problem,
+    };
+
+    let text = std::fs::read_to_string(path).map_err(AppError::IOError)?;
+    let keymap: KeymapJson =
+        serde_json::from_str(&text).map_err(|e| import_failed(e.to_string()))?;
+
+    let key_count = keymap.layers.first().map_or(0, Vec::len);
+    let (layout, row_lens) = builder.layout_for(layout, columns, key_count)?;
+
+    let mut import = Import {
+        builder,
+        layer_names: (0..keymap.layers.len())
+            .map(|idx| builder.str(format!("layer{idx}")))
+            .collect(),
+        named_keys: NAMED_KEYCODES
+            .iter()
+            .map(|(name, keycode)| (*keycode, name.clone()))
+            .chain([("KC_NO", "n".to_owned())])
+            .collect(),
+        char_keys: CHAR_KEYCODES
+            .iter()
+            .map(|(c, (keycode, _))| (*keycode, *c))
+            .collect(),
+        custom_keys: CustomKeys::new("qmk", predefined_named_keys().into_keys()),
+    };
+
+    let mut layers = Vec::new();
+    for (idx, keycodes) in keymap.layers.iter().enumerate() {
+        let keys = keycodes
+            .iter()
+            .map(|k| KeyOrChord::Key(import.map_key(k)))
+            .collect::<Vec<_>>();
+
+        let Some(rows) = split_rows(keys, &row_lens) else {
+            return Err(import_failed(format!(
+                "Layer {idx} has {} keys, but the layout has {}",
+                keycodes.len(),
+                row_lens.iter().sum::<usize>()
+            ))
+            .into());
+        };
+
+        layers.push(builder.layer_block(import.layer_names[idx], rows, Vec::new()));
+    }
+
+    let options = vec![builder.options(OptionsFor::Qmk(token()), vec![("layout", keymap.layout)])];
+
+    let custom_keys = import.custom_keys.into_custom_keys(builder);
+
+    Ok(builder.file(layout, options, custom_keys, layers))
+}
@@ -0,0 +1,683 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    emit_zmk::{predefined_named_keys, CHAR_KEYCODES, NAMED_KEYCODES},
+    errors::AppError,
+    import::{split_call, to_ident, token, Builder, CustomKeys, LayerCombo},
+    syntax::{File, Key, LayerSwitchKind, Layout, ModTapType, Modifier, PlainKey},
+};
+
+/// A devicetree node, with only as much of the syntax as keymaps use
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    label: Option<String>,
+    properties: Vec<(String, Vec<Value>)>,
+    children: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Cells(Vec<String>),
+}
+
+impl Node {
+    fn property(&self, name: &str) -> Option<&[Value]> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.property(name)? {
+            [Value::String(s)] => Some(s),
+            _ => None,
+        }
+    }
+
+    /// All the cells of a property, even when split over several `<...>`
+    fn cells(&self, name: &str) -> Option<Vec<&str>> {
+        let cells = self
+            .property(name)?
+            .iter()
+            .filter_map(|v| match v {
+                Value::Cells(c) => Some(c.iter().map(String::as_str)),
+                Value::String(_) => None,
+            })
+            .flatten()
+            .collect();
+
+        Some(cells)
+    }
+
+    fn is_compatible(&self, compatible: &str) -> bool {
+        self.string("compatible") == Some(compatible)
+    }
+
+    /// This node and everything below it
+    fn descendants(&self) -> Vec<&Node> {
+        let mut nodes = vec![self];
+
+        for child in &self.children {
+            nodes.extend(child.descendants());
+        }
+
+        nodes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    String(String),
+    Cells(Vec<String>),
+    Punct(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "`{w}`"),
+            Token::String(s) => write!(f, "{s:?}"),
+            Token::Cells(_) => write!(f, "`<...>`"),
+            Token::Punct(c) => write!(f, "`{c}`"),
+        }
+    }
+}
+
+/// Split a keymap into tokens, taking the `#define`s out on the way
+fn tokenize(text: &str, defines: &mut HashMap<String, String>) -> Result<Vec<Token>, String> {
+    let mut source = String::new();
+
+    // preprocessor directives take up whole lines, continued with `\`
+    let mut continued = false;
+    for line in strip_comments(text).lines() {
+        if continued {
+            continued = line.ends_with('\\');
+            continue;
+        }
+
+        let directive = line
+            .trim_start()
+            .strip_prefix('#')
+            .map(|d| d.split_whitespace().collect::<Vec<_>>());
+
+        match directive.as_deref() {
+            Some(["define", name, value]) => {
+                defines.insert(name.to_string(), value.to_string());
+            }
+            Some([d, ..]) if DIRECTIVES.contains(d) => continued = line.ends_with('\\'),
+            // `#binding-cells` and the like are properties
+            _ => {
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '{' | '}' | ';' | '=' | ',' | ':' => tokens.push(Token::Punct(c)),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.extend(chars.next()),
+                        Some(c) => s.push(c),
+                        None => return Err("A string is never closed".to_string()),
+                    }
+                }
+                tokens.push(Token::String(s));
+            }
+            '<' => {
+                let mut cells = String::new();
+                loop {
+                    match chars.next() {
+                        Some('>') => break,
+                        Some(c) => cells.push(c),
+                        None => return Err("A `<` is never closed".to_string()),
+                    }
+                }
+                tokens.push(Token::Cells(
+                    cells.split_whitespace().map(str::to_owned).collect(),
+                ));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{};=,:<\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+const DIRECTIVES: [&str; 9] = [
+    "include", "define", "undef", "if", "ifdef", "ifndef", "else", "elif", "endif",
+];
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+            out.push(' ');
+        } else if let Some(after) = rest.strip_prefix('"') {
+            // comment markers inside strings are left alone
+            let end = after.find('"').map_or(after.len(), |end| end + 1);
+            out.push('"');
+            out.push_str(&after[..end]);
+            rest = &after[end..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    out
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+
+        token
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(c)) if c == punct => Ok(()),
+            Some(other) => Err(format!("Expected `{punct}`, found {other}")),
+            None => Err(format!("Expected `{punct}`, but the file ended")),
+        }
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(w)) => Ok(w),
+            Some(other) => Err(format!("Expected a name, found {other}")),
+            None => Err("Expected a name, but the file ended".to_string()),
+        }
+    }
+
+    /// The nodes and properties of a node up to its closing `}`, or the end of
+    /// the file at the top level
+    fn body(&mut self, node: &mut Node, top_level: bool) -> Result<(), String> {
+        loop {
+            match self.peek() {
+                None if top_level => return Ok(()),
+                None => return Err("A `{` is never closed".to_string()),
+                Some(Token::Punct('}')) if !top_level => {
+                    self.next();
+                    return self.expect(';');
+                }
+                Some(Token::Punct(';')) => {
+                    self.next();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let first = self.word()?;
+            match self.peek() {
+                Some(Token::Punct(':')) => {
+                    self.next();
+                    let name = self.word()?;
+                    self.expect('{')?;
+
+                    let mut child = Node {
+                        name,
+                        label: Some(first),
+                        ..Node::default()
+                    };
+                    self.body(&mut child, false)?;
+                    node.children.push(child);
+                }
+                Some(Token::Punct('{')) => {
+                    self.next();
+
+                    let mut child = Node {
+                        name: first,
+                        ..Node::default()
+                    };
+                    self.body(&mut child, false)?;
+                    node.children.push(child);
+                }
+                Some(Token::Punct('=')) => {
+                    self.next();
+
+                    let mut values = Vec::new();
+                    loop {
+                        match self.next() {
+                            Some(Token::String(s)) => values.push(Value::String(s)),
+                            Some(Token::Cells(c)) => values.push(Value::Cells(c)),
+                            Some(other) => {
+                                return Err(format!("Expected the value of {first}, found {other}"))
+                            }
+                            None => return Err("The file ended in a property".to_string()),
+                        }
+
+                        match self.next() {
+                            Some(Token::Punct(',')) => continue,
+                            Some(Token::Punct(';')) => break,
+                            _ => return Err(format!("Expected `;` after {first}")),
+                        }
+                    }
+
+                    node.properties.push((first, values));
+                }
+                Some(Token::Punct(';')) => {
+                    self.next();
+                    node.properties.push((first, Vec::new()));
+                }
+                _ => return Err(format!("Didn't expect what comes after {first}")),
+            }
+        }
+    }
+}
+
+/// A `zmk,behavior-hold-tap`, which gets used as `&name hold tap`
+#[derive(Debug)]
+struct HoldTap {
+    hold_behavior: String,
+    tap_behavior: String,
+    flavor: String,
+    tapping_term: u32,
+}
+
+impl HoldTap {
+    fn new(hold_behavior: &str, flavor: &str) -> Self {
+        Self {
+            hold_behavior: hold_behavior.to_string(),
+            tap_behavior: "&kp".to_string(),
+            flavor: flavor.to_string(),
+            tapping_term: DEFAULT_TAPPING_TERM,
+        }
+    }
+
+    /// `&mt` and `&lt` as ZMK defines them
+    fn builtin() -> HashMap<String, HoldTap> {
+        HashMap::from([
+            ("&mt".to_string(), Self::new("&kp", "hold-preferred")),
+            ("&lt".to_string(), Self::new("&mo", "tap-preferred")),
+        ])
+    }
+
+    /// Update from the properties of a node defining or overriding it
+    fn configure(&mut self, node: &Node) {
+        if let Some(flavor) = node.string("flavor") {
+            self.flavor = flavor.to_string();
+        }
+
+        if let Some(term) = node
+            .cells("tapping-term-ms")
+            .and_then(|c| c.first()?.parse().ok())
+        {
+            self.tapping_term = term;
+        }
+
+        if let Some(bindings) = node.cells("bindings") {
+            if let [hold, tap] = bindings.as_slice() {
+                self.hold_behavior = hold.to_string();
+                self.tap_behavior = tap.to_string();
+            }
+        }
+    }
+}
+
+/// What the zmk backend uses unless told otherwise
+const DEFAULT_TAPPING_TERM: u32 = 200;
+
+struct Import<'a, 'b> {
+    builder: &'b Builder<'a>,
+    layer_names: Vec<&'a str>,
+    defines: HashMap<String, String>,
+    hold_taps: HashMap<String, HoldTap>,
+    named_keys: HashMap<String, String>,
+    char_keys: HashMap<String, char>,
+    custom_keys: CustomKeys<'a>,
+}
+
+impl<'a, 'b> Import<'a, 'b> {
+    fn map_binding(&mut self, binding: &[&str]) -> Key<'a> {
+        match self.known_binding(binding) {
+            Some(key) => key,
+            None => {
+                let output = binding.join(" ");
+
+                Key::Plain(self.custom_keys.key(self.builder, &output, &output))
+            }
+        }
+    }
+
+    fn known_binding(&self, binding: &[&str]) -> Option<Key<'a>> {
+        let b = self.builder;
+        let switch = |kind, layer| {
+            let layer = self.layer_name(layer)?;
+
+            Some(Key::Plain(b.layer_switch(kind, layer)))
+        };
+
+        match binding {
+            ["&trans"] => Some(Key::Plain(b.trans())),
+            ["&none"] => Some(Key::Plain(b.named("n"))),
+            ["&kp", keycode] => self.keycode_key(keycode).map(Key::Plain),
+            ["&mo", layer] => Some(Key::Plain(b.layer(self.layer_name(layer)?))),
+            ["&tog", layer] => switch(LayerSwitchKind::Toggle(token()), layer),
+            ["&sl", layer] => switch(LayerSwitchKind::OneShot(token()), layer),
+            ["&to", layer] => switch(LayerSwitchKind::Default(token()), layer),
+            [behavior, hold, tap] => {
+                let hold_tap = self.hold_taps.get(*behavior)?;
+
+                let hold = match hold_tap.hold_behavior.as_str() {
+                    "&mo" => b.layer(self.layer_name(hold)?),
+                    "&kp" => self.keycode_key(hold)?,
+                    _ => return None,
+                };
+                let tap = match hold_tap.tap_behavior.as_str() {
+                    "&kp" => self.keycode_key(tap)?,
+                    _ => return None,
+                };
+
+                // balanced is permissive hold and hold-preferred is hold on
+                // other key press, the others (like tap-preferred for `&lt`)
+                // stay custom keys so they keep behaving the same
+                let at = match hold_tap.flavor.as_str() {
+                    "balanced" => ModTapType::Permissive(token()),
+                    "hold-preferred" => ModTapType::OnOtherKey(token()),
+                    _ => return None,
+                };
+                let timeout = (hold_tap.tapping_term != DEFAULT_TAPPING_TERM)
+                    .then_some(hold_tap.tapping_term);
+
+                Some(b.mod_tap(tap, at, timeout, hold))
+            }
+            _ => None,
+        }
+    }
+
+    /// Keycodes given to `&kp`, including ones wrapped in modifier functions
+    /// like `LC(LS(T))`
+    fn keycode_key(&self, keycode: &str) -> Option<PlainKey<'a>> {
+        let mut modifiers = Vec::new();
+        let mut keycode = keycode;
+
+        while let Some((function, args)) = split_call(keycode) {
+            let modifier = match function {
+                "LC" => Modifier::Ctrl(token()),
+                "LS" => Modifier::Shift(token()),
+                "LA" => Modifier::Alt(token()),
+                "LG" => Modifier::Gui(token()),
+                _ => return None,
+            };
+            let [inner] = args.as_slice() else {
+                return None;
+            };
+
+            modifiers.push(modifier);
+            keycode = inner;
+        }
+
+        let keycode = KEYCODE_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == keycode)
+            .map_or(keycode, |(_, canonical)| canonical);
+
+        let key = if let Some(c) = self.char_keys.get(keycode) {
+            self.builder.char(*c)
+        } else {
+            self.builder.named(self.named_keys.get(keycode)?.clone())
+        };
+
+        Some(self.builder.modified(modifiers, key))
+    }
+
+    /// Layers are referred to by index, often through a `#define`
+    fn layer_index(&self, layer: &str) -> Option<usize> {
+        let layer = self.defines.get(layer).map_or(layer, String::as_str);
+        let idx = layer
+            .trim_matches(|c| c == '(' || c == ')')
+            .parse::<usize>()
+            .ok()?;
+
+        (idx < self.layer_names.len()).then_some(idx)
+    }
+
+    fn layer_name(&self, layer: &str) -> Option<&'a str> {
+        Some(self.layer_names[self.layer_index(layer)?])
+    }
+}
+
+/// Other names ZMK accepts for the keycodes the zmk backend uses
+const KEYCODE_ALIASES: [(&str, &str); 34] = [
+    ("ESCAPE", "ESC"),
+    ("SPC", "SPACE"),
+    ("BACKSPACE", "BSPC"),
+    ("DELETE", "DEL"),
+    ("LSHIFT", "LSHFT"),
+    ("LEFT_SHIFT", "LSHFT"),
+    ("RSHIFT", "RSHFT"),
+    ("RIGHT_SHIFT", "RSHFT"),
+    ("LCTL", "LCTRL"),
+    ("LEFT_CONTROL", "LCTRL"),
+    ("RCTL", "RCTRL"),
+    ("RIGHT_CONTROL", "RCTRL"),
+    ("LEFT_ALT", "LALT"),
+    ("RIGHT_ALT", "RALT"),
+    ("LEFT_GUI", "LGUI"),
+    ("LCMD", "LGUI"),
+    ("LWIN", "LGUI"),
+    ("RIGHT_GUI", "RGUI"),
+    ("RCMD", "RGUI"),
+    ("RWIN", "RGUI"),
+    ("RETURN", "RET"),
+    ("ENTER", "RET"),
+    ("PAGE_UP", "PG_UP"),
+    ("PAGE_DOWN", "PG_DN"),
+    ("LEFT_ARROW", "LEFT"),
+    ("RIGHT_ARROW", "RIGHT"),
+    ("UP_ARROW", "UP"),
+    ("DOWN_ARROW", "DOWN"),
+    ("SEMICOLON", "SEMI"),
+    ("APOSTROPHE", "SQT"),
+    ("SINGLE_QUOTE", "SQT"),
+    ("PERIOD", "DOT"),
+    ("BACKSLASH", "BSLH"),
+    ("QUESTION", "QMARK"),
+];
+
+/// Split a list of cells into bindings, each starting with its behaviour
+fn split_bindings<'c>(cells: &[&'c str]) -> Vec<Vec<&'c str>> {
+    let mut bindings: Vec<Vec<&str>> = Vec::new();
+
+    for cell in cells {
+        match bindings.last_mut() {
+            Some(binding) if !cell.starts_with('&') => binding.push(cell),
+            _ => bindings.push(vec![cell]),
+        }
+    }
+
+    bindings
+}
+
+/// Read a ZMK `.keymap` into a layout, with its keys laid out in rows of
+/// `layout` if one is given
+pub fn import<'a>(
+    builder: &Builder<'a>,
+    path: &Path,
+    layout: Option<Layout<'a>>,
+    columns: u8,
+) -> miette::Result<File<'a>> {
+    let import_failed = |problem: String| AppError::ImportFailed {
+        path: path.to_string_lossy().into_owned(),
+        problem,
+    };
+
+    let text = std::fs::read_to_string(path).map_err(AppError::IOError)?;
+
+    let mut defines = HashMap::new();
+    let mut parser = Parser {
+        tokens: tokenize(&text, &mut defines).map_err(import_failed)?,
+        pos: 0,
+    };
+    let mut root = Node::default();
+    parser.body(&mut root, true).map_err(import_failed)?;
+    let nodes = root.descendants();
+
+    let keymap = nodes
+        .iter()
+        .find(|n| n.is_compatible("zmk,keymap"))
+        .ok_or_else(|| import_failed("There's no `zmk,keymap` node".to_string()))?;
+
+    let mut hold_taps = HoldTap::builtin();
+    for node in &nodes {
+        if let Some(hold_tap) = hold_taps.get_mut(&node.name) {
+            // overrides of the builtin ones, `&mt { ... };`
+            hold_tap.configure(node);
+        } else if node.is_compatible("zmk,behavior-hold-tap") {
+            let mut hold_tap = HoldTap::new("&kp", "hold-preferred");
+            hold_tap.configure(node);
+
+            let name = node.label.as_ref().unwrap_or(&node.name);
+            hold_taps.insert(format!("&{name}"), hold_tap);
+        }
+    }
+
+    let mut layer_names: Vec<&str> = Vec::new();
+    for layer in &keymap.children {
+        let name = layer
+            .string("display-name")
+            .map(to_ident)
+            .unwrap_or_else(|| to_ident(layer.name.trim_end_matches("_layer")));
+
+        // names need to be unique, display names needn't be
+        let name = if layer_names.contains(&name.as_str()) {
+            format!("{name}_{}", layer_names.len())
+        } else {
+            name
+        };
+
+        layer_names.push(builder.str(name));
+    }
+
+    let mut import = Import {
+        builder,
+        layer_names,
+        defines,
+        hold_taps,
+        named_keys: NAMED_KEYCODES
+            .iter()
+            .map(|(name, keycode)| (keycode.clone(), name.clone()))
+            .collect(),
+        char_keys: CHAR_KEYCODES
+            .iter()
+            .map(|(c, keycode)| (keycode.clone(), *c))
+            .collect(),
+        custom_keys: CustomKeys::new("zmk", predefined_named_keys().into_keys()),
+    };
+
+    let mut layer_keys = Vec::new();
+    for (idx, layer) in keymap.children.iter().enumerate() {
+        let cells = layer.cells("bindings").unwrap_or_default();
+        let keys = split_bindings(&cells)
+            .iter()
+            .map(|binding| import.map_binding(binding))
+            .collect::<Vec<_>>();
+
+        if let Some(first) = layer_keys.first().map(Vec::len) {
+            if keys.len() != first {
+                return Err(import_failed(format!(
+                    "Layer {} has {} keys, but the first has {first}",
+                    import.layer_names[idx],
+                    keys.len()
+                ))
+                .into());
+            }
+        }
+
+        layer_keys.push(keys);
+    }
+
+    let mut layer_combos = (0..layer_keys.len())
+        .map(|_| Vec::new())
+        .collect::<Vec<_>>();
+    for combo in nodes
+        .iter()
+        .filter(|n| n.is_compatible("zmk,combos"))
+        .flat_map(|n| &n.children)
+    {
+        let positions = combo
+            .cells("key-positions")
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| import_failed(format!("{} has bad key positions", combo.name)))?;
+        let bindings = combo.cells("bindings").unwrap_or_default();
+
+        // combos without layers are on all of them
+        let layers = match combo.cells("layers") {
+            Some(layers) => layers
+                .iter()
+                .map(|l| import.layer_index(l))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    import_failed(format!("{} is on layers that don't exist", combo.name))
+                })?,
+            None => (0..layer_keys.len()).collect(),
+        };
+
+        for idx in layers {
+            let key = import.map_binding(&bindings);
+
+            layer_combos[idx].push(LayerCombo {
+                positions: positions.clone(),
+                key,
+            });
+        }
+    }
+
+    let key_count = layer_keys.first().map_or(0, Vec::len);
+    let (layout, row_lens) = builder.layout_for(layout, columns, key_count)?;
+
+    let mut layers = Vec::new();
+    for ((name, keys), combos) in import.layer_names.iter().zip(layer_keys).zip(layer_combos) {
+        let layer = builder
+            .layer_with_combos(name, keys, &row_lens, combos)
+            .map_err(import_failed)?;
+
+        layers.push(layer);
+    }
+
+    let custom_keys = import.custom_keys.into_custom_keys(builder);
+
+    Ok(builder.file(layout, Vec::new(), custom_keys, layers))
+}
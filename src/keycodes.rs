@@ -0,0 +1,163 @@
+use once_cell::sync::Lazy;
+
+/// A named key, along with what each backend calls it
+#[derive(Debug)]
+pub struct Keycode {
+    /// What the key is called in layout files
+    pub name: String,
+    /// What's written on the key when drawing layers
+    pub legend: String,
+    pub qmk: Option<String>,
+    pub zmk: Option<String>,
+    pub kanata: Option<String>,
+    /// Without the `KC.` prefix
+    pub kmk: Option<String>,
+    /// The `KeyCode` variant
+    pub keyberon: Option<String>,
+    /// The `KeyCode` variant
+    pub rmk: Option<String>,
+}
+
+pub static KEYCODES: Lazy<Vec<Keycode>> = Lazy::new(keycodes);
+
+/// Every named key a backend has a keycode for, along with that keycode
+pub fn named_keycodes(
+    backend: fn(&Keycode) -> &Option<String>,
+) -> impl Iterator<Item = (&'static str, &'static str)> {
+    KEYCODES
+        .iter()
+        .filter_map(move |k| Some((k.name.as_str(), backend(k).as_deref()?)))
+}
+
+/// Whether a char key can be typed by every backend, which is the case for
+/// everything on a US keyboard
+pub fn is_typeable(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || "-=[]\\;'`,./!@#$%^&*()_+{}|:\"~<>?".contains(c)
+}
+
+/// Named keys as `[name, legend, qmk, zmk, kanata, kmk, keyberon, rmk]`,
+/// with an empty keycode for backends that don't have the key
+#[rustfmt::skip]
+const NAMED_KEYS: [[&str; 8]; 51] = [
+    ["esc", "Escape", "KC_ESC", "ESC", "esc", "ESC", "Escape", "Escape"],
+    ["space", "Space", "KC_SPC", "SPACE", "spc", "SPC", "Space", "Space"],
+    ["bspace", "BSpace", "KC_BSPC", "BSPC", "bspc", "BSPC", "BSpace", "Backspace"],
+    ["del", "Delete", "KC_DEL", "DEL", "del", "DEL", "Delete", "Delete"],
+    ["insert", "Insert", "KC_INS", "INS", "ins", "INS", "Insert", "Insert"],
+    ["enter", "Enter", "KC_ENT", "RET", "ret", "ENT", "Enter", "Enter"],
+    ["tab", "Tab", "KC_TAB", "TAB", "tab", "TAB", "Tab", "Tab"],
+    ["lshift", "LShift", "KC_LSFT", "LSHFT", "lsft", "LSFT", "LShift", "LShift"],
+    ["rshift", "RShift", "KC_RSFT", "RSHFT", "rsft", "RSFT", "RShift", "RShift"],
+    ["lctrl", "LCtrl", "KC_LCTL", "LCTRL", "lctl", "LCTL", "LCtrl", "LCtrl"],
+    ["rctrl", "RCtrl", "KC_RCTL", "RCTRL", "rctl", "RCTL", "RCtrl", "RCtrl"],
+    ["lalt", "LAlt", "KC_LALT", "LALT", "lalt", "LALT", "LAlt", "LAlt"],
+    ["ralt", "RAlt", "KC_RALT", "RALT", "ralt", "RALT", "RAlt", "RAlt"],
+    ["lgui", "LGui", "KC_LGUI", "LGUI", "lmet", "LGUI", "LGui", "LGui"],
+    ["rgui", "RGui", "KC_RGUI", "RGUI", "rmet", "RGUI", "RGui", "RGui"],
+    ["left", "Left", "KC_LEFT", "LEFT", "left", "LEFT", "Left", "Left"],
+    ["up", "Up", "KC_UP", "UP", "up", "UP", "Up", "Up"],
+    ["right", "Right", "KC_RGHT", "RIGHT", "rght", "RGHT", "Right", "Right"],
+    ["down", "Down", "KC_DOWN", "DOWN", "down", "DOWN", "Down", "Down"],
+    ["home", "Home", "KC_HOME", "HOME", "home", "HOME", "Home", "Home"],
+    ["end", "End", "KC_END", "END", "end", "END", "End", "End"],
+    ["pgup", "PgUp", "KC_PGUP", "PG_UP", "pgup", "PGUP", "PgUp", "PageUp"],
+    ["pgdown", "PgDown", "KC_PGDN", "PG_DN", "pgdn", "PGDN", "PgDown", "PageDown"],
+    ["pscreen", "PrtSc", "KC_PSCR", "PSCRN", "prnt", "PSCR", "PScreen", "PrintScreen"],
+    ["scrolllock", "ScrLk", "KC_SCRL", "SLCK", "slck", "SLCK", "ScrollLock", "ScrollLock"],
+    ["pause", "Pause", "KC_PAUS", "PAUSE_BREAK", "pause", "PAUS", "Pause", "Pause"],
+    ["capslock", "Caps", "KC_CAPS", "CAPS", "caps", "CAPS", "CapsLock", "CapsLock"],
+    ["numlock", "NumLk", "KC_NUM", "KP_NUM", "nlck", "NLCK", "NumLock", "NumLock"],
+    ["app", "App", "KC_APP", "K_APP", "comp", "APP", "Application", "Application"],
+    ["kpslash", "Kp/", "KC_PSLS", "KP_SLASH", "kp/", "PSLS", "KpSlash", "KpSlash"],
+    ["kpasterisk", "Kp*", "KC_PAST", "KP_ASTERISK", "kp*", "PAST", "KpAsterisk", "KpAsterisk"],
+    ["kpminus", "Kp-", "KC_PMNS", "KP_MINUS", "kp-", "PMNS", "KpMinus", "KpMinus"],
+    ["kpplus", "Kp+", "KC_PPLS", "KP_PLUS", "kp+", "PPLS", "KpPlus", "KpPlus"],
+    ["kpenter", "KpEnter", "KC_PENT", "KP_ENTER", "kprt", "PENT", "KpEnter", "KpEnter"],
+    ["kpdot", "Kp.", "KC_PDOT", "KP_DOT", "kp.", "PDOT", "KpDot", "KpDot"],
+    ["kpequal", "Kp=", "KC_PEQL", "KP_EQUAL", "kp=", "PEQL", "KpEqual", "KpEqual"],
+    ["kpcomma", "Kp,", "KC_PCMM", "KP_COMMA", "", "PCMM", "KpComma", "KpComma"],
+    [
+        "nonusbslash", "NonUS\\", "KC_NUBS", "NON_US_BSLH", "102d", "NUBS", "NonUsBslash",
+        "NonusBackslash",
+    ],
+    ["nonushash", "NonUS#", "KC_NUHS", "NON_US_HASH", "", "NUHS", "NonUsHash", "NonusHash"],
+    ["volup", "VolUp", "KC_VOLU", "C_VOL_UP", "volu", "VOLU", "VolUp", "AudioVolUp"],
+    ["voldown", "VolDown", "KC_VOLD", "C_VOL_DN", "vold", "VOLD", "VolDown", "AudioVolDown"],
+    ["mute", "Mute", "KC_MUTE", "C_MUTE", "mute", "MUTE", "Mute", "AudioMute"],
+    ["next", "Next", "KC_MNXT", "C_NEXT", "next", "MNXT", "MediaNextSong", "MediaNextTrack"],
+    ["prev", "Prev", "KC_MPRV", "C_PREV", "prev", "MPRV", "MediaPreviousSong", "MediaPrevTrack"],
+    ["playpause", "Play", "KC_MPLY", "C_PP", "pp", "MPLY", "MediaPlayPause", "MediaPlayPause"],
+    ["mstop", "Stop", "KC_MSTP", "C_STOP", "", "MSTP", "MediaStop", "MediaStop"],
+    ["eject", "Eject", "KC_EJCT", "C_EJECT", "", "EJCT", "MediaEjectCD", "MediaEject"],
+    ["ffwd", "FFwd", "KC_MFFD", "C_FF", "", "MFFD", "", "MediaFastForward"],
+    ["rewind", "Rewind", "KC_MRWD", "C_RW", "", "MRWD", "", "MediaRewind"],
+    ["briup", "BriUp", "KC_BRIU", "C_BRI_UP", "brup", "BRIU", "", "BrightnessUp"],
+    ["bridown", "BriDown", "KC_BRID", "C_BRI_DN", "brdown", "BRID", "", "BrightnessDown"],
+];
+
+fn keycodes() -> Vec<Keycode> {
+    let code = |code: &str| (!code.is_empty()).then(|| code.to_owned());
+
+    let mut keys = NAMED_KEYS
+        .iter()
+        .map(
+            |[name, legend, qmk, zmk, kanata, kmk, keyberon, rmk]| Keycode {
+                name: name.to_string(),
+                legend: legend.to_string(),
+                qmk: code(qmk),
+                zmk: code(zmk),
+                kanata: code(kanata),
+                kmk: code(kmk),
+                keyberon: code(keyberon),
+                rmk: code(rmk),
+            },
+        )
+        .collect::<Vec<_>>();
+
+    keys.extend((1..=24).map(|n| Keycode {
+        name: format!("f{n}"),
+        legend: format!("F{n}"),
+        qmk: Some(format!("KC_F{n}")),
+        zmk: Some(format!("F{n}")),
+        kanata: Some(format!("f{n}")),
+        kmk: Some(format!("F{n}")),
+        keyberon: Some(format!("F{n}")),
+        rmk: Some(format!("F{n}")),
+    }));
+
+    keys.extend((0..=9).map(|n| Keycode {
+        name: format!("kp{n}"),
+        legend: format!("Kp{n}"),
+        qmk: Some(format!("KC_P{n}")),
+        zmk: Some(format!("KP_N{n}")),
+        kanata: Some(format!("kp{n}")),
+        kmk: Some(format!("P{n}")),
+        keyberon: Some(format!("Kp{n}")),
+        rmk: Some(format!("Kp{n}")),
+    }));
+
+    // the keys for Japanese, Korean and other layouts
+    keys.extend((1..=9).map(|n| Keycode {
+        name: format!("intl{n}"),
+        legend: format!("Intl{n}"),
+        qmk: Some(format!("KC_INT{n}")),
+        zmk: Some(format!("INT{n}")),
+        kanata: None,
+        kmk: Some(format!("INT{n}")),
+        keyberon: Some(format!("Intl{n}")),
+        rmk: Some(format!("International{n}")),
+    }));
+
+    keys.extend((1..=9).map(|n| Keycode {
+        name: format!("lang{n}"),
+        legend: format!("Lang{n}"),
+        qmk: Some(format!("KC_LNG{n}")),
+        zmk: Some(format!("LANG{n}")),
+        kanata: None,
+        kmk: Some(format!("LANG{n}")),
+        keyberon: Some(format!("Lang{n}")),
+        rmk: Some(format!("Language{n}")),
+    }));
+
+    keys
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::{
+    emit_keymap_drawer::{layer_switch_label, modifier_label, predefined_named_keys},
+    errors::Errors,
+    process::{unknown_named_key, KeyAt, LayerMeta, LayoutMeta},
+    syntax::{File, Key, PlainKey},
+};
+
+/// What gets written on a key when drawing a layer
+pub struct Legend {
+    pub tap: Option<String>,
+    pub hold: Option<String>,
+    pub trans: bool,
+}
+
+/// A layer's legends by physical position, chords are by the physical
+/// positions of the keys they're made of
+pub struct LayerLegends {
+    pub name: String,
+    pub keys: Vec<((u8, u8), Legend)>,
+    pub chords: Vec<(Vec<(u8, u8)>, Legend)>,
+}
+
+/// Turns keys into legends, for the backends that draw layers themselves
+pub struct Legends {
+    named_keys: HashMap<String, Option<String>>,
+}
+
+impl Legends {
+    /// Custom keys are labelled the same as for keymap-drawer unless they have
+    /// an `svg` output
+    pub fn new(file: &File<'_>) -> Self {
+        let mut named_keys = file
+            .custom_keys
+            .iter()
+            .filter_map(|k| {
+                k.outputs
+                    .iter()
+                    .find(|d| d.name.s == "svg")
+                    .or_else(|| k.outputs.iter().find(|d| d.name.s == "keymap_drawer"))
+                    .map(|d| (k.name.s.to_string(), Some(d.output.text.to_string())))
+            })
+            .collect::<HashMap<_, _>>();
+
+        named_keys.extend(predefined_named_keys());
+
+        Self { named_keys }
+    }
+
+    fn unmodified_legend(&self, k: &PlainKey<'_>) -> miette::Result<Option<String>> {
+        match k {
+            PlainKey::Named(name) => {
+                if let Some(k) = self.named_keys.get(name.s) {
+                    return Ok(k.clone());
+                }
+
+                Err(unknown_named_key(self.named_keys.keys(), name).into())
+            }
+            PlainKey::Trans(_) => Ok(None),
+            PlainKey::Layer { layer, .. } => Ok(Some(layer.s.to_string())),
+            PlainKey::LayerSwitch { kind, layer, .. } => {
+                Ok(Some(format!("{} {}", layer_switch_label(kind), layer.s)))
+            }
+            PlainKey::Char { c, .. } => Ok(Some(c.to_string())),
+            PlainKey::Modified { .. } => {
+                unreachable!("the parser only allows modifiers on named and char keys")
+            }
+        }
+    }
+
+    fn plain_legend(&self, k: &PlainKey<'_>) -> miette::Result<Option<String>> {
+        let PlainKey::Modified { modifiers, key, .. } = k else {
+            return self.unmodified_legend(k);
+        };
+
+        let legend = self.unmodified_legend(key)?.unwrap_or_default();
+        let modifiers = modifiers.iter().map(modifier_label).join("+");
+
+        Ok(Some(format!("{modifiers}+{legend}")))
+    }
+
+    pub fn legend(&self, k: &Key<'_>) -> miette::Result<Legend> {
+        match k {
+            Key::Plain(PlainKey::Trans(_)) => Ok(Legend {
+                tap: None,
+                hold: None,
+                trans: true,
+            }),
+            Key::Plain(PlainKey::LayerSwitch { kind, layer, .. }) => Ok(Legend {
+                tap: Some(layer.s.to_string()),
+                hold: Some(layer_switch_label(kind).to_string()),
+                trans: false,
+            }),
+            Key::Plain(k) => Ok(Legend {
+                tap: self.plain_legend(k)?,
+                hold: None,
+                trans: false,
+            }),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap { tap, hold, .. } => Ok(Legend {
+                tap: self.plain_legend(tap)?,
+                hold: self.plain_legend(hold)?,
+                trans: false,
+            }),
+        }
+    }
+
+    /// Legends for every key of a layer where it physically is, leaving the
+    /// spaces out
+    pub fn layer(
+        &self,
+        layout: &LayoutMeta,
+        layer: &LayerMeta<'_>,
+        errors: &mut Errors,
+    ) -> LayerLegends {
+        let keys = layout
+            .phys_to_matrix
+            .iter()
+            .filter_map(|(phys, at)| match at {
+                KeyAt::Located(pos) => Some((phys, pos)),
+                KeyAt::Space => None,
+            })
+            .filter_map(|(phys, pos)| {
+                let key = layer.keys.iter().find(|k| k.matrix_pos == *pos)?;
+
+                Some((*phys, errors.take(self.legend(&key.key))?))
+            })
+            .collect();
+
+        let chords = layer
+            .chords
+            .iter()
+            .filter_map(|chord| {
+                let positions = chord
+                    .layout_positions
+                    .iter()
+                    .filter_map(|pos| layout.layout_to_phys.get(pos).copied())
+                    .collect();
+
+                Some((positions, errors.take(self.legend(&chord.key))?))
+            })
+            .collect();
+
+        LayerLegends {
+            name: layer.name.to_string(),
+            keys,
+            chords,
+        }
+    }
+}
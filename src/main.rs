@@ -1,22 +1,42 @@
 #![feature(adt_const_params)]
 
+mod check;
+mod emit_json;
+mod emit_kanata;
+mod emit_keyberon;
 mod emit_keymap_drawer;
+mod emit_kmk;
+mod emit_qmk;
+mod emit_rmk;
 mod emit_rustydilemma;
+mod emit_svg;
+mod emit_vial;
+mod emit_zmk;
 mod errors;
 mod format;
+mod import;
+mod import_keymap_drawer;
+mod import_layout;
+mod import_qmk;
+mod import_zmk;
+mod keycodes;
+mod legend;
 mod parse;
 mod process;
+mod serialize;
+mod show;
+mod sources;
 mod syntax;
 
 use std::path::PathBuf;
 
-use chumsky::Parser as _;
-use clap::{CommandFactory, Parser};
-use miette::NamedSource;
+use clap::{CommandFactory, Parser, ValueEnum};
 use patharg::OutputArg;
 use process::Metadata;
+use sources::Sources;
+use typed_arena::Arena;
 
-use crate::errors::AppError;
+use crate::errors::{AppError, BackendFailed, Errors};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -34,9 +54,12 @@ struct Args {
 
 #[derive(clap::Subcommand, Debug)]
 enum Command {
+    Check(Check),
     Emit(Emit),
     Format(Format),
     GenCompletions(GenCompletions),
+    Import(Import),
+    Show(Show),
 }
 
 /// Process a keyboard layout and emit for a specified backend
@@ -55,31 +78,19 @@ struct Emit {
 
 impl Emit {
     fn run(self) -> miette::Result<()> {
-        let source = std::fs::read_to_string(&self.file).map_err(AppError::IOError)?;
-        let r = match parse::file().parse(&source).into_result() {
-            Ok(r) => r,
-            Err(e) => {
-                for m in e {
-                    let e = miette::Error::new(parse::convert_error(m));
-                    return Err(e);
-                }
-                return Ok(());
-            }
-        };
+        let sources = Sources::load(&self.file)?;
+
+        self.emit(&sources)
+            .map_err(|e| e.with_source_code(sources.clone()))
+    }
+
+    fn emit(&self, sources: &Sources) -> miette::Result<()> {
+        let r = sources.parse()?.merged;
 
         let metadata = Metadata::process(&r)?;
 
         let mut output = self.output.create().map_err(AppError::IOError)?;
-        match self.mode {
-            EmitBackend::RustyDilemma => {
-                emit_rustydilemma::emit(&r, &metadata, &mut output)?;
-            }
-            EmitBackend::KeymapDrawer => {
-                emit_keymap_drawer::emit(&r, &metadata, &mut output)?;
-            }
-        }
-
-        Ok(())
+        self.mode.emit(&r, &metadata, &mut output)
     }
 }
 
@@ -89,6 +100,116 @@ enum EmitBackend {
     RustyDilemma,
     /// Generate a layout file for https://github.com/caksoylar/keymap-drawer
     KeymapDrawer,
+    /// Generate a keymap.c for QMK
+    Qmk,
+    /// Generate a devicetree .keymap for ZMK
+    Zmk,
+    /// Generate a .kbd configuration for kanata
+    Kanata,
+    /// Generate a keymap.py for KMK
+    Kmk,
+    /// Generate a layout file for any keyberon based firmware
+    Keyberon,
+    /// Generate a Rust keymap for RMK
+    Rmk,
+    /// Generate a .vil layout to load into Vial
+    Vial,
+    /// Draw every layer as an SVG
+    Svg,
+    /// Export the processed layout as JSON, for use by other tools
+    Json,
+}
+
+impl EmitBackend {
+    fn emit<'a>(
+        self,
+        file: &'a syntax::File<'a>,
+        metadata: &'a Metadata<'a>,
+        out: &mut impl std::io::Write,
+    ) -> miette::Result<()> {
+        match self {
+            EmitBackend::RustyDilemma => emit_rustydilemma::emit(file, metadata, out),
+            EmitBackend::KeymapDrawer => emit_keymap_drawer::emit(file, metadata, out),
+            EmitBackend::Qmk => emit_qmk::emit(file, metadata, out),
+            EmitBackend::Zmk => emit_zmk::emit(file, metadata, out),
+            EmitBackend::Kanata => emit_kanata::emit(file, metadata, out),
+            EmitBackend::Kmk => emit_kmk::emit(file, metadata, out),
+            EmitBackend::Keyberon => {
+                emit_keyberon::emit(file, metadata, &emit_keyberon::KEYBERON, out)
+            }
+            EmitBackend::Rmk => emit_rmk::emit(file, metadata, out),
+            EmitBackend::Vial => emit_vial::emit(file, metadata, out),
+            EmitBackend::Svg => emit_svg::emit(file, metadata, out),
+            EmitBackend::Json => emit_json::emit(file, metadata, out),
+        }
+    }
+}
+
+/// Check the layout for problems without writing anything, first with what
+/// every backend knows and then with each backend's own key tables
+#[derive(clap::Args, Debug)]
+struct Check {
+    /// Which backends to check with, by default all of them
+    #[arg(short, long, value_enum)]
+    backend: Vec<EmitBackend>,
+
+    #[arg(from_global)]
+    file: PathBuf,
+}
+
+impl Check {
+    fn run(self) -> miette::Result<()> {
+        let sources = Sources::load(&self.file)?;
+
+        self.check(&sources)
+            .map_err(|e| e.with_source_code(sources.clone()))
+    }
+
+    fn check(&self, sources: &Sources) -> miette::Result<()> {
+        let r = sources.parse()?.merged;
+
+        let mut errors = Errors::default();
+        let metadata = errors.take(Metadata::process(&r));
+        errors.take(check::check(&r));
+
+        // anything wrong so far would only be repeated by each backend
+        let Some(metadata) = metadata.filter(|_| errors.is_empty()) else {
+            return errors.finish();
+        };
+
+        let backends = if self.backend.is_empty() {
+            EmitBackend::value_variants().to_vec()
+        } else {
+            self.backend.clone()
+        };
+
+        for backend in backends {
+            let Err(e) = backend.emit(&r, &metadata, &mut std::io::sink()) else {
+                continue;
+            };
+
+            // options a backend needs aren't a problem with the layout itself
+            let mut failures = Errors::default();
+            failures.push(e);
+            failures.retain(|e| {
+                !matches!(
+                    e.downcast_ref::<AppError>(),
+                    Some(
+                        AppError::OptionRequired { .. }
+                            | AppError::KeyNeedsOption { .. }
+                            | AppError::LayerSwitchActionRequired { .. }
+                    )
+                )
+            });
+
+            if !failures.is_empty() {
+                let name = backend.to_possible_value().unwrap().get_name().to_owned();
+                errors.push(BackendFailed::new(name, failures));
+            }
+        }
+
+        errors.finish()
+    }
 }
 
 /// Format the layout definition
@@ -107,32 +228,178 @@ struct Format {
 
 impl Format {
     fn run(&self) -> miette::Result<()> {
-        let source = std::fs::read_to_string(&self.file).map_err(AppError::IOError)?;
-        let r = match parse::file().parse(&source).into_result() {
-            Ok(r) => r,
-            Err(e) => {
-                for m in e {
-                    let e = miette::Error::new(parse::convert_error(m));
-                    return Err(e);
-                }
-                return Ok(());
-            }
-        };
+        let sources = Sources::load(&self.file)?;
 
-        let metadata = Metadata::process(&r)?;
+        self.format(&sources)
+            .map_err(|e| e.with_source_code(sources.clone()))
+    }
+
+    fn format(&self, sources: &Sources) -> miette::Result<()> {
+        let parsed = sources.parse()?;
+
+        // included files contribute to the layout and column widths, but only
+        // the file we were given gets formatted
+        let metadata = Metadata::process(&parsed.merged)?;
 
         if self.inplace {
             let mut output = std::fs::File::create(&self.file).map_err(AppError::IOError)?;
-            format::format(&r, &metadata, &mut output);
+            format::format(&parsed.root, &metadata, &mut output);
         } else {
             let mut output = self.output.create().map_err(AppError::IOError)?;
-            format::format(&r, &metadata, &mut output);
+            format::format(&parsed.root, &metadata, &mut output);
         }
 
         Ok(())
     }
 }
 
+/// Print every layer of the layout to the terminal
+#[derive(clap::Args, Debug)]
+struct Show {
+    /// Only use ASCII characters to draw the keys
+    #[arg(short, long)]
+    ascii: bool,
+
+    #[arg(from_global)]
+    file: PathBuf,
+
+    #[arg(from_global)]
+    output: OutputArg,
+}
+
+impl Show {
+    fn run(self) -> miette::Result<()> {
+        let sources = Sources::load(&self.file)?;
+
+        self.show(&sources)
+            .map_err(|e| e.with_source_code(sources.clone()))
+    }
+
+    fn show(&self, sources: &Sources) -> miette::Result<()> {
+        let r = sources.parse()?.merged;
+
+        let metadata = Metadata::process(&r)?;
+
+        let mut output = self.output.create().map_err(AppError::IOError)?;
+        show::show(&r, &metadata, self.ascii, &mut output)
+    }
+}
+
+/// Convert a layout from another tool's format into a layout file
+#[derive(clap::Args, Debug)]
+struct Import {
+    #[command(subcommand)]
+    from: ImportFrom,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ImportFrom {
+    /// Read a QMK keymap.json
+    Qmk(ImportArgs),
+    /// Read a ZMK devicetree .keymap
+    Zmk(ImportArgs),
+    /// Read a keymap-drawer YAML file
+    KeymapDrawer(ImportArgs),
+    /// Make a `layout` block from a QMK info.json or keyboard-layout-editor
+    /// JSON with `row,column` legends
+    Layout(ImportLayout),
+}
+
+#[derive(clap::Args, Debug)]
+struct ImportArgs {
+    /// A layout file to take the `layout` block from, rather than putting
+    /// keys in rows of `--columns`
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    layout: Option<PathBuf>,
+
+    /// How many keys to put in each row when there's no `--layout`
+    #[arg(
+        long,
+        default_value_t = 10,
+        conflicts_with = "layout",
+        value_parser = clap::value_parser!(u8).range(1..)
+    )]
+    columns: u8,
+
+    #[arg(from_global)]
+    file: PathBuf,
+
+    #[arg(from_global)]
+    output: OutputArg,
+}
+
+#[derive(clap::Args, Debug)]
+struct ImportLayout {
+    /// Which of the layouts of an info.json to use, which can be left out
+    /// when it only has one
+    #[arg(long)]
+    name: Option<String>,
+
+    #[arg(from_global)]
+    file: PathBuf,
+
+    #[arg(from_global)]
+    output: OutputArg,
+}
+
+impl ImportLayout {
+    fn run(&self) -> miette::Result<()> {
+        let strings = Arena::new();
+        let builder = import::Builder::new(&strings);
+
+        let file = import_layout::import(&builder, &self.file, self.name.as_deref())?;
+
+        let mut output = self.output.create().map_err(AppError::IOError)?;
+        import::write(&file, &mut output)
+    }
+}
+
+impl Import {
+    fn run(self) -> miette::Result<()> {
+        let args = match &self.from {
+            ImportFrom::Qmk(args) | ImportFrom::Zmk(args) | ImportFrom::KeymapDrawer(args) => args,
+            ImportFrom::Layout(layout) => return layout.run(),
+        };
+
+        let Some(layout) = &args.layout else {
+            return self.import(args, None);
+        };
+        let sources = Sources::load(layout)?;
+
+        self.import(args, Some(&sources))
+            .map_err(|e| e.with_source_code(sources.clone()))
+    }
+
+    fn import(&self, args: &ImportArgs, sources: Option<&Sources>) -> miette::Result<()> {
+        let layout = match sources {
+            Some(sources) => {
+                let merged = sources.parse()?.merged;
+                let layout = merged.layout.ok_or_else(|| AppError::MissingLayout {
+                    span: merged.span.start_singleton(),
+                })?;
+
+                Some(layout)
+            }
+            None => None,
+        };
+
+        let strings = Arena::new();
+        let builder = import::Builder::new(&strings);
+
+        let file = match &self.from {
+            ImportFrom::Qmk(_) => import_qmk::import(&builder, &args.file, layout, args.columns)?,
+            ImportFrom::Zmk(_) => import_zmk::import(&builder, &args.file, layout, args.columns)?,
+            ImportFrom::KeymapDrawer(_) => {
+                import_keymap_drawer::import(&builder, &args.file, layout, args.columns)?
+            }
+            ImportFrom::Layout(_) => unreachable!("layouts are imported on their own"),
+        };
+
+        let mut output = args.output.create().map_err(AppError::IOError)?;
+        import::write(&file, &mut output)
+    }
+}
+
 /// Generate completions for your shell
 #[derive(clap::Args, Debug)]
 struct GenCompletions {
@@ -157,7 +424,8 @@ fn main() -> miette::Result<()> {
         )
     }))?;
 
-    let r = match args.command {
+    match args.command {
+        Command::Check(cmd) => cmd.run(),
         Command::Emit(cmd) => cmd.run(),
         Command::Format(cmd) => cmd.run(),
         Command::GenCompletions(cmd) => {
@@ -185,19 +453,7 @@ fn main() -> miette::Result<()> {
 
             Ok(())
         }
-    };
-
-    if let Err(e) = r {
-        if let Some((name, source)) = args
-            .file
-            .as_ref()
-            .and_then(|name| Some((name, std::fs::read_to_string(name).ok()?)))
-        {
-            return Err(e.with_source_code(NamedSource::new(name.to_string_lossy(), source)));
-        }
-
-        return Err(e);
+        Command::Import(cmd) => cmd.run(),
+        Command::Show(cmd) => cmd.run(),
     }
-
-    Ok(())
 }
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 
 use chumsky::{
     combinator::{Map, ToSpan},
+    input::WithContext,
     label::Labelled,
     prelude::*,
     primitive::Just,
@@ -11,11 +12,20 @@ use itertools::Itertools;
 use thiserror::Error;
 
 use crate::syntax::{
-    Chord, CustomKey, CustomKeyOutput, File, Ident, Key, KeyOrChord, Layer, LayerRow, Layout,
-    LayoutDefn, LayoutRow, ModTapTimeout, ModTapType, Options, OptionsFor, OptionsItem, PlainKey,
-    Span, Text, Token,
+    Chord, Combo, ComboPosition, Comment, CustomKey, CustomKeyOutput, File, Ident, Include, Key,
+    KeyOrChord, Layer, LayerExtends, LayerRow, LayerSwitchKind, Layout, LayoutDefn, LayoutRow,
+    ModTapTimeout, ModTapType, Modifier, Options, OptionsFor, OptionsItem, PlainKey, Span, Text,
+    Token, Trivia,
 };
 
+/// Spans produced while parsing, the context is the offset of the file being
+/// parsed within [`crate::sources::Sources`]
+pub type ParseSpan = SimpleSpan<usize, usize>;
+
+pub type ParserInput<'a> = WithContext<ParseSpan, &'a str>;
+
+type Extra<'a> = extra::Err<Rich<'a, char, ParseSpan>>;
+
 trait HasMapWithSpan<'a, I, O, E>
 where
     I: chumsky::input::Input<'a>,
@@ -35,56 +45,112 @@ where
     }
 }
 
-pub fn file<'a>() -> impl Parser<'a, &'a str, File<'a>, extra::Err<Rich<'a, char>>> {
+pub fn file<'a>() -> impl Parser<'a, ParserInput<'a>, File<'a>, Extra<'a>> {
     group((
-        layout(),
-        options().padded().repeated().collect(),
-        custom_key().padded().repeated().collect(),
-        layer().padded().repeated().collect(),
+        include().repeated().collect(),
+        layout().or_not(),
+        options().repeated().collect(),
+        custom_key().repeated().collect(),
+        layer().repeated().collect(),
+        trivia(),
     ))
-    .map_with_span(|(layout, options, custom_keys, layers), span| File {
-        layout,
-        options,
-        custom_keys,
-        layers,
-        span: span.into(),
-    })
+    .map_with_span(
+        |(includes, layout, options, custom_keys, layers, trailing), span| File {
+            includes,
+            layout,
+            options,
+            custom_keys,
+            layers,
+            trailing,
+            span: span.into(),
+        },
+    )
 }
 
-pub fn layout<'a>() -> impl Parser<'a, &'a str, Layout, extra::Err<Rich<'a, char>>> {
-    token::<"layout">()
-        .padded()
-        .then(token::<"{">().padded())
-        .then(layout_row().padded().repeated().collect())
-        .then(token::<"}">().padded())
-        .map_with_span(
-            |(((layout_token, left_curly), rows), right_curly), span| Layout {
-                layout_token,
-                left_curly,
-                rows,
-                right_curly,
-                span: span.into(),
-            },
-        )
+pub fn include<'a>() -> impl Parser<'a, ParserInput<'a>, Include<'a>, Extra<'a>> {
+    let include = group((
+        token::<"include">(),
+        trivia(),
+        text(),
+        trivia(),
+        token::<";">(),
+    ))
+    .map_with_span(
+        |(include_token, after_include, path, after_path, semi), span| Include {
+            leading: Trivia::default(),
+            include_token,
+            after_include,
+            path,
+            after_path,
+            semi,
+            span: span.into(),
+        },
+    );
+
+    trivia()
+        .then(include)
+        .map(|(leading, include)| Include { leading, ..include })
+        .labelled("include")
+}
+
+pub fn layout<'a>() -> impl Parser<'a, ParserInput<'a>, Layout<'a>, Extra<'a>> {
+    let layout = group((
+        token::<"layout">(),
+        trivia(),
+        token::<"{">(),
+        layout_row().repeated().collect(),
+        trivia(),
+        token::<"}">(),
+    ))
+    .map_with_span(
+        |(layout_token, after_layout, left_curly, rows, trailing, right_curly), span| Layout {
+            leading: Trivia::default(),
+            layout_token,
+            after_layout,
+            left_curly,
+            rows,
+            trailing,
+            right_curly,
+            span: span.into(),
+        },
+    );
+
+    trivia()
+        .then(layout)
+        .map(|(leading, layout)| Layout { leading, ..layout })
 }
 
-fn layout_row<'a>() -> impl Parser<'a, &'a str, LayoutRow, extra::Err<Rich<'a, char>>> {
-    layout_defn()
-        .padded()
+fn layout_row<'a>() -> impl Parser<'a, ParserInput<'a>, LayoutRow<'a>, Extra<'a>> {
+    let row = layout_defn()
+        .then(trivia())
         .repeated()
         .at_least(1)
-        .collect()
+        .collect::<Vec<_>>()
         .then(token::<";">())
-        .padded()
-        .map_with_span(|(items, semi), span| LayoutRow {
-            items,
-            semi,
-            span: span.into(),
+        .map_with_span(|(items, semi), span| {
+            let (items, after_items) = items.into_iter().unzip();
+
+            LayoutRow {
+                leading: Trivia::default(),
+                before_items: Trivia::default(),
+                items,
+                after_items,
+                semi,
+                span: span.into(),
+            }
+        });
+
+    row_leading()
+        .then(row)
+        .map(|((leading, before_items), row)| LayoutRow {
+            leading,
+            before_items,
+            ..row
         })
         .labelled("layout row")
 }
 
-pub fn layout_defn<'a>() -> impl Parser<'a, &'a str, LayoutDefn, extra::Err<Rich<'a, char>>> {
+pub fn layout_defn<'a>() -> impl Parser<'a, ParserInput<'a>, LayoutDefn, Extra<'a>> {
     let i = int(10).try_map(|s: &str, span| s.parse().map_err(|e| Rich::custom(span, e)));
 
     let k = i
@@ -115,135 +181,325 @@ pub fn layout_defn<'a>() -> impl Parser<'a, &'a str, LayoutDefn, extra::Err<Rich
     k.or(s).or(remapped)
 }
 
-pub fn options<'a>() -> impl Parser<'a, &'a str, Options<'a>, extra::Err<Rich<'a, char>>> {
-    group((
-        token::<"options">().padded(),
-        options_for().padded(),
-        token::<"{">().padded(),
-        options_item().padded().repeated().collect(),
-        token::<"}">().padded(),
+pub fn options<'a>() -> impl Parser<'a, ParserInput<'a>, Options<'a>, Extra<'a>> {
+    let options = group((
+        token::<"options">(),
+        trivia(),
+        options_for(),
+        trivia(),
+        token::<"{">(),
+        options_item().repeated().collect(),
+        trivia(),
+        token::<"}">(),
     ))
     .map_with_span(
-        |(options_token, for_, left_curly, items, right_curly), span| Options {
+        |(
+            options_token,
+            after_options,
+            for_,
+            after_for,
+            left_curly,
+            items,
+            trailing,
+            right_curly,
+        ),
+         span| Options {
+            leading: Trivia::default(),
             options_token,
+            after_options,
             for_,
+            after_for,
             left_curly,
             items,
+            trailing,
             right_curly,
             span: span.into(),
         },
-    )
+    );
+
+    trivia()
+        .then(options)
+        .map(|(leading, options)| Options { leading, ..options })
 }
-pub fn options_for<'a>() -> impl Parser<'a, &'a str, OptionsFor, extra::Err<Rich<'a, char>>> {
+pub fn options_for<'a>() -> impl Parser<'a, ParserInput<'a>, OptionsFor, Extra<'a>> {
     choice((
         token::<"rusty_dilemma">().map(OptionsFor::RustyDilemma),
         token::<"keymap_drawer">().map(OptionsFor::KeymapDrawer),
+        token::<"qmk">().map(OptionsFor::Qmk),
+        token::<"zmk">().map(OptionsFor::Zmk),
+        token::<"kanata">().map(OptionsFor::Kanata),
+        token::<"kmk">().map(OptionsFor::Kmk),
+        token::<"keyberon">().map(OptionsFor::Keyberon),
+        token::<"rmk">().map(OptionsFor::Rmk),
+        token::<"vial">().map(OptionsFor::Vial),
         token::<"formatter">().map(OptionsFor::Formatter),
     ))
 }
 
-pub fn options_item<'a>() -> impl Parser<'a, &'a str, OptionsItem<'a>, extra::Err<Rich<'a, char>>> {
-    group((
-        ident().padded(),
-        token::<":">().padded(),
-        text().padded(),
-        token::<";">().padded(),
+pub fn options_item<'a>() -> impl Parser<'a, ParserInput<'a>, OptionsItem<'a>, Extra<'a>> {
+    let item = group((
+        ident(),
+        trivia(),
+        token::<":">(),
+        trivia(),
+        text(),
+        trivia(),
+        token::<";">(),
     ))
-    .map_with_span(|(name, colon, value, semi), span| OptionsItem {
-        name,
-        colon,
-        value,
-        semi,
-        span: span.into(),
-    })
-    .labelled("custom key output")
+    .map_with_span(
+        |(name, after_name, colon, after_colon, value, after_value, semi), span| OptionsItem {
+            leading: Trivia::default(),
+            name,
+            after_name,
+            colon,
+            after_colon,
+            value,
+            after_value,
+            semi,
+            span: span.into(),
+        },
+    );
+
+    trivia()
+        .then(item)
+        .map(|(leading, item)| OptionsItem { leading, ..item })
+        .labelled("custom key output")
 }
 
-pub fn custom_key<'a>() -> impl Parser<'a, &'a str, CustomKey<'a>, extra::Err<Rich<'a, char>>> {
-    group((
-        token::<"key">().padded(),
-        ident().padded(),
-        token::<"{">().padded(),
-        custom_key_output().padded().repeated().collect(),
-        token::<"}">().padded(),
+pub fn custom_key<'a>() -> impl Parser<'a, ParserInput<'a>, CustomKey<'a>, Extra<'a>> {
+    let key = group((
+        token::<"key">(),
+        trivia(),
+        ident(),
+        trivia(),
+        token::<"{">(),
+        custom_key_output().repeated().collect(),
+        trivia(),
+        token::<"}">(),
     ))
     .map_with_span(
-        |(key_token, name, left_curly, outputs, right_curly), span| CustomKey {
+        |(key_token, after_key, name, after_name, left_curly, outputs, trailing, right_curly),
+         span| CustomKey {
+            leading: Trivia::default(),
             key_token,
+            after_key,
             name,
+            after_name,
             left_curly,
             outputs,
+            trailing,
             right_curly,
             span: span.into(),
         },
-    )
+    );
+
+    trivia()
+        .then(key)
+        .map(|(leading, key)| CustomKey { leading, ..key })
 }
 
-pub fn custom_key_output<'a>(
-) -> impl Parser<'a, &'a str, CustomKeyOutput<'a>, extra::Err<Rich<'a, char>>> {
-    group((
-        token::<"out">().padded(),
-        ident().padded(),
-        token::<":">().padded(),
-        text().padded(),
-        token::<";">().padded(),
+pub fn custom_key_output<'a>() -> impl Parser<'a, ParserInput<'a>, CustomKeyOutput<'a>, Extra<'a>> {
+    let output = group((
+        token::<"out">(),
+        trivia(),
+        ident(),
+        trivia(),
+        token::<":">(),
+        trivia(),
+        text(),
+        trivia(),
+        token::<";">(),
     ))
     .map_with_span(
-        |(out_token, name, colon, output, semi), span| CustomKeyOutput {
+        |(
+            out_token,
+            after_out,
+            name,
+            after_name,
+            colon,
+            after_colon,
+            output,
+            after_output,
+            semi,
+        ),
+         span| CustomKeyOutput {
+            leading: Trivia::default(),
             out_token,
+            after_out,
             name,
+            after_name,
             colon,
+            after_colon,
             output,
+            after_output,
             semi,
             span: span.into(),
         },
-    )
-    .labelled("custom key output")
+    );
+
+    trivia()
+        .then(output)
+        .map(|(leading, output)| CustomKeyOutput { leading, ..output })
+        .labelled("custom key output")
 }
 
-pub fn layer<'a>() -> impl Parser<'a, &'a str, Layer<'a>, extra::Err<Rich<'a, char>>> {
-    token::<"layer">()
-        .padded()
-        .then(ident().padded())
-        .then(token::<"{">().padded())
-        .then(layer_row().padded().repeated().collect())
-        .then(token::<"}">().padded())
-        .map_with_span(
-            |((((layer_token, name), left_curly), rows), right_curly), span| Layer {
+pub fn layer<'a>() -> impl Parser<'a, ParserInput<'a>, Layer<'a>, Extra<'a>> {
+    let layer = group((
+        token::<"layer">(),
+        trivia(),
+        ident(),
+        trivia(),
+        layer_extends().or_not(),
+        token::<"{">(),
+        layer_row().repeated().collect(),
+        combo().repeated().collect(),
+        trivia(),
+        token::<"}">(),
+    ))
+    .map_with_span(
+        |(
+            layer_token,
+            after_layer,
+            name,
+            after_name,
+            extends,
+            left_curly,
+            rows,
+            combos,
+            trailing,
+            right_curly,
+        ),
+         span| {
+            Layer {
+                leading: Trivia::default(),
                 layer_token,
+                after_layer,
                 name,
+                after_name,
+                extends,
                 left_curly,
                 rows,
+                combos,
+                trailing,
                 right_curly,
                 span: span.into(),
+            }
+        },
+    );
+
+    trivia()
+        .then(layer)
+        .map(|(leading, layer)| Layer { leading, ..layer })
+        .labelled("layer")
+}
+
+/// `extends parent`, along with the trivia after it
+fn layer_extends<'a>() -> impl Parser<'a, ParserInput<'a>, LayerExtends<'a>, Extra<'a>> {
+    group((token::<"extends">(), trivia(), ident()))
+        .map_with_span(
+            |(extends_token, after_extends, parent), span| LayerExtends {
+                extends_token,
+                after_extends,
+                parent,
+                after_parent: Trivia::default(),
+                span: span.into(),
             },
         )
-        .labelled("layer")
+        .then(trivia())
+        .map(|(extends, after_parent)| LayerExtends {
+            after_parent,
+            ..extends
+        })
 }
 
-fn layer_row<'a>() -> impl Parser<'a, &'a str, LayerRow<'a>, extra::Err<Rich<'a, char>>> {
-    key_or_chord()
-        .padded()
+fn layer_row<'a>() -> impl Parser<'a, ParserInput<'a>, LayerRow<'a>, Extra<'a>> {
+    let row = key_or_chord()
+        .then(trivia())
         .repeated()
         .at_least(1)
-        .collect()
+        .collect::<Vec<_>>()
         .then(token::<";">())
-        .padded()
-        .map_with_span(|(items, semi), span| LayerRow {
-            items,
-            semi,
-            span: span.into(),
+        .map_with_span(|(items, semi), span| {
+            let (items, after_items) = items.into_iter().unzip();
+
+            LayerRow {
+                leading: Trivia::default(),
+                before_items: Trivia::default(),
+                items,
+                after_items,
+                semi,
+                span: span.into(),
+            }
+        });
+
+    row_leading()
+        .then(row)
+        .map(|((leading, before_items), row)| LayerRow {
+            leading,
+            before_items,
+            ..row
         })
         .labelled("row")
 }
 
-fn key_or_chord<'a>() -> impl Parser<'a, &'a str, KeyOrChord<'a>, extra::Err<Rich<'a, char>>> {
+fn combo<'a>() -> impl Parser<'a, ParserInput<'a>, Combo<'a>, Extra<'a>> {
+    let int = || int(10).try_map(|s: &str, span| s.parse().map_err(|e| Rich::custom(span, e)));
+    let position = group((int(), token::<":">(), int())).map_with_span(
+        |(row, colon, column), span: ParseSpan| ComboPosition {
+            row,
+            colon,
+            column,
+            span: span.into(),
+        },
+    );
+
+    let combo = group((
+        token::<"combo">(),
+        trivia(),
+        position
+            .then(trivia())
+            .repeated()
+            .at_least(2)
+            .collect::<Vec<_>>(),
+        token::<"=>">(),
+        trivia(),
+        key(),
+        trivia(),
+        token::<";">(),
+    ))
+    .map_with_span(
+        |(combo_token, after_combo, positions, arrow, after_arrow, key, after_key, semi), span| {
+            let (positions, after_positions) = positions.into_iter().unzip();
+
+            Combo {
+                leading: Trivia::default(),
+                combo_token,
+                after_combo,
+                positions,
+                after_positions,
+                arrow,
+                after_arrow,
+                key,
+                after_key,
+                semi,
+                span: span.into(),
+            }
+        },
+    );
+
+    trivia()
+        .then(combo)
+        .map(|(leading, combo)| Combo { leading, ..combo })
+        .labelled("combo")
+}
+
+fn key_or_chord<'a>() -> impl Parser<'a, ParserInput<'a>, KeyOrChord<'a>, Extra<'a>> {
     key()
         .map(KeyOrChord::Key)
         .or(chord().map(KeyOrChord::Chord))
 }
 
-fn chord<'a>() -> impl Parser<'a, &'a str, Chord<'a>, extra::Err<Rich<'a, char>>> {
+fn chord<'a>() -> impl Parser<'a, ParserInput<'a>, Chord<'a>, Extra<'a>> {
     token::<">">()
         .then(key())
         .then(token::<"<">())
@@ -256,7 +512,7 @@ fn chord<'a>() -> impl Parser<'a, &'a str, Chord<'a>, extra::Err<Rich<'a, char>>
         .labelled("chord")
 }
 
-fn key<'a>() -> impl Parser<'a, &'a str, Key<'a>, extra::Err<Rich<'a, char>>> {
+fn key<'a>() -> impl Parser<'a, ParserInput<'a>, Key<'a>, Extra<'a>> {
     let i = int(10).try_map(|s: &str, span| s.parse().map_err(|e| Rich::custom(span, e)));
     let p = plainkey().map(Key::Plain);
     let mt = plainkey()
@@ -288,11 +544,12 @@ fn key<'a>() -> impl Parser<'a, &'a str, Key<'a>, extra::Err<Rich<'a, char>>> {
             span: span.into(),
         });
 
-    mt.or(p).labelled("key")
+    let inherit = token::<".">().map(Key::Inherit);
+
+    mt.or(p).or(inherit).labelled("key")
 }
 
-fn plainkey<'a>() -> impl Parser<'a, &'a str, PlainKey<'a>, extra::Err<Rich<'a, char>>> {
-    let i = ident().map(PlainKey::Named);
+fn plainkey<'a>() -> impl Parser<'a, ParserInput<'a>, PlainKey<'a>, Extra<'a>> {
     let l = token::<"[">()
         .then(ident())
         .then(token::<"]">())
@@ -304,33 +561,83 @@ fn plainkey<'a>() -> impl Parser<'a, &'a str, PlainKey<'a>, extra::Err<Rich<'a,
                 span: span.into(),
             },
         );
-    let c = group((token::<"'">(), any(), token::<"'">())).map_with_span(
-        |(left_quote, c, right_quote), span: SimpleSpan| PlainKey::Char {
+    let kind = choice((
+        token::<"toggle">().map(LayerSwitchKind::Toggle),
+        token::<"oneshot">().map(LayerSwitchKind::OneShot),
+        token::<"default">().map(LayerSwitchKind::Default),
+    ));
+    let s = group((kind, token::<"(">(), ident(), token::<")">())).map_with_span(
+        |(kind, left_paren, layer, right_paren), span: ParseSpan| PlainKey::LayerSwitch {
+            kind,
+            left_paren,
+            layer,
+            right_paren,
+            span: span.into(),
+        },
+    );
+    let modifier = choice((
+        token::<"C-">().map(Modifier::Ctrl),
+        token::<"S-">().map(Modifier::Shift),
+        token::<"A-">().map(Modifier::Alt),
+        token::<"G-">().map(Modifier::Gui),
+    ));
+    let m = modifier
+        .repeated()
+        .at_least(1)
+        .collect()
+        .then(named_key().or(char_key()))
+        .map_with_span(|(modifiers, key), span: ParseSpan| PlainKey::Modified {
+            modifiers,
+            key: Box::new(key),
+            span: span.into(),
+        });
+
+    // modifiers and layer switches go first, as they start out looking like
+    // a named key
+    m.or(s)
+        .or(named_key())
+        .or(l)
+        .or(char_key())
+        .labelled("plain key")
+}
+
+fn named_key<'a>() -> impl Parser<'a, ParserInput<'a>, PlainKey<'a>, Extra<'a>> {
+    // `_` would otherwise be a valid key name
+    ident().map(|i| {
+        if i.s == "_" {
+            PlainKey::Trans(Token(i.span))
+        } else {
+            PlainKey::Named(i)
+        }
+    })
+}
+
+fn char_key<'a>() -> impl Parser<'a, ParserInput<'a>, PlainKey<'a>, Extra<'a>> {
+    group((token::<"'">(), any(), token::<"'">())).map_with_span(
+        |(left_quote, c, right_quote), span: ParseSpan| PlainKey::Char {
             left_quote,
             c,
             right_quote,
             span: span.into(),
         },
-    );
-
-    i.or(l).or(c).labelled("plain key")
+    )
 }
 
 fn token<'a, const T: &'static str>() -> Labelled<
     Map<
-        ToSpan<Just<&'static str, &'a str, extra::Err<Rich<'a, char>>>, &'static str>,
-        SimpleSpan,
-        fn(SimpleSpan) -> Token<T>,
+        ToSpan<Just<&'static str, ParserInput<'a>, Extra<'a>>, &'static str>,
+        ParseSpan,
+        fn(ParseSpan) -> Token<T>,
     >,
     &'static str,
 > {
     just(T)
         .to_span()
-        .map((|s: SimpleSpan| Token(s.into())) as fn(_) -> _)
+        .map((|s: ParseSpan| Token(s.into())) as fn(_) -> _)
         .labelled(T)
 }
 
-fn ident<'a>() -> impl Parser<'a, &'a str, Ident<'a>, extra::Err<Rich<'a, char>>> {
+fn ident<'a>() -> impl Parser<'a, ParserInput<'a>, Ident<'a>, Extra<'a>> {
     group((
         any()
             .filter(|c: &char| c.is_alphabetic() || "-_".contains(*c))
@@ -341,13 +648,68 @@ fn ident<'a>() -> impl Parser<'a, &'a str, Ident<'a>, extra::Err<Rich<'a, char>>
             .ignored(),
     ))
     .to_slice()
-    .map_with_span(|t, s: SimpleSpan| Ident {
+    .map_with_span(|t, s: ParseSpan| Ident {
         s: t,
         span: s.into(),
     })
 }
 
-fn text<'a>() -> impl Parser<'a, &'a str, Text<'a>, extra::Err<Rich<'a, char>>> {
+/// Whitespace and any comments within it
+fn trivia<'a>() -> impl Parser<'a, ParserInput<'a>, Trivia<'a>, Extra<'a>> {
+    comments()
+        .then_ignore(whitespace())
+        .map(|comments| Trivia { comments })
+}
+
+/// The comments ahead of a row, split into the ones above it and the block
+/// comments on the same line as it
+fn row_leading<'a>() -> impl Parser<'a, ParserInput<'a>, (Trivia<'a>, Trivia<'a>), Extra<'a>> {
+    comments()
+        .then(whitespace())
+        .map(|(mut comments, ws): (Vec<_>, &str)| {
+            let on_row = match comments.iter().rposition(|c| c.own_line) {
+                Some(idx) if !ws.contains('\n') => comments.split_off(idx),
+                _ => Vec::new(),
+            };
+
+            (Trivia { comments }, Trivia { comments: on_row })
+        })
+}
+
+// NOTE: `filter` and a bare `.repeated()` both mess with the furthest error
+// seen so far, so whitespace is skipped with `padded` (which doesn't report
+// errors at all) and the repetitions below go through `collect`, to keep
+// error messages pointing at the real problem
+fn whitespace<'a>() -> impl Parser<'a, ParserInput<'a>, &'a str, Extra<'a>> {
+    empty().padded().to_slice()
+}
+
+fn comments<'a>() -> impl Parser<'a, ParserInput<'a>, Vec<Comment<'a>>, Extra<'a>> {
+    let line = just("//")
+        .then(none_of('\n').repeated().collect::<()>())
+        .ignored();
+    let block = just("/*")
+        .then(any().and_is(just("*/").not()).repeated().collect::<()>())
+        .then(just("*/"))
+        .ignored();
+
+    let comment = line
+        .or(block)
+        .to_slice()
+        .map_with_span(|t: &str, s: ParseSpan| (t, s));
+
+    whitespace()
+        .then(comment)
+        .map_with_span(|(ws, (text, span)): (&str, _), outer: ParseSpan| Comment {
+            text,
+            own_line: ws.contains('\n') || outer.start == 0,
+            span: span.into(),
+        })
+        .repeated()
+        .collect()
+}
+
+fn text<'a>() -> impl Parser<'a, ParserInput<'a>, Text<'a>, Extra<'a>> {
     let escape = just('\\').then(choice((just('\\'), just('"')))).ignored();
 
     let escaped_string = none_of("\n\\\"")
@@ -409,20 +771,17 @@ pub enum ParseError {
         #[related]
         contexts: Vec<LabelNote>,
     },
-    // #[error("Multiple errors happened")]
-    // Multiple {
-    //     #[label]
-    //     err_span: miette::SourceSpan,
-
-    //     #[related]
-    //     contexts: Vec<LabelNote>,
+    #[error("Multiple errors happened")]
+    Multiple {
+        #[label(primary)]
+        err_span: Span,
 
-    //     #[related]
-    //     errors: Vec<Self>,
-    // },
+        #[related]
+        errors: Vec<Self>,
+    },
 }
 
-pub fn convert_error<'a>(err: Rich<'a, char>) -> ParseError {
+pub fn convert_error<'a>(err: Rich<'a, char, ParseSpan>) -> ParseError {
     let contexts = err
         .contexts()
         .map(|(l, span)| LabelNote {
@@ -431,29 +790,50 @@ pub fn convert_error<'a>(err: Rich<'a, char>) -> ParseError {
         })
         .collect::<Vec<_>>();
 
-    match err.reason() {
-        chumsky::error::RichReason::ExpectedFound { .. } => {
-            let expected = err.expected().map(|x| x.to_string()).join(", ");
-            let found = if let Some(m) = err.found() {
+    convert_reason((*err.span()).into(), err.reason(), contexts)
+}
+
+fn convert_reason(
+    err_span: Span,
+    reason: &chumsky::error::RichReason<'_, char>,
+    mut contexts: Vec<LabelNote>,
+) -> ParseError {
+    match reason {
+        chumsky::error::RichReason::ExpectedFound { expected, found } => {
+            let expected = expected.iter().map(|x| x.to_string()).join(", ");
+            let found = if let Some(m) = found.as_deref() {
                 format!("{:?}", m.to_string())
             } else {
                 "EOF".to_string()
             };
 
             ParseError::UnexpectedInput {
-                err_span: err.span().into(),
+                err_span,
                 expected_msg: format!("Expected: {expected}"),
                 found,
                 contexts,
             }
         }
         chumsky::error::RichReason::Custom(m) => ParseError::Custom {
-            err_span: err.span().into(),
+            err_span,
             custom: m.to_string(),
             contexts,
         },
-        chumsky::error::RichReason::Many(_o) => {
-            panic!("idk")
-        }
+        // the contexts apply to every reason, so only show them once
+        chumsky::error::RichReason::Many(reasons) => ParseError::Multiple {
+            err_span,
+            errors: reasons
+                .iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    let contexts = if i == 0 {
+                        std::mem::take(&mut contexts)
+                    } else {
+                        vec![]
+                    };
+                    convert_reason(err_span, r, contexts)
+                })
+                .collect(),
+        },
     }
 }
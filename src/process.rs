@@ -1,30 +1,49 @@
 use std::collections::{BTreeMap, HashMap};
 
+use itertools::Itertools;
 use locspan::Spanned;
+use ngrammatic::CorpusBuilder;
 
 use crate::{
-    errors::AppError,
-    syntax::{Chord, File, Key, KeyOrChord, Layer, Layout, LayoutDefn, Options, OptionsFor},
+    errors::{AppError, Errors},
+    serialize,
+    syntax::{
+        Chord, File, Ident, Key, KeyOrChord, Layer, LayerExtends, Layout, LayoutDefn, Options,
+        OptionsFor, PlainKey, Span,
+    },
 };
 
-#[derive(Debug, debug3::Debug, Clone, Copy)]
+#[derive(Debug, debug3::Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum KeyAt {
     Space,
 
     Located(MatrixPosition),
 }
 
-#[derive(Debug, debug3::Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(
+    Debug, debug3::Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, serde::Serialize,
+)]
 pub struct MatrixPosition(pub u8, pub u8);
 
-#[derive(Debug, debug3::Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(
+    Debug, debug3::Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
 pub enum OptionKey {
     RustyDilemma,
     KeymapDrawer,
+    Qmk,
+    Zmk,
+    Kanata,
+    Kmk,
+    Keyberon,
+    Rmk,
+    Vial,
     Formatter,
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
 pub struct Metadata<'a> {
     pub options: OptionsMeta<'a>,
     pub layout: LayoutMeta,
@@ -34,7 +53,13 @@ pub struct Metadata<'a> {
 impl<'a> Metadata<'a> {
     pub fn process(file: &'a File<'a>) -> miette::Result<Self> {
         let options = OptionsMeta::process(&file.options);
-        let layout = LayoutMeta::process(&file.layout)?;
+        let Some(layout) = &file.layout else {
+            return Err(AppError::MissingLayout {
+                span: file.span.start_singleton(),
+            }
+            .into());
+        };
+        let layout = LayoutMeta::process(layout)?;
         let layers = LayersMeta::process(&layout, &file.layers)?;
 
         Ok(Self {
@@ -49,8 +74,10 @@ impl<'a> Metadata<'a> {
     }
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
+#[serde(transparent)]
 pub struct OptionsMeta<'a> {
+    #[serde(serialize_with = "serialize::serialize_options")]
     pub options: HashMap<(OptionKey, &'a str), &'a str>,
 }
 
@@ -62,6 +89,13 @@ impl<'a> OptionsMeta<'a> {
             let for_ = match option.for_ {
                 OptionsFor::RustyDilemma(_) => OptionKey::RustyDilemma,
                 OptionsFor::KeymapDrawer(_) => OptionKey::KeymapDrawer,
+                OptionsFor::Qmk(_) => OptionKey::Qmk,
+                OptionsFor::Zmk(_) => OptionKey::Zmk,
+                OptionsFor::Kanata(_) => OptionKey::Kanata,
+                OptionsFor::Kmk(_) => OptionKey::Kmk,
+                OptionsFor::Keyberon(_) => OptionKey::Keyberon,
+                OptionsFor::Rmk(_) => OptionKey::Rmk,
+                OptionsFor::Vial(_) => OptionKey::Vial,
                 OptionsFor::Formatter(_) => OptionKey::Formatter,
             };
 
@@ -79,10 +113,13 @@ impl<'a> OptionsMeta<'a> {
     }
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
 pub struct LayoutMeta {
+    #[serde(serialize_with = "serialize::serialize_entries")]
     pub phys_to_matrix: BTreeMap<(u8, u8), KeyAt>,
+    #[serde(serialize_with = "serialize::serialize_entries")]
     pub layout_to_matrix: BTreeMap<(u8, u8), KeyAt>,
+    #[serde(serialize_with = "serialize::serialize_entries")]
     pub layout_to_phys: BTreeMap<(u8, u8), (u8, u8)>,
     pub width: u8,
     pub height: u8,
@@ -95,22 +132,37 @@ impl LayoutMeta {
         let mut layout_to_phys = BTreeMap::new();
         let mut matrix_to_key: BTreeMap<(u8, u8), &LayoutDefn> = BTreeMap::new();
         let mut width = None;
-        let height = layout.rows.len() as u8;
+        let mut errors = Errors::default();
+        let Ok(height) = u8::try_from(layout.rows.len()) else {
+            return Err(AppError::LayoutTooLarge {
+                span: layout.rows[usize::from(u8::MAX)].span(),
+            }
+            .into());
+        };
 
-        for (y, row) in layout.rows.iter().enumerate() {
-            let mut x = 0;
+        'rows: for (y, row) in layout.rows.iter().enumerate() {
+            let mut x: u8 = 0;
             let mut x_l = 0;
             for defn in &row.items {
+                let defn_width = match defn {
+                    LayoutDefn::Keys { count, .. } | LayoutDefn::Spaces { count, .. } => *count,
+                    LayoutDefn::RemappedKey { .. } => 1,
+                };
+
+                if x.checked_add(defn_width).is_none() {
+                    errors.push(AppError::LayoutTooLarge { span: defn.span() });
+                    continue 'rows;
+                }
+
                 match defn {
                     crate::syntax::LayoutDefn::Keys { count, k: _, span } => {
                         for n in 0..*count {
                             let pos = (x + n, y as u8);
                             if let Some(k) = matrix_to_key.insert(pos, defn) {
-                                return Err(AppError::OverlappingKeys {
+                                errors.push(AppError::OverlappingKeys {
                                     span: k.span(),
                                     other_span: *span,
-                                }
-                                .into());
+                                });
                             }
 
                             phys_to_matrix
@@ -134,11 +186,10 @@ impl LayoutMeta {
                         let matr_pos = (*position, y as u8);
 
                         if let Some(k) = matrix_to_key.insert(matr_pos, defn) {
-                            return Err(AppError::OverlappingKeys {
+                            errors.push(AppError::OverlappingKeys {
                                 span: k.span(),
                                 other_span: *span,
-                            }
-                            .into());
+                            });
                         }
 
                         phys_to_matrix.insert(
@@ -172,29 +223,36 @@ impl LayoutMeta {
 
             if let Some(expected_width) = width {
                 if expected_width != x {
-                    return Err(AppError::InconsistentMatrixWidth {
+                    errors.push(AppError::InconsistentMatrixWidth {
                         bad_row: row.span(),
                         got: x,
                         expected: expected_width,
-                    }
-                    .into());
+                    });
                 }
             } else {
                 width = Some(x);
             }
         }
 
-        Ok(LayoutMeta {
+        errors.finish_with(LayoutMeta {
             phys_to_matrix,
             layout_to_matrix,
             layout_to_phys,
-            width: width.unwrap(),
+            width: width.unwrap_or(0),
             height,
         })
     }
+
+    /// How many keys a row of layers should have
+    pub fn row_len(&self, y: u8) -> usize {
+        self.layout_to_phys
+            .keys()
+            .filter(|(_, y_)| *y_ == y)
+            .count()
+    }
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
 pub struct LayersMeta<'a> {
     pub layer_map: BTreeMap<String, usize>,
     pub layers: Vec<LayerMeta<'a>>,
@@ -204,39 +262,102 @@ impl<'a> LayersMeta<'a> {
     pub fn process(layout_meta: &LayoutMeta, layers: &[Layer<'a>]) -> miette::Result<Self> {
         let mut layer_map = BTreeMap::new();
         let mut processed_layers = Vec::new();
+        let mut errors = Errors::default();
 
         for layer in layers {
             layer_map.insert(layer.name.s.to_string(), layer_map.len());
         }
 
         for layer in layers {
-            processed_layers.push(LayerMeta::process(&layout_meta, &layer_map, layer)?);
+            let parent = match &layer.extends {
+                Some(extends) => {
+                    match Self::find_parent(&layer_map, layers, &processed_layers, extends) {
+                        Ok(parent) => Some(parent),
+                        Err(e) => {
+                            // keep the indices lined up with `layer_map`, the
+                            // layer's own keys would only add noise
+                            errors.push(e);
+                            processed_layers.push(LayerMeta::empty(layer));
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let processed = LayerMeta::process(
+                &layout_meta,
+                &layer_map,
+                &processed_layers,
+                parent,
+                layer,
+                &mut errors,
+            );
+            processed_layers.push(processed);
         }
 
-        Ok(LayersMeta {
+        errors.finish_with(LayersMeta {
             layer_map,
             layers: processed_layers,
         })
     }
+
+    fn find_parent<'l>(
+        layer_map: &BTreeMap<String, usize>,
+        layers: &[Layer<'a>],
+        processed_layers: &'l [LayerMeta<'a>],
+        extends: &LayerExtends<'a>,
+    ) -> miette::Result<&'l LayerMeta<'a>> {
+        let parent = &extends.parent;
+
+        let Some(&idx) = layer_map.get(parent.s) else {
+            return Err(unknown_layer(layer_map, parent).into());
+        };
+
+        // layers are processed in order, so only earlier ones are available
+        processed_layers.get(idx).ok_or_else(|| {
+            AppError::LayerExtendsLater {
+                span: extends.span,
+                parent_span: layers[idx].name.span,
+                parent: parent.s.to_string(),
+            }
+            .into()
+        })
+    }
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, Clone, serde::Serialize)]
 pub struct ResolvedChord<'a> {
+    #[serde(serialize_with = "serialize::serialize_key")]
+    pub key: Key<'a>,
+    /// Set for chords written between two keys of a row, rather than as a
+    /// `combo`
+    #[serde(skip)]
+    pub inline: Option<InlineChord<'a>>,
+    pub layout_positions: Vec<(u8, u8)>,
+    pub matrix_positions: Vec<MatrixPosition>,
+}
+
+#[derive(Debug, debug3::Debug, Clone)]
+pub struct InlineChord<'a> {
     pub chord: Chord<'a>,
     pub left_layout: (u8, u8),
-    pub left: MatrixPosition,
-    pub right: MatrixPosition,
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
 pub struct ResolvedKey<'a> {
+    #[serde(serialize_with = "serialize::serialize_key")]
     pub key: Key<'a>,
     pub layout_pos: (u8, u8),
     pub physical_pos: (u8, u8),
     pub matrix_pos: MatrixPosition,
+    /// What this key does once transparent keys are resolved through the
+    /// layers below it, `None` if it falls through all of them
+    #[serde(serialize_with = "serialize::serialize_optional_key")]
+    pub effective: Option<Key<'a>>,
 }
 
-#[derive(Debug, debug3::Debug)]
+#[derive(Debug, debug3::Debug, serde::Serialize)]
 pub struct LayerMeta<'a> {
     pub name: &'a str,
     pub chords: Vec<ResolvedChord<'a>>,
@@ -244,85 +365,405 @@ pub struct LayerMeta<'a> {
 }
 
 impl<'a> LayerMeta<'a> {
+    fn empty(layer: &Layer<'a>) -> Self {
+        Self {
+            name: layer.name.s,
+            chords: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Resolve a layer's keys and chords, anything wrong is added to `errors`
+    /// and left out of the result
     pub fn process(
         layout_meta: &LayoutMeta,
-        _layer_map: &BTreeMap<String, usize>,
+        layer_map: &BTreeMap<String, usize>,
+        lower_layers: &[LayerMeta<'a>],
+        parent: Option<&LayerMeta<'a>>,
         layer: &Layer<'a>,
-    ) -> miette::Result<Self> {
+        errors: &mut Errors,
+    ) -> Self {
         let mut keys = Vec::new();
         let mut chords = Vec::new();
 
-        for (y, row) in layer.rows.iter().enumerate() {
+        // layers extending another can leave off anything they don't change
+        let height = usize::from(layout_meta.height);
+        if layer.rows.len() > height || (parent.is_none() && layer.rows.len() < height) {
+            errors.push(AppError::WrongRowCount {
+                span: layer.name.span,
+                got: layer.rows.len(),
+                expected: height,
+            });
+        }
+
+        for (y, row) in layer.rows.iter().take(height).enumerate() {
             let y = y as u8;
             let mut x = 0;
             let mut last_item = None;
             let mut item_iter = row.items.iter().peekable();
 
+            let row_len = layout_meta.row_len(y);
+            let key_count = row
+                .items
+                .iter()
+                .filter(|i| matches!(i, KeyOrChord::Key(_)))
+                .count();
+            if key_count > row_len || (parent.is_none() && key_count < row_len) {
+                errors.push(AppError::WrongKeyCount {
+                    bad_row: row.span(),
+                    got: key_count,
+                    expected: row_len,
+                });
+            }
+
             while let Some(item) = item_iter.next() {
+                let prev_item = last_item.replace(item);
+
                 match item {
                     crate::syntax::KeyOrChord::Key(key) => {
-                        let Some(&physical_pos) = layout_meta.layout_to_phys.get(&(x, y)) else {
-                            return Err(AppError::ImpossibleKeyLocation { key: item.span() }.into());
+                        if usize::from(x) >= row_len {
+                            break;
+                        }
+
+                        let (Some(&physical_pos), Some(&KeyAt::Located(matrix_pos))) = (
+                            layout_meta.layout_to_phys.get(&(x, y)),
+                            layout_meta.layout_to_matrix.get(&(x, y)),
+                        ) else {
+                            errors.push(AppError::ImpossibleKeyLocation { key: item.span() });
+                            x += 1;
+                            continue;
                         };
-                        let KeyAt::Located(matrix_pos) =
-                            *layout_meta.layout_to_matrix.get(&(x, y)).unwrap()
-                        else {
-                            panic!("Huh");
+                        Self::check_layers(layer_map, key, errors);
+                        let key = if let Key::Inherit(_) = key {
+                            let inherited =
+                                parent.and_then(|p| p.keys.iter().find(|k| k.layout_pos == (x, y)));
+                            let Some(inherited) = inherited else {
+                                errors.push(AppError::NothingToInherit { span: key.span() });
+                                x += 1;
+                                continue;
+                            };
+                            inherited.key.clone()
+                        } else {
+                            key.clone()
+                        };
+                        let effective = if key.is_trans() {
+                            Self::resolve_trans(lower_layers, (x, y))
+                        } else {
+                            Some(key.clone())
                         };
                         let resolved_key = ResolvedKey {
-                            key: key.clone(),
+                            key,
                             layout_pos: (x, y),
                             physical_pos,
                             matrix_pos,
+                            effective,
                         };
                         keys.push(resolved_key);
                         x += 1;
                     }
                     crate::syntax::KeyOrChord::Chord(chord) => {
-                        if matches!(last_item, Some(&KeyOrChord::Key(_)))
+                        if matches!(prev_item, Some(&KeyOrChord::Key(_)))
                             && matches!(item_iter.peek(), Some(KeyOrChord::Key(_)))
                         {
-                            let Some(KeyAt::Located(left)) =
-                                layout_meta.layout_to_matrix.get(&(x - 1, y)).copied()
-                            else {
-                                return Err(AppError::ImpossibleKeyLocation { key: item.span() }.into());
-                            };
-                            let Some(KeyAt::Located(right)) =
-                                layout_meta.layout_to_matrix.get(&(x, y)).copied()
+                            let left = layout_meta.layout_to_matrix.get(&(x - 1, y)).copied();
+                            let right = layout_meta.layout_to_matrix.get(&(x, y)).copied();
+                            let (Some(KeyAt::Located(left)), Some(KeyAt::Located(right))) =
+                                (left, right)
                             else {
-                                return Err(AppError::ImpossibleKeyLocation { key: item.span() }.into());
+                                errors.push(AppError::ImpossibleKeyLocation { key: item.span() });
+                                continue;
                             };
 
                             let left_layout = (x - 1, y);
+                            let layout_positions = vec![left_layout, (x, y)];
+
+                            Self::check_layers(layer_map, &chord.key, errors);
+
+                            let Some(key) = errors.take(Self::chord_key(
+                                parent,
+                                &chord.key,
+                                &layout_positions,
+                                chord.span(),
+                            )) else {
+                                continue;
+                            };
 
                             chords.push(ResolvedChord {
-                                chord: chord.clone(),
-                                left_layout,
-                                left,
-                                right,
+                                key,
+                                inline: Some(InlineChord {
+                                    chord: chord.clone(),
+                                    left_layout,
+                                }),
+                                layout_positions,
+                                matrix_positions: vec![left, right],
                             });
                         } else {
                             let prev_item =
-                                last_item.map_or(row.span.start_singleton(), |c| c.span());
+                                prev_item.map_or(row.span.start_singleton(), |c| c.span());
                             let next_item = item_iter
                                 .peek()
                                 .map_or(row.semi.span().start_singleton(), |c| c.span());
 
-                            return Err(AppError::BadChordPositions {
+                            errors.push(AppError::BadChordPositions {
                                 bad_chord: chord.span(),
                                 prev_item,
                                 next_item,
-                            }
-                            .into());
+                            });
                         }
                     }
                 }
+            }
+        }
+
+        'combos: for combo in &layer.combos {
+            Self::check_layers(layer_map, &combo.key, errors);
+
+            let mut layout_positions = Vec::new();
+            let mut matrix_positions = Vec::new();
+
+            for (idx, position) in combo.positions.iter().enumerate() {
+                let layout_pos = (position.column, position.row);
+
+                // the same key twice could never be pressed
+                if let Some(other) = combo.positions[..idx]
+                    .iter()
+                    .find(|p| (p.column, p.row) == layout_pos)
+                {
+                    errors.push(AppError::RepeatedComboPosition {
+                        span: position.span(),
+                        other_span: other.span(),
+                    });
+                    continue 'combos;
+                }
+
+                let Some(KeyAt::Located(matrix_pos)) =
+                    layout_meta.layout_to_matrix.get(&layout_pos).copied()
+                else {
+                    errors.push(AppError::ImpossibleKeyLocation {
+                        key: position.span(),
+                    });
+                    continue 'combos;
+                };
+
+                layout_positions.push(layout_pos);
+                matrix_positions.push(matrix_pos);
+            }
+
+            if layout_positions.len() < 2 {
+                errors.push(AppError::ComboTooSmall { span: combo.span() });
+                continue;
+            }
+
+            let Some(key) = errors.take(Self::chord_key(
+                parent,
+                &combo.key,
+                &layout_positions,
+                combo.span(),
+            )) else {
+                continue;
+            };
+
+            chords.push(ResolvedChord {
+                key,
+                inline: None,
+                layout_positions,
+                matrix_positions,
+            });
+        }
+
+        // anything not mentioned at all is also inherited
+        if let Some(parent) = parent {
+            for key in &parent.keys {
+                if keys.iter().any(|k| k.layout_pos == key.layout_pos) {
+                    continue;
+                }
 
-                last_item = Some(item);
+                let effective = if key.key.is_trans() {
+                    Self::resolve_trans(lower_layers, key.layout_pos)
+                } else {
+                    Some(key.key.clone())
+                };
+
+                keys.push(ResolvedKey {
+                    key: key.key.clone(),
+                    layout_pos: key.layout_pos,
+                    physical_pos: key.physical_pos,
+                    matrix_pos: key.matrix_pos,
+                    effective,
+                });
             }
+
+            for chord in &parent.chords {
+                if Self::find_chord(&chords, &chord.layout_positions).is_none() {
+                    chords.push(chord.clone());
+                }
+            }
+
+            keys.sort_by_key(|k| (k.layout_pos.1, k.layout_pos.0));
+            chords.sort_by_key(|c| {
+                let (x, y) = c.layout_positions[0];
+                (y, x)
+            });
         }
 
         let name = layer.name.s;
-        Ok(Self { name, keys, chords })
+        Self { name, keys, chords }
+    }
+
+    /// Make sure every layer a key refers to exists
+    fn check_layers(layer_map: &BTreeMap<String, usize>, key: &Key<'a>, errors: &mut Errors) {
+        fn check_plain(
+            layer_map: &BTreeMap<String, usize>,
+            key: &PlainKey<'_>,
+            errors: &mut Errors,
+        ) {
+            match key {
+                PlainKey::Layer { layer, .. } | PlainKey::LayerSwitch { layer, .. } => {
+                    if !layer_map.contains_key(layer.s) {
+                        errors.push(unknown_layer(layer_map, layer));
+                    }
+                }
+                PlainKey::Modified { key, .. } => check_plain(layer_map, key, errors),
+                PlainKey::Named(_) | PlainKey::Trans(_) | PlainKey::Char { .. } => {}
+            }
+        }
+
+        match key {
+            Key::Plain(p) => check_plain(layer_map, p, errors),
+            Key::ModTap { tap, hold, .. } => {
+                check_plain(layer_map, tap, errors);
+                check_plain(layer_map, hold, errors);
+            }
+            Key::Inherit(_) => {}
+        }
+    }
+
+    /// The key for a chord, looking through the parent layer for `.`
+    fn chord_key(
+        parent: Option<&LayerMeta<'a>>,
+        key: &Key<'a>,
+        layout_positions: &[(u8, u8)],
+        span: Span,
+    ) -> miette::Result<Key<'a>> {
+        let Key::Inherit(_) = key else {
+            return Ok(key.clone());
+        };
+
+        parent
+            .and_then(|p| Self::find_chord(&p.chords, layout_positions))
+            .map(|c| c.key.clone())
+            .ok_or_else(|| AppError::NothingToInherit { span }.into())
+    }
+
+    /// Find the chord pressed by exactly the given keys, in any order
+    fn find_chord<'c>(
+        chords: &'c [ResolvedChord<'a>],
+        layout_positions: &[(u8, u8)],
+    ) -> Option<&'c ResolvedChord<'a>> {
+        let mut wanted = layout_positions.to_vec();
+        wanted.sort();
+
+        chords.iter().find(|c| {
+            let mut positions = c.layout_positions.clone();
+            positions.sort();
+            positions == wanted
+        })
+    }
+
+    /// Layers are stacked in the order they're declared, so a transparent key
+    /// takes on whatever the closest layer below has in the same position
+    fn resolve_trans(lower_layers: &[LayerMeta<'a>], layout_pos: (u8, u8)) -> Option<Key<'a>> {
+        lower_layers.iter().rev().find_map(|layer| {
+            layer
+                .keys
+                .iter()
+                .find(|k| k.layout_pos == layout_pos)
+                .and_then(|k| k.effective.clone())
+        })
+    }
+}
+
+/// The names out of `names` that look like `name`, for suggesting instead
+fn similar_names(names: impl IntoIterator<Item = impl AsRef<str>>, name: &str) -> String {
+    let mut possible_names = CorpusBuilder::new().case_insensitive().finish();
+
+    for name in names {
+        possible_names.add_text(name.as_ref());
+    }
+
+    possible_names
+        .search(name, 0.40)
+        .into_iter()
+        .map(|s| s.text)
+        .join(", ")
+}
+
+/// An error for a layer that doesn't exist, suggesting ones with similar names
+pub fn unknown_layer(layer_map: &BTreeMap<String, usize>, layer: &Ident<'_>) -> AppError {
+    AppError::UnknownNamedLayer {
+        span: layer.span,
+        layer: layer.s.to_string(),
+        similar: similar_names(layer_map.keys(), layer.s),
+    }
+}
+
+/// An error for a named key that isn't one of `named_keys`, suggesting ones
+/// with similar names
+pub fn unknown_named_key(
+    named_keys: impl IntoIterator<Item = impl AsRef<str>>,
+    name: &Ident<'_>,
+) -> AppError {
+    AppError::UnknownNamedKey {
+        span: name.span,
+        key: name.s.to_string(),
+        similar: similar_names(named_keys, name.s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{input::Input as _, Parser as _};
+
+    use super::*;
+    use crate::parse;
+
+    const TEXT: &str =
+        "layout {\n  2k;\n}\n\nlayer base {\n  'q' 'w';\n  combo 0:0 0:0 => esc;\n}\n";
+
+    fn parse(text: &str) -> File<'_> {
+        parse::file()
+            .parse(text.with_context(0))
+            .into_result()
+            .unwrap()
+    }
+
+    fn process_error(file: &File<'_>) -> AppError {
+        Metadata::process(file)
+            .unwrap_err()
+            .downcast::<AppError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn combos_cant_repeat_a_position() {
+        let AppError::RepeatedComboPosition { span, other_span } = process_error(&parse(TEXT))
+        else {
+            panic!("expected a repeated combo position");
+        };
+
+        let second = TEXT.rfind("0:0").unwrap();
+        assert_eq!(span.0.offset(), second);
+        assert_eq!(other_span.0.offset(), TEXT.find("0:0").unwrap());
+    }
+
+    #[test]
+    fn combos_need_two_keys() {
+        let mut file = parse(TEXT);
+        file.layers[0].combos[0].positions.truncate(1);
+
+        assert!(matches!(
+            process_error(&file),
+            AppError::ComboTooSmall { .. }
+        ));
     }
 }
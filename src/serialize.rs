@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    process::OptionKey,
+    syntax::{Key, LayerSwitchKind, ModTapType, Modifier, PlainKey},
+};
+
+/// Keys as they're written out, the syntax without any of its spans
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KeyJson<'k> {
+    Named {
+        name: &'k str,
+    },
+    Char {
+        char: char,
+    },
+    Trans,
+    Layer {
+        layer: &'k str,
+    },
+    LayerSwitch {
+        kind: &'static str,
+        layer: &'k str,
+    },
+    Modified {
+        modifiers: Vec<&'static str>,
+        key: Box<KeyJson<'k>>,
+    },
+    ModTap {
+        tap: Box<KeyJson<'k>>,
+        hold: Box<KeyJson<'k>>,
+        flavor: &'static str,
+        timeout: Option<u32>,
+    },
+}
+
+impl<'k> KeyJson<'k> {
+    fn from_key(key: &'k Key<'_>) -> Self {
+        match key {
+            Key::Plain(p) => Self::from_plain_key(p),
+            Key::Inherit(_) => unreachable!("inherited keys are resolved by processing"),
+            Key::ModTap {
+                tap,
+                at,
+                timeout,
+                hold,
+                span: _,
+            } => Self::ModTap {
+                tap: Box::new(Self::from_plain_key(tap)),
+                hold: Box::new(Self::from_plain_key(hold)),
+                flavor: match at {
+                    ModTapType::Permissive(_) => "permissive",
+                    ModTapType::OnOtherKey(_) => "on_other_key",
+                },
+                timeout: timeout.as_ref().map(|t| t.timeout),
+            },
+        }
+    }
+
+    fn from_plain_key(key: &'k PlainKey<'_>) -> Self {
+        match key {
+            PlainKey::Named(name) => Self::Named { name: name.s },
+            PlainKey::Trans(_) => Self::Trans,
+            PlainKey::Layer { layer, .. } => Self::Layer { layer: layer.s },
+            PlainKey::LayerSwitch { kind, layer, .. } => Self::LayerSwitch {
+                kind: match kind {
+                    LayerSwitchKind::Toggle(_) => "toggle",
+                    LayerSwitchKind::OneShot(_) => "oneshot",
+                    LayerSwitchKind::Default(_) => "default",
+                },
+                layer: layer.s,
+            },
+            PlainKey::Char { c, .. } => Self::Char { char: *c },
+            PlainKey::Modified { modifiers, key, .. } => Self::Modified {
+                modifiers: modifiers
+                    .iter()
+                    .map(|m| match m {
+                        Modifier::Ctrl(_) => "ctrl",
+                        Modifier::Shift(_) => "shift",
+                        Modifier::Alt(_) => "alt",
+                        Modifier::Gui(_) => "gui",
+                    })
+                    .collect(),
+                key: Box::new(Self::from_plain_key(key)),
+            },
+        }
+    }
+}
+
+pub fn serialize_key<S: Serializer>(key: &Key<'_>, s: S) -> Result<S::Ok, S::Error> {
+    KeyJson::from_key(key).serialize(s)
+}
+
+pub fn serialize_optional_key<S: Serializer>(
+    key: &Option<Key<'_>>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    key.as_ref().map(KeyJson::from_key).serialize(s)
+}
+
+/// JSON only has string keys, so maps keyed by positions become lists of pairs
+pub fn serialize_entries<K: Serialize, V: Serialize, S: Serializer>(
+    map: &BTreeMap<K, V>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_seq(map)
+}
+
+pub fn serialize_options<S: Serializer>(
+    options: &HashMap<(OptionKey, &str), &str>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let mut grouped: BTreeMap<OptionKey, BTreeMap<&str, &str>> = BTreeMap::new();
+
+    for ((backend, name), value) in options {
+        grouped.entry(*backend).or_default().insert(name, value);
+    }
+
+    grouped.serialize(s)
+}
@@ -0,0 +1,259 @@
+use std::io::Write;
+
+use itertools::Itertools;
+
+use crate::{
+    errors::Errors,
+    legend::{Legend, Legends},
+    process::Metadata,
+    syntax::File,
+};
+
+const UP: u8 = 1;
+const DOWN: u8 = 2;
+const LEFT: u8 = 4;
+const RIGHT: u8 = 8;
+
+/// Rows each key takes up, the border below it belongs to the next row
+const KEY_HEIGHT: usize = 3;
+const MIN_KEY_WIDTH: usize = 3;
+const MAX_KEY_WIDTH: usize = 9;
+
+/// A grid of characters to draw boxes onto, lines that meet are joined up
+struct Canvas {
+    lines: Vec<Vec<u8>>,
+    text: Vec<Vec<Option<char>>>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            lines: vec![vec![0; width]; height],
+            text: vec![vec![None; width]; height],
+        }
+    }
+
+    fn draw_box(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        for x in left..right {
+            for y in [top, bottom] {
+                self.lines[y][x] |= RIGHT;
+                self.lines[y][x + 1] |= LEFT;
+            }
+        }
+
+        for y in top..bottom {
+            for x in [left, right] {
+                self.lines[y][x] |= DOWN;
+                self.lines[y + 1][x] |= UP;
+            }
+        }
+    }
+
+    /// Where text centred on a column starts, keeping it within the canvas
+    fn centred_start(&self, centre: f64, y: usize, len: usize) -> usize {
+        let start = (centre - len as f64 / 2.0).round().max(0.0) as usize;
+
+        start.min(self.text[y].len().saturating_sub(len))
+    }
+
+    /// Write text centred on a column, keeping it within the canvas
+    fn write_centred(&mut self, centre: f64, y: usize, text: &str) {
+        let start = self.centred_start(centre, y, text.chars().count());
+        let width = self.text[y].len();
+
+        for (x, c) in (start..width).zip(text.chars()) {
+            self.text[y][x] = Some(c);
+        }
+    }
+
+    /// Whether text centred on a column wouldn't write over any other text
+    fn fits_centred(&self, centre: f64, y: usize, text: &str) -> bool {
+        let len = text.chars().count();
+        let start = self.centred_start(centre, y, len);
+
+        self.text[y].len() >= len && self.text[y][start..start + len].iter().all(Option::is_none)
+    }
+
+    fn render(&self, ascii: bool, out: &mut impl Write) {
+        for (lines, text) in self.lines.iter().zip(&self.text) {
+            let line = lines
+                .iter()
+                .zip(text)
+                .map(|(&l, &t)| t.unwrap_or_else(|| line_char(l, ascii)))
+                .collect::<String>();
+
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+    }
+}
+
+fn line_char(directions: u8, ascii: bool) -> char {
+    let horizontal = directions & (LEFT | RIGHT) != 0;
+    let vertical = directions & (UP | DOWN) != 0;
+
+    if ascii {
+        return match (horizontal, vertical) {
+            (true, true) => '+',
+            (true, false) => '-',
+            (false, true) => '|',
+            (false, false) => ' ',
+        };
+    }
+
+    match directions {
+        0 => ' ',
+        d if d == DOWN | RIGHT => '┌',
+        d if d == DOWN | LEFT => '┐',
+        d if d == UP | RIGHT => '└',
+        d if d == UP | LEFT => '┘',
+        d if d == UP | DOWN | RIGHT => '├',
+        d if d == UP | DOWN | LEFT => '┤',
+        d if d == LEFT | RIGHT | DOWN => '┬',
+        d if d == LEFT | RIGHT | UP => '┴',
+        d if d == UP | DOWN | LEFT | RIGHT => '┼',
+        _ if vertical => '│',
+        _ => '─',
+    }
+}
+
+/// Cut text down to fit on a key
+fn fit(text: &str, width: usize, ascii: bool) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+
+    let mut fitted = text.chars().take(width - 1).collect::<String>();
+    fitted.push(if ascii { '~' } else { '…' });
+
+    fitted
+}
+
+fn tap_text(legend: &Legend) -> Option<&str> {
+    if legend.trans {
+        return Some("_");
+    }
+
+    legend.tap.as_deref()
+}
+
+/// Print every layer as a grid of keys, laid out the way the keys physically
+/// are
+pub fn show(
+    file: &File<'_>,
+    metadata: &Metadata<'_>,
+    ascii: bool,
+    out: &mut impl Write,
+) -> miette::Result<()> {
+    let legends = Legends::new(file);
+    let layout = &metadata.layout;
+    let mut errors = Errors::default();
+
+    let layers = metadata
+        .layers
+        .layers
+        .iter()
+        .map(|layer| legends.layer(layout, layer, &mut errors))
+        .collect::<Vec<_>>();
+
+    // check every key before writing anything out
+    errors.finish()?;
+
+    // every key is as wide as the longest legend, within reason
+    let key_width = layers
+        .iter()
+        .flat_map(|l| l.keys.iter().map(|(_, legend)| legend))
+        .flat_map(|legend| [tap_text(legend), legend.hold.as_deref()])
+        .flatten()
+        .map(|text| text.chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp(MIN_KEY_WIDTH, MAX_KEY_WIDTH);
+
+    let phys = &layout.phys_to_matrix;
+    let columns = phys.keys().map(|p| usize::from(p.0) + 1).max().unwrap_or(0);
+    let rows = phys.keys().map(|p| usize::from(p.1) + 1).max().unwrap_or(0);
+
+    let key_left = |x: u8| usize::from(x) * (key_width + 1);
+    let key_top = |y: u8| usize::from(y) * KEY_HEIGHT;
+    let key_centre = |(x, y): (u8, u8)| {
+        (
+            key_left(x) as f64 + (key_width + 1) as f64 / 2.0,
+            key_top(y) as f64 + KEY_HEIGHT as f64 / 2.0,
+        )
+    };
+
+    for (idx, layer) in layers.iter().enumerate() {
+        if idx > 0 {
+            writeln!(out).unwrap();
+        }
+        writeln!(out, "{}", layer.name).unwrap();
+
+        let mut canvas = Canvas::new(columns * (key_width + 1) + 1, rows * KEY_HEIGHT + 1);
+
+        for ((x, y), _) in &layer.keys {
+            let (left, top) = (key_left(*x), key_top(*y));
+
+            canvas.draw_box(left, top, left + key_width + 1, top + KEY_HEIGHT);
+        }
+
+        for (pos, legend) in &layer.keys {
+            let (centre, top) = (key_centre(*pos).0, key_top(pos.1));
+
+            if let Some(tap) = tap_text(legend) {
+                canvas.write_centred(centre, top + 1, &fit(tap, key_width, ascii));
+            }
+
+            if let Some(hold) = &legend.hold {
+                canvas.write_centred(centre, top + 2, &fit(hold, key_width, ascii));
+            }
+        }
+
+        // chords of keys next to each other on a row go on the border below
+        // them if there's room, the rest are listed after the layer
+        let mut listed = Vec::new();
+        for (positions, legend) in &layer.chords {
+            let Some(text) = tap_text(legend).or(legend.hold.as_deref()) else {
+                continue;
+            };
+
+            let Some((first, last)) = positions.iter().minmax().into_option() else {
+                continue;
+            };
+
+            let in_a_row = positions.iter().all(|p| p.1 == first.1)
+                && usize::from(last.0 - first.0) + 1 == positions.len();
+            let between = format!(" {text} ");
+            let x = (key_centre(*first).0 + key_centre(*last).0) / 2.0;
+            let y = key_top(first.1) + KEY_HEIGHT;
+
+            if in_a_row
+                && between.chars().count() <= key_width
+                && canvas.fits_centred(x, y, &between)
+            {
+                canvas.write_centred(x, y, &between);
+                continue;
+            }
+
+            let keys = positions
+                .iter()
+                .map(|pos| {
+                    layer
+                        .keys
+                        .iter()
+                        .find(|(p, _)| p == pos)
+                        .and_then(|(_, legend)| tap_text(legend).or(legend.hold.as_deref()))
+                        .unwrap_or("?")
+                })
+                .join(" + ");
+            listed.push(format!("{keys} {} {text}", if ascii { "->" } else { "→" }));
+        }
+
+        canvas.render(ascii, out);
+
+        for chord in listed {
+            writeln!(out, "  {chord}").unwrap();
+        }
+    }
+
+    Ok(())
+}
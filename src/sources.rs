@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chumsky::{input::Input as _, Parser as _};
+use miette::{MietteError, MietteSpanContents, SourceCode, SourceSpan, SpanContents};
+
+use crate::{
+    errors::{AppError, Errors},
+    parse,
+    syntax::{File, Trivia},
+};
+
+#[derive(Debug, Clone)]
+struct SourceFile {
+    name: String,
+    /// Where this file starts in the combined offsets used by spans
+    offset: usize,
+    text: String,
+    /// Indices of the files this one includes, in order
+    includes: Vec<usize>,
+}
+
+/// Every file that makes up a layout: the one given on the command line and
+/// anything it (transitively) includes.
+///
+/// The files are laid out end to end so that a single [`crate::syntax::Span`] can point into
+/// any of them, this is also what gets handed to miette so that diagnostics
+/// show the right file.
+#[derive(Debug, Clone)]
+pub struct Sources {
+    files: Arc<Vec<SourceFile>>,
+}
+
+pub struct Parsed<'a> {
+    /// The file given on the command line, as written
+    pub root: File<'a>,
+    /// The root file with everything it includes merged in
+    pub merged: File<'a>,
+}
+
+impl Sources {
+    /// Load a layout and everything it includes. Errors returned from here
+    /// already have the source code attached.
+    pub fn load(root: &Path) -> miette::Result<Self> {
+        let mut files: Vec<SourceFile> = Vec::new();
+        let mut loaded: HashMap<PathBuf, usize> = HashMap::new();
+
+        let text = std::fs::read_to_string(root).map_err(AppError::IOError)?;
+        Self::push(&mut files, &mut loaded, root, text);
+
+        let mut idx = 0;
+        while idx < files.len() {
+            let includes = Self::parse_one(&files, idx)
+                .map_err(|e| e.with_source_code(Self::from_files(&files)))?
+                .includes
+                .into_iter()
+                .map(|i| (i.path.text.into_owned(), i.path.span))
+                .collect::<Vec<_>>();
+
+            let dir = Path::new(&files[idx].name)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+
+            for (path, span) in includes {
+                let path = dir.join(path);
+                let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+                let included = if let Some(&included) = loaded.get(&key) {
+                    included
+                } else {
+                    let text = std::fs::read_to_string(&path).map_err(|source| {
+                        miette::Report::new(AppError::IncludeFailed {
+                            span,
+                            path: path.to_string_lossy().into_owned(),
+                            source,
+                        })
+                        .with_source_code(Self::from_files(&files))
+                    })?;
+
+                    Self::push(&mut files, &mut loaded, &path, text)
+                };
+
+                files[idx].includes.push(included);
+            }
+
+            idx += 1;
+        }
+
+        Ok(Self {
+            files: Arc::new(files),
+        })
+    }
+
+    fn push(
+        files: &mut Vec<SourceFile>,
+        loaded: &mut HashMap<PathBuf, usize>,
+        path: &Path,
+        text: String,
+    ) -> usize {
+        // leave a gap between files so that a span at the very end of one
+        // file isn't mistaken for the start of the next
+        let offset = files.last().map_or(0, |f| f.offset + f.text.len() + 1);
+
+        files.push(SourceFile {
+            name: path.to_string_lossy().into_owned(),
+            offset,
+            text,
+            includes: Vec::new(),
+        });
+
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        loaded.insert(key, files.len() - 1);
+
+        files.len() - 1
+    }
+
+    fn from_files(files: &[SourceFile]) -> Self {
+        Self {
+            files: Arc::new(files.to_vec()),
+        }
+    }
+
+    fn parse_one(files: &[SourceFile], idx: usize) -> miette::Result<File<'_>> {
+        let file = &files[idx];
+
+        parse::file()
+            .parse(file.text.as_str().with_context(file.offset))
+            .into_result()
+            .map_err(|e| {
+                let mut errors = Errors::default();
+                errors.extend(
+                    e.into_iter()
+                        .map(|e| miette::Report::new(parse::convert_error(e))),
+                );
+                errors.finish().unwrap_err()
+            })
+    }
+
+    /// Parse the root file and merge in everything it includes.
+    ///
+    /// Included files are merged in place of their `include` item, so the
+    /// including file's options and keys take precedence and included layers
+    /// come first. Each file is only included once.
+    pub fn parse(&self) -> miette::Result<Parsed<'_>> {
+        let mut errors = Errors::default();
+        let files = (0..self.files.len())
+            .filter_map(|idx| errors.take(Self::parse_one(&self.files, idx)))
+            .collect::<Vec<_>>();
+        errors.finish()?;
+
+        let mut merged = File {
+            includes: Vec::new(),
+            layout: None,
+            options: Vec::new(),
+            custom_keys: Vec::new(),
+            layers: Vec::new(),
+            trailing: Trivia::default(),
+            span: files[0].span,
+        };
+
+        let mut seen = vec![false; files.len()];
+        self.merge(&files, 0, &mut seen, &mut merged)?;
+
+        Ok(Parsed {
+            root: files[0].clone(),
+            merged,
+        })
+    }
+
+    fn merge<'a>(
+        &self,
+        files: &[File<'a>],
+        idx: usize,
+        seen: &mut [bool],
+        into: &mut File<'a>,
+    ) -> miette::Result<()> {
+        if std::mem::replace(&mut seen[idx], true) {
+            return Ok(());
+        }
+
+        for &included in &self.files[idx].includes {
+            self.merge(files, included, seen, into)?;
+        }
+
+        let file = &files[idx];
+
+        if let Some(layout) = &file.layout {
+            if let Some(existing) = &into.layout {
+                return Err(AppError::DuplicateLayout {
+                    span: layout.layout_token.0,
+                    other_span: existing.layout_token.0,
+                }
+                .into());
+            }
+
+            into.layout = Some(layout.clone());
+        }
+
+        into.options.extend(file.options.iter().cloned());
+        into.custom_keys.extend(file.custom_keys.iter().cloned());
+        into.layers.extend(file.layers.iter().cloned());
+
+        Ok(())
+    }
+}
+
+impl SourceCode for Sources {
+    fn read_span<'a>(
+        &'a self,
+        span: &SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let file = self
+            .files
+            .iter()
+            .rev()
+            .find(|f| f.offset <= span.offset())
+            .ok_or(MietteError::OutOfBounds)?;
+
+        let local = SourceSpan::new((span.offset() - file.offset).into(), span.len());
+        let contents = file
+            .text
+            .read_span(&local, context_lines_before, context_lines_after)?;
+
+        Ok(Box::new(MietteSpanContents::new_named(
+            file.name.clone(),
+            contents.data(),
+            SourceSpan::new(
+                (contents.span().offset() + file.offset).into(),
+                contents.span().len(),
+            ),
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        )))
+    }
+}
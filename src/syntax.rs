@@ -1,12 +1,12 @@
 use std::{borrow::Cow, collections::HashSet};
 
-use chumsky::span::SimpleSpan;
+use chumsky::span::Span as _;
 use locspan::Spanned;
 use miette::SourceSpan;
 use pad::PadStr;
 use pretty::RcDoc;
 
-use crate::format::KeySpacing;
+use crate::{format::KeySpacing, parse::ParseSpan};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Span(pub SourceSpan);
@@ -34,17 +34,17 @@ impl Span {
     }
 }
 
-impl From<SimpleSpan> for Span {
-    fn from(span: SimpleSpan) -> Self {
-        let s = span.start;
-        let e = span.end;
+impl From<ParseSpan> for Span {
+    fn from(span: ParseSpan) -> Self {
+        let s = span.context() + span.start;
+        let e = span.context() + span.end;
 
-        Self(SourceSpan::new(s.into(), (e - s).into()))
+        Self(SourceSpan::new(s.into(), e - s))
     }
 }
 
-impl From<&SimpleSpan> for Span {
-    fn from(value: &SimpleSpan) -> Self {
+impl From<&ParseSpan> for Span {
+    fn from(value: &ParseSpan) -> Self {
         Span::from(*value)
     }
 }
@@ -98,35 +98,207 @@ impl<'a, S: Copy> Spanned for Ident<'a, S> {
     }
 }
 
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct Comment<'a, S = Span> {
+    pub text: &'a str,
+    /// Whether a line break separates this comment from whatever preceded it
+    pub own_line: bool,
+    pub span: S,
+}
+
+impl<'a, S> Comment<'a, S> {
+    pub fn to_doc(&self) -> RcDoc {
+        RcDoc::text(self.text.trim_end())
+    }
+
+    /// Whether this is a `//` comment, which runs to the end of the line
+    pub fn is_line(&self) -> bool {
+        self.text.starts_with("//")
+    }
+}
+
+impl<'a, S: Copy> Spanned for Comment<'a, S> {
+    type Span = S;
+
+    fn span(&self) -> Self::Span {
+        self.span
+    }
+}
+
+/// Comments attached to a node, in source order
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct Trivia<'a, S = Span> {
+    pub comments: Vec<Comment<'a, S>>,
+}
+
+impl<'a, S> Default for Trivia<'a, S> {
+    fn default() -> Self {
+        Self {
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Trivia<'a> {
+    fn same_line_doc(&self) -> RcDoc {
+        RcDoc::concat(
+            self.comments
+                .iter()
+                .take_while(|c| !c.own_line)
+                .map(|c| RcDoc::space().append(c.to_doc())),
+        )
+    }
+
+    fn own_line_docs(&self) -> Vec<RcDoc> {
+        let mut lines: Vec<RcDoc> = Vec::new();
+
+        for c in self.comments.iter().skip_while(|c| !c.own_line) {
+            match lines.pop() {
+                Some(line) if !c.own_line => {
+                    lines.push(line.append(RcDoc::space()).append(c.to_doc()))
+                }
+                line => {
+                    lines.extend(line);
+                    lines.push(c.to_doc());
+                }
+            }
+        }
+
+        lines
+    }
+
+    /// Lay out `doc` after these comments, comments that shared a line with
+    /// the previous token stay there and the rest go above `doc`
+    pub fn wrap(&'a self, sep: RcDoc<'a>, doc: RcDoc<'a>) -> RcDoc<'a> {
+        self.same_line_doc()
+            .append(sep)
+            .append(RcDoc::concat(
+                self.own_line_docs()
+                    .into_iter()
+                    .map(|l| l.append(RcDoc::hardline())),
+            ))
+            .append(doc)
+    }
+
+    /// These comments written out after a token on the same line, or `None`
+    /// if any of them starts or ends a line
+    fn inline_text(&self) -> Option<String> {
+        self.comments
+            .iter()
+            .all(|c| !c.own_line && !c.is_line())
+            .then(|| {
+                self.comments
+                    .iter()
+                    .map(|c| format!(" {}", c.text.trim_end()))
+                    .collect()
+            })
+    }
+
+    /// These comments written out ahead of a token on the same line
+    fn leading_text(&self) -> String {
+        self.comments
+            .iter()
+            .map(|c| format!("{} ", c.text.trim_end()))
+            .collect()
+    }
+
+    /// Whether the last comment is a `//` one, so whatever comes next has to
+    /// go on a new line
+    pub fn ends_line(&self) -> bool {
+        self.comments.last().is_some_and(Comment::is_line)
+    }
+
+    /// Lay out comments found between two tokens of a construct (the keys of
+    /// a row, the parts of a block header, ...) where they were, `sep` goes
+    /// between the tokens if there aren't any
+    pub fn between(&'a self, sep: RcDoc<'a>) -> RcDoc<'a> {
+        if self.comments.is_empty() {
+            return sep;
+        }
+
+        let comments = RcDoc::concat(self.comments.iter().map(|c| {
+            let before = if c.own_line {
+                RcDoc::hardline()
+            } else {
+                RcDoc::space()
+            };
+
+            before.append(c.to_doc())
+        }));
+
+        if self.ends_line() {
+            comments.append(RcDoc::hardline())
+        } else {
+            comments.append(sep)
+        }
+    }
+
+    /// Lay out these comments at the end of a block
+    pub fn trailing_doc(&self) -> RcDoc {
+        self.same_line_doc().append(RcDoc::concat(
+            self.own_line_docs()
+                .into_iter()
+                .map(|l| RcDoc::hardline().append(l)),
+        ))
+    }
+}
+
+/// The comments after an item of a row, rows made when importing don't have
+/// any
+fn after_item<'a>(after_items: &'a [Trivia<'a>], idx: usize) -> RcDoc<'a> {
+    after_items
+        .get(idx)
+        .map_or(RcDoc::nil(), |t| t.between(RcDoc::nil()))
+}
+
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct File<'a, S = Span> {
-    pub layout: Layout<S>,
+    pub includes: Vec<Include<'a, S>>,
+    pub layout: Option<Layout<'a, S>>,
     pub options: Vec<Options<'a, S>>,
     pub custom_keys: Vec<CustomKey<'a, S>>,
     pub layers: Vec<Layer<'a, S>>,
+    pub trailing: Trivia<'a, S>,
     pub span: S,
 }
 
 impl<'a> File<'a> {
     pub fn to_doc(&self, spacing: &[KeySpacing], empties: &HashSet<(u8, u8)>) -> RcDoc {
         let twoline = RcDoc::line().append(RcDoc::line_());
-        self.layout
-            .to_doc()
-            .append(twoline.clone())
-            .append(RcDoc::intersperse(
-                self.options.iter().map(|o| o.to_doc()),
-                twoline.clone(),
-            ))
-            .append(twoline.clone())
-            .append(RcDoc::intersperse(
-                self.custom_keys.iter().map(|o| o.to_doc()),
-                twoline.clone(),
-            ))
-            .append(twoline.clone())
-            .append(RcDoc::intersperse(
-                self.layers.iter().map(|o| o.to_doc(spacing, empties)),
-                twoline.clone(),
-            ))
+        let includes = self.includes.iter().enumerate().map(|(idx, i)| {
+            let sep = if idx == 0 {
+                RcDoc::nil()
+            } else {
+                RcDoc::line()
+            };
+
+            i.leading.wrap(sep, i.to_doc())
+        });
+        let items = self
+            .layout
+            .iter()
+            .map(|l| (&l.leading, l.to_doc()))
+            .chain(self.options.iter().map(|o| (&o.leading, o.to_doc())))
+            .chain(self.custom_keys.iter().map(|o| (&o.leading, o.to_doc())))
+            .chain(
+                self.layers
+                    .iter()
+                    .map(|o| (&o.leading, o.to_doc(spacing, empties))),
+            )
+            .enumerate()
+            .map(|(idx, (leading, doc))| {
+                let sep = if idx == 0 && self.includes.is_empty() {
+                    RcDoc::nil()
+                } else {
+                    twoline.clone()
+                };
+
+                leading.wrap(sep, doc)
+            });
+
+        RcDoc::concat(includes)
+            .append(RcDoc::concat(items))
+            .append(self.trailing.trailing_doc())
             .append(RcDoc::line())
     }
 }
@@ -139,12 +311,46 @@ impl<'a, S: Copy> Spanned for File<'a, S> {
     }
 }
 
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct Include<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
+    pub include_token: Token<"include", S>,
+    pub after_include: Trivia<'a, S>,
+    pub path: Text<'a, S>,
+    pub after_path: Trivia<'a, S>,
+    pub semi: Token<";", S>,
+    pub span: S,
+}
+
+impl<'a> Include<'a> {
+    pub fn to_doc(&self) -> RcDoc {
+        self.include_token
+            .to_doc()
+            .append(self.after_include.between(RcDoc::space()))
+            .append(self.path.to_doc())
+            .append(self.after_path.between(RcDoc::nil()))
+            .append(self.semi.to_doc())
+    }
+}
+
+impl<'a, S: Copy> Spanned for Include<'a, S> {
+    type Span = S;
+
+    fn span(&self) -> Self::Span {
+        self.span
+    }
+}
+
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct Options<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub options_token: Token<"options", S>,
+    pub after_options: Trivia<'a, S>,
     pub for_: OptionsFor<S>,
+    pub after_for: Trivia<'a, S>,
     pub left_curly: Token<"{", S>,
     pub items: Vec<OptionsItem<'a, S>>,
+    pub trailing: Trivia<'a, S>,
     pub right_curly: Token<"}", S>,
     pub span: S,
 }
@@ -153,12 +359,18 @@ impl<'a> Options<'a> {
     pub fn to_doc(&self) -> RcDoc {
         self.options_token
             .to_doc()
-            .append(RcDoc::space())
+            .append(self.after_options.between(RcDoc::space()))
             .append(self.for_.to_doc())
-            .append(RcDoc::space())
+            .append(self.after_for.between(RcDoc::space()))
             .append(self.left_curly.to_doc())
             .append(
-                RcDoc::concat(self.items.iter().map(|i| RcDoc::line().append(i.to_doc()))).nest(2),
+                RcDoc::concat(
+                    self.items
+                        .iter()
+                        .map(|i| i.leading.wrap(RcDoc::line(), i.to_doc())),
+                )
+                .append(self.trailing.trailing_doc())
+                .nest(2),
             )
             .append(RcDoc::line())
             .append(self.right_curly.to_doc())
@@ -177,6 +389,13 @@ impl<'a, S: Copy> Spanned for Options<'a, S> {
 pub enum OptionsFor<S = Span> {
     RustyDilemma(Token<"rusty_dilemma", S>),
     KeymapDrawer(Token<"keymap_drawer", S>),
+    Qmk(Token<"qmk", S>),
+    Zmk(Token<"zmk", S>),
+    Kanata(Token<"kanata", S>),
+    Kmk(Token<"kmk", S>),
+    Keyberon(Token<"keyberon", S>),
+    Rmk(Token<"rmk", S>),
+    Vial(Token<"vial", S>),
     Formatter(Token<"formatter", S>),
 }
 
@@ -185,6 +404,13 @@ impl OptionsFor {
         match self {
             OptionsFor::RustyDilemma(x) => x.to_doc(),
             OptionsFor::KeymapDrawer(x) => x.to_doc(),
+            OptionsFor::Qmk(x) => x.to_doc(),
+            OptionsFor::Zmk(x) => x.to_doc(),
+            OptionsFor::Kanata(x) => x.to_doc(),
+            OptionsFor::Kmk(x) => x.to_doc(),
+            OptionsFor::Keyberon(x) => x.to_doc(),
+            OptionsFor::Rmk(x) => x.to_doc(),
+            OptionsFor::Vial(x) => x.to_doc(),
             OptionsFor::Formatter(x) => x.to_doc(),
         }
     }
@@ -197,6 +423,13 @@ impl<S: Copy> Spanned for OptionsFor<S> {
         match self {
             OptionsFor::RustyDilemma(t) => t.span(),
             OptionsFor::KeymapDrawer(t) => t.span(),
+            OptionsFor::Qmk(t) => t.span(),
+            OptionsFor::Zmk(t) => t.span(),
+            OptionsFor::Kanata(t) => t.span(),
+            OptionsFor::Kmk(t) => t.span(),
+            OptionsFor::Keyberon(t) => t.span(),
+            OptionsFor::Rmk(t) => t.span(),
+            OptionsFor::Vial(t) => t.span(),
             OptionsFor::Formatter(t) => t.span(),
         }
     }
@@ -204,9 +437,13 @@ impl<S: Copy> Spanned for OptionsFor<S> {
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct OptionsItem<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub name: Ident<'a, S>,
+    pub after_name: Trivia<'a, S>,
     pub colon: Token<":", S>,
+    pub after_colon: Trivia<'a, S>,
     pub value: Text<'a, S>,
+    pub after_value: Trivia<'a, S>,
     pub semi: Token<";", S>,
     pub span: S,
 }
@@ -215,9 +452,11 @@ impl<'a> OptionsItem<'a> {
     pub fn to_doc(&self) -> RcDoc {
         self.name
             .to_doc()
+            .append(self.after_name.between(RcDoc::nil()))
             .append(self.colon.to_doc())
-            .append(RcDoc::space())
+            .append(self.after_colon.between(RcDoc::space()))
             .append(self.value.to_doc())
+            .append(self.after_value.between(RcDoc::nil()))
             .append(self.semi.to_doc())
     }
 }
@@ -232,10 +471,14 @@ impl<'a, S: Copy> Spanned for OptionsItem<'a, S> {
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct CustomKey<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub key_token: Token<"key", S>,
+    pub after_key: Trivia<'a, S>,
     pub name: Ident<'a, S>,
+    pub after_name: Trivia<'a, S>,
     pub left_curly: Token<"{", S>,
     pub outputs: Vec<CustomKeyOutput<'a, S>>,
+    pub trailing: Trivia<'a, S>,
     pub right_curly: Token<"}", S>,
     pub span: S,
 }
@@ -244,16 +487,17 @@ impl<'a> CustomKey<'a> {
     pub fn to_doc(&self) -> RcDoc {
         self.key_token
             .to_doc()
-            .append(RcDoc::space())
+            .append(self.after_key.between(RcDoc::space()))
             .append(self.name.to_doc())
-            .append(RcDoc::space())
+            .append(self.after_name.between(RcDoc::space()))
             .append(self.left_curly.to_doc())
             .append(
                 RcDoc::concat(
                     self.outputs
                         .iter()
-                        .map(|i| RcDoc::line().append(i.to_doc())),
+                        .map(|i| i.leading.wrap(RcDoc::line(), i.to_doc())),
                 )
+                .append(self.trailing.trailing_doc())
                 .nest(2),
             )
             .append(RcDoc::line())
@@ -270,10 +514,15 @@ impl<'a, S: Copy> Spanned for CustomKey<'a, S> {
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct CustomKeyOutput<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub out_token: Token<"out", S>,
+    pub after_out: Trivia<'a, S>,
     pub name: Ident<'a, S>,
+    pub after_name: Trivia<'a, S>,
     pub colon: Token<":", S>,
+    pub after_colon: Trivia<'a, S>,
     pub output: Text<'a, S>,
+    pub after_output: Trivia<'a, S>,
     pub semi: Token<";", S>,
     pub span: S,
 }
@@ -282,11 +531,13 @@ impl<'a> CustomKeyOutput<'a> {
     pub fn to_doc(&self) -> RcDoc {
         self.out_token
             .to_doc()
-            .append(RcDoc::space())
+            .append(self.after_out.between(RcDoc::space()))
             .append(self.name.to_doc())
+            .append(self.after_name.between(RcDoc::nil()))
             .append(self.colon.to_doc())
-            .append(RcDoc::space())
+            .append(self.after_colon.between(RcDoc::space()))
             .append(self.output.to_doc())
+            .append(self.after_output.between(RcDoc::nil()))
             .append(self.semi.to_doc())
     }
 }
@@ -322,29 +573,38 @@ impl<'a, S: Copy> Spanned for Text<'a, S> {
 }
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
-pub struct Layout<S = Span> {
+pub struct Layout<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub layout_token: Token<"layout", S>,
+    pub after_layout: Trivia<'a, S>,
     pub left_curly: Token<"{", S>,
-    pub rows: Vec<LayoutRow<S>>,
+    pub rows: Vec<LayoutRow<'a, S>>,
+    pub trailing: Trivia<'a, S>,
     pub right_curly: Token<"}", S>,
     pub span: S,
 }
 
-impl Layout {
+impl<'a> Layout<'a> {
     pub fn to_doc(&self) -> RcDoc {
         self.layout_token
             .to_doc()
-            .append(RcDoc::space())
+            .append(self.after_layout.between(RcDoc::space()))
             .append(self.left_curly.to_doc())
             .append(
-                RcDoc::concat(self.rows.iter().map(|i| RcDoc::line().append(i.to_doc()))).nest(2),
+                RcDoc::concat(
+                    self.rows
+                        .iter()
+                        .map(|i| i.leading.wrap(RcDoc::line(), i.to_doc())),
+                )
+                .append(self.trailing.trailing_doc())
+                .nest(2),
             )
             .append(RcDoc::line())
             .append(self.right_curly.to_doc())
     }
 }
 
-impl<S: Copy> Spanned for Layout<S> {
+impl<'a, S: Copy> Spanned for Layout<'a, S> {
     type Span = S;
 
     fn span(&self) -> Self::Span {
@@ -353,21 +613,34 @@ impl<S: Copy> Spanned for Layout<S> {
 }
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
-pub struct LayoutRow<S = Span> {
+pub struct LayoutRow<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
+    /// Block comments on the same line as the row, ahead of its first item
+    pub before_items: Trivia<'a, S>,
     pub items: Vec<LayoutDefn<S>>,
+    /// Comments after each of `items`
+    pub after_items: Vec<Trivia<'a, S>>,
     pub semi: Token<";", S>,
     pub span: S,
 }
 
-impl LayoutRow {
+impl<'a> LayoutRow<'a> {
     pub fn to_doc(&self) -> RcDoc {
-        let doc = RcDoc::intersperse(self.items.iter().map(|i| i.to_doc()), RcDoc::softline());
+        let doc = RcDoc::intersperse(
+            self.items
+                .iter()
+                .enumerate()
+                .map(|(idx, i)| i.to_doc().append(after_item(&self.after_items, idx))),
+            RcDoc::softline(),
+        );
 
-        doc.append(self.semi.to_doc())
+        RcDoc::text(self.before_items.leading_text())
+            .append(doc)
+            .append(self.semi.to_doc())
     }
 }
 
-impl<S: Copy> Spanned for LayoutRow<S> {
+impl<'a, S: Copy> Spanned for LayoutRow<'a, S> {
     type Span = S;
 
     fn span(&self) -> Self::Span {
@@ -448,10 +721,16 @@ impl<'a, S: Copy> Spanned for LayoutDefn<S> {
 
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct Layer<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
     pub layer_token: Token<"layer", S>,
+    pub after_layer: Trivia<'a, S>,
     pub name: Ident<'a, S>,
+    pub after_name: Trivia<'a, S>,
+    pub extends: Option<LayerExtends<'a, S>>,
     pub left_curly: Token<"{", S>,
     pub rows: Vec<LayerRow<'a, S>>,
+    pub combos: Vec<Combo<'a, S>>,
+    pub trailing: Trivia<'a, S>,
     pub right_curly: Token<"}", S>,
     pub span: S,
 }
@@ -466,17 +745,26 @@ impl<'a> Layer<'a> {
                 .filter_map(|(x, y_)| (y_ == y as u8).then_some(x))
                 .collect::<HashSet<_>>();
 
-            doc = doc.append(RcDoc::line());
-            doc = doc.append(row.to_doc(spacing, &empties));
+            doc = doc.append(
+                row.leading
+                    .wrap(RcDoc::line(), row.to_doc(spacing, &empties)),
+            );
+        }
+
+        for combo in &self.combos {
+            doc = doc.append(combo.leading.wrap(RcDoc::line(), combo.to_doc()));
         }
 
         self.layer_token
             .to_doc()
-            .append(RcDoc::space())
+            .append(self.after_layer.between(RcDoc::space()))
             .append(self.name.to_doc())
-            .append(RcDoc::space())
+            .append(self.after_name.between(RcDoc::space()))
+            .append(self.extends.as_ref().map_or(RcDoc::nil(), |e| {
+                e.to_doc().append(e.after_parent.between(RcDoc::space()))
+            }))
             .append(self.left_curly.to_doc())
-            .append(doc.nest(2))
+            .append(doc.append(self.trailing.trailing_doc()).nest(2))
             .append(RcDoc::line())
             .append(self.right_curly.to_doc())
     }
@@ -490,9 +778,42 @@ impl<'a, S: Copy> Spanned for Layer<'a, S> {
     }
 }
 
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct LayerExtends<'a, S = Span> {
+    pub extends_token: Token<"extends", S>,
+    pub after_extends: Trivia<'a, S>,
+    pub parent: Ident<'a, S>,
+    /// Comments between the parent and the `{` of the layer, which aren't
+    /// part of the span
+    pub after_parent: Trivia<'a, S>,
+    pub span: S,
+}
+
+impl<'a> LayerExtends<'a> {
+    pub fn to_doc(&self) -> RcDoc {
+        self.extends_token
+            .to_doc()
+            .append(self.after_extends.between(RcDoc::space()))
+            .append(self.parent.to_doc())
+    }
+}
+
+impl<'a, S: Copy> Spanned for LayerExtends<'a, S> {
+    type Span = S;
+
+    fn span(&self) -> Self::Span {
+        self.span
+    }
+}
+
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct LayerRow<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
+    /// Block comments on the same line as the row, ahead of its first item
+    pub before_items: Trivia<'a, S>,
     pub items: Vec<KeyOrChord<'a, S>>,
+    /// Comments after each of `items`
+    pub after_items: Vec<Trivia<'a, S>>,
     pub semi: Token<";", S>,
     pub span: S,
 }
@@ -501,14 +822,26 @@ impl<'a> LayerRow<'a> {
     pub fn to_doc(&self, spacing: &[KeySpacing], empties: &HashSet<u8>) -> RcDoc {
         let mut doc = RcDoc::nil();
 
-        let mut items_it = self.items.iter().peekable();
+        let mut items_it = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(idx, item)| {
+                let before = (idx == 0).then_some(&self.before_items);
+
+                (item, before, self.after_items.get(idx))
+            })
+            .peekable();
         let mut is_first = true;
+        // the rest of the row can't be lined up after a `//` comment, so it
+        // carries on from the start of the next line
+        let mut line_ended = false;
 
         for (idx, s) in spacing.iter().enumerate() {
             let idx = idx as u8;
 
             if empties.contains(&idx) {
-                if items_it.peek().is_some() {
+                if items_it.peek().is_some() && !line_ended {
                     if !is_first {
                         doc = doc.append(RcDoc::softline());
                     }
@@ -517,22 +850,36 @@ impl<'a> LayerRow<'a> {
                     doc = doc.append(RcDoc::text(" ".repeat(s.chord_width)));
                 }
             } else {
-                if !is_first {
+                // rows of layers extending another one can stop short
+                let Some((item, before, comments)) = items_it.next() else {
+                    break;
+                };
+                if !is_first && !line_ended {
                     doc = doc.append(RcDoc::softline());
                 }
-                let item = items_it.next().unwrap();
                 let (key_width, chord_width) = if items_it.peek().is_some() {
                     (s.key_width, s.chord_width)
                 } else {
                     (0, 0)
                 };
 
-                doc = doc.append(item.to_doc(key_width, chord_width));
+                doc = doc.append(row_cell(item, before, comments, key_width, chord_width));
+                line_ended = comments.is_some_and(Trivia::ends_line);
 
-                if let Some(KeyOrChord::Chord(_)) = items_it.peek() {
-                    doc = doc.append(RcDoc::softline());
-                    doc = doc.append(items_it.next().unwrap().to_doc(s.key_width, s.chord_width));
-                } else if items_it.peek().is_some() {
+                if let Some((KeyOrChord::Chord(_), _, _)) = items_it.peek() {
+                    let (chord, before, comments) = items_it.next().unwrap();
+                    if !line_ended {
+                        doc = doc.append(RcDoc::softline());
+                    }
+                    doc = doc.append(row_cell(
+                        chord,
+                        before,
+                        comments,
+                        s.key_width,
+                        s.chord_width,
+                    ));
+                    line_ended = comments.is_some_and(Trivia::ends_line);
+                } else if items_it.peek().is_some() && !line_ended {
                     doc = doc.append(RcDoc::softline());
                     doc = doc.append(RcDoc::text(" ".repeat(s.chord_width)));
                 }
@@ -543,6 +890,52 @@ impl<'a> LayerRow<'a> {
 
         doc.append(self.semi.to_doc())
     }
+
+    /// How much wider each item is made by the comments kept on its line,
+    /// which its column has to make room for
+    pub fn comment_widths(&self) -> impl Iterator<Item = (Span, usize)> + '_ {
+        self.items.iter().enumerate().map(|(idx, item)| {
+            let before = if idx == 0 {
+                self.before_items.leading_text().len()
+            } else {
+                0
+            };
+            let after = self
+                .after_items
+                .get(idx)
+                .and_then(Trivia::inline_text)
+                .map_or(0, |t| t.len());
+
+            (item.span(), before + after)
+        })
+    }
+}
+
+/// An item of a row along with the comments on its line, padded as a whole so
+/// that the rest of the row still lines up
+fn row_cell<'a>(
+    item: &'a KeyOrChord<'a>,
+    before: Option<&'a Trivia<'a>>,
+    after: Option<&'a Trivia<'a>>,
+    key_width: usize,
+    chord_width: usize,
+) -> RcDoc<'a> {
+    let width = match item {
+        KeyOrChord::Key(_) => key_width,
+        KeyOrChord::Chord(_) => chord_width,
+    };
+    let before = before.map_or(String::new(), Trivia::leading_text);
+    let plain = item.to_doc(0, 0).pretty(item.span().len()).to_string();
+
+    match after.map(|t| (t, t.inline_text())) {
+        // comments that break the line can't be lined up anyway
+        Some((after, None)) => RcDoc::text(format!("{before}{plain}").pad_to_width(width))
+            .append(after.between(RcDoc::nil())),
+        Some((_, Some(after))) => {
+            RcDoc::text(format!("{before}{plain}{after}").pad_to_width(width))
+        }
+        None => RcDoc::text(format!("{before}{plain}").pad_to_width(width)),
+    }
 }
 
 impl<'a, S: Copy> Spanned for LayerRow<'a, S> {
@@ -553,6 +946,78 @@ impl<'a, S: Copy> Spanned for LayerRow<'a, S> {
     }
 }
 
+/// A key pressed by some set of other keys in the layer together, wherever
+/// they are
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct Combo<'a, S = Span> {
+    pub leading: Trivia<'a, S>,
+    pub combo_token: Token<"combo", S>,
+    pub after_combo: Trivia<'a, S>,
+    pub positions: Vec<ComboPosition<S>>,
+    /// Comments after each of `positions`
+    pub after_positions: Vec<Trivia<'a, S>>,
+    pub arrow: Token<"=>", S>,
+    pub after_arrow: Trivia<'a, S>,
+    pub key: Key<'a, S>,
+    pub after_key: Trivia<'a, S>,
+    pub semi: Token<";", S>,
+    pub span: S,
+}
+
+impl<'a> Combo<'a> {
+    pub fn to_doc(&self) -> RcDoc {
+        self.combo_token
+            .to_doc()
+            .append(self.after_combo.between(RcDoc::space()))
+            .append(RcDoc::intersperse(
+                self.positions
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, p)| p.to_doc().append(after_item(&self.after_positions, idx))),
+                RcDoc::space(),
+            ))
+            .append(RcDoc::space())
+            .append(self.arrow.to_doc())
+            .append(self.after_arrow.between(RcDoc::space()))
+            .append(self.key.to_doc(None))
+            .append(self.after_key.between(RcDoc::nil()))
+            .append(self.semi.to_doc())
+    }
+}
+
+impl<'a, S: Copy> Spanned for Combo<'a, S> {
+    type Span = S;
+
+    fn span(&self) -> Self::Span {
+        self.span
+    }
+}
+
+/// `row:column` of a key in a layer
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub struct ComboPosition<S = Span> {
+    pub row: u8,
+    pub colon: Token<":", S>,
+    pub column: u8,
+    pub span: S,
+}
+
+impl ComboPosition {
+    pub fn to_doc(&self) -> RcDoc {
+        RcDoc::as_string(self.row)
+            .append(self.colon.to_doc())
+            .append(RcDoc::as_string(self.column))
+    }
+}
+
+impl<S: Copy> Spanned for ComboPosition<S> {
+    type Span = S;
+
+    fn span(&self) -> Self::Span {
+        self.span
+    }
+}
+
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub enum KeyOrChord<'a, S = Span> {
     Key(Key<'a, S>),
@@ -626,6 +1091,43 @@ impl ModTapType {
     }
 }
 
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub enum Modifier<S = Span> {
+    Ctrl(Token<"C-", S>),
+    Shift(Token<"S-", S>),
+    Alt(Token<"A-", S>),
+    Gui(Token<"G-", S>),
+}
+
+impl Modifier {
+    pub fn to_doc(&self) -> RcDoc {
+        match self {
+            Modifier::Ctrl(t) => t.to_doc(),
+            Modifier::Shift(t) => t.to_doc(),
+            Modifier::Alt(t) => t.to_doc(),
+            Modifier::Gui(t) => t.to_doc(),
+        }
+    }
+}
+
+/// Ways to switch layer other than holding a key
+#[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
+pub enum LayerSwitchKind<S = Span> {
+    Toggle(Token<"toggle", S>),
+    OneShot(Token<"oneshot", S>),
+    Default(Token<"default", S>),
+}
+
+impl LayerSwitchKind {
+    pub fn to_doc(&self) -> RcDoc {
+        match self {
+            LayerSwitchKind::Toggle(t) => t.to_doc(),
+            LayerSwitchKind::OneShot(t) => t.to_doc(),
+            LayerSwitchKind::Default(t) => t.to_doc(),
+        }
+    }
+}
+
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub struct ModTapTimeout<S = Span> {
     pub left_square: Token<"[", S>,
@@ -646,6 +1148,8 @@ impl ModTapTimeout {
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub enum Key<'a, S = Span> {
     Plain(PlainKey<'a, S>),
+    /// `.`, keeps whatever the layer being extended has in this position
+    Inherit(Token<".", S>),
     ModTap {
         tap: PlainKey<'a, S>,
         at: ModTapType<S>,
@@ -655,10 +1159,17 @@ pub enum Key<'a, S = Span> {
     },
 }
 
+impl<'a, S> Key<'a, S> {
+    pub fn is_trans(&self) -> bool {
+        matches!(self, Key::Plain(PlainKey::Trans(_)))
+    }
+}
+
 impl<'a> Key<'a> {
     pub fn to_doc(&self, spacing: Option<usize>) -> RcDoc {
         let d = match self {
             Key::Plain(p) => p.to_doc(),
+            Key::Inherit(t) => t.to_doc(),
             Key::ModTap {
                 tap,
                 at,
@@ -690,6 +1201,7 @@ impl<'a, S: Copy> Spanned for Key<'a, S> {
     fn span(&self) -> Self::Span {
         match self {
             Key::Plain(p) => p.span(),
+            Key::Inherit(t) => t.span(),
             Key::ModTap { span, .. } => *span,
         }
     }
@@ -698,24 +1210,40 @@ impl<'a, S: Copy> Spanned for Key<'a, S> {
 #[derive(Debug, debug3::Debug, Clone, PartialEq, Eq)]
 pub enum PlainKey<'a, S = Span> {
     Named(Ident<'a, S>),
+    /// `_`, falls through to whatever the layers below have in this position
+    Trans(Token<"_", S>),
     Layer {
         left_square: Token<"[", S>,
         layer: Ident<'a, S>,
         right_square: Token<"]", S>,
         span: S,
     },
+    LayerSwitch {
+        kind: LayerSwitchKind<S>,
+        left_paren: Token<"(", S>,
+        layer: Ident<'a, S>,
+        right_paren: Token<")", S>,
+        span: S,
+    },
     Char {
         left_quote: Token<"'", S>,
         c: char,
         right_quote: Token<"'", S>,
         span: S,
     },
+    /// A named or char key pressed along with some modifiers, `C-S-'t'`
+    Modified {
+        modifiers: Vec<Modifier<S>>,
+        key: Box<PlainKey<'a, S>>,
+        span: S,
+    },
 }
 
 impl<'a> PlainKey<'a> {
     pub fn to_doc(&self) -> RcDoc {
         match self {
             PlainKey::Named(name) => name.to_doc(),
+            PlainKey::Trans(t) => t.to_doc(),
             PlainKey::Layer {
                 left_square,
                 layer,
@@ -725,6 +1253,17 @@ impl<'a> PlainKey<'a> {
                 .to_doc()
                 .append(layer.to_doc())
                 .append(right_square.to_doc()),
+            PlainKey::LayerSwitch {
+                kind,
+                left_paren,
+                layer,
+                right_paren,
+                span: _,
+            } => kind
+                .to_doc()
+                .append(left_paren.to_doc())
+                .append(layer.to_doc())
+                .append(right_paren.to_doc()),
             PlainKey::Char {
                 left_quote,
                 c,
@@ -734,6 +1273,11 @@ impl<'a> PlainKey<'a> {
                 .to_doc()
                 .append(RcDoc::as_string(c))
                 .append(right_quote.to_doc()),
+            PlainKey::Modified {
+                modifiers,
+                key,
+                span: _,
+            } => RcDoc::concat(modifiers.iter().map(Modifier::to_doc)).append(key.to_doc()),
         }
     }
 }
@@ -744,8 +1288,11 @@ impl<'a, S: Copy> Spanned for PlainKey<'a, S> {
     fn span(&self) -> Self::Span {
         match self {
             PlainKey::Named(n) => n.span(),
+            PlainKey::Trans(t) => t.span(),
             PlainKey::Layer { span, .. } => *span,
+            PlainKey::LayerSwitch { span, .. } => *span,
             PlainKey::Char { span, .. } => *span,
+            PlainKey::Modified { span, .. } => *span,
         }
     }
 }